@@ -0,0 +1,164 @@
+use super::aim::*;
+use super::bot::*;
+use super::options_menu::*;
+use super::pause::*;
+use super::player::*;
+use super::settings::*;
+use super::simulation::*;
+use super::stick::*;
+use super::training::*;
+use bevy::prelude::*;
+
+pub struct ActionsPlugin;
+impl Plugin for ActionsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(ActionSource::Gamepads)
+            .add_system(
+                collect_gamepad_actions
+                    .system()
+                    .label("collect_gamepad_actions")
+                    .after("read_sticks")
+                    // Still sees the pause and the settings menu on the frame they're closed, so
+                    // the button that closed them isn't a jump
+                    .before("pause_menu_input")
+                    .before("toggle_options_menu"),
+            )
+            .add_system_to_stage(
+                SIMULATION,
+                clear_pressed_actions
+                    .system()
+                    .after("respawn_players")
+                    .after("record_actions"),
+            );
+    }
+}
+
+// Where PlayerActions come from. Anything other than Gamepads leaves them alone so
+// another system (a replay, a bot, the network) can fill them in.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ActionSource {
+    Gamepads,
+    Replay,
+    // The local player's pad, everyone else over the network
+    Network,
+}
+
+// Everything a player can do in one tick. Gameplay only ever reads this, never the
+// gamepad directly. Sticks are stored at the same precision replays use, so a live match
+// and its replay see exactly the same numbers.
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub struct PlayerActions {
+    pub movement: Vec2,
+    pub aim: Vec2,
+    // Pressed since the last tick
+    pub jump: bool,
+    pub fire: bool,
+}
+
+// PlayerActions squeezed into five bytes
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PackedActions {
+    pub movement: [i8; 2],
+    pub aim: [i8; 2],
+    pub buttons: u8,
+}
+
+const JUMP_BIT: u8 = 1;
+const FIRE_BIT: u8 = 1 << 1;
+
+fn quantize(value: f32) -> i8 {
+    (value.clamp(-1., 1.) * 127.).round() as i8
+}
+
+fn quantize_stick(stick: Vec2) -> [i8; 2] {
+    [quantize(stick.x), quantize(stick.y)]
+}
+
+fn unquantize_stick(stick: [i8; 2]) -> Vec2 {
+    Vec2::new(stick[0] as f32 / 127., stick[1] as f32 / 127.)
+}
+
+impl PlayerActions {
+    pub fn pack(&self) -> PackedActions {
+        let mut buttons = 0;
+        if self.jump {
+            buttons |= JUMP_BIT;
+        }
+        if self.fire {
+            buttons |= FIRE_BIT;
+        }
+
+        PackedActions {
+            movement: quantize_stick(self.movement),
+            aim: quantize_stick(self.aim),
+            buttons,
+        }
+    }
+}
+
+impl PackedActions {
+    pub fn unpack(&self) -> PlayerActions {
+        PlayerActions {
+            movement: unquantize_stick(self.movement),
+            aim: unquantize_stick(self.aim),
+            jump: self.buttons & JUMP_BIT != 0,
+            fire: self.buttons & FIRE_BIT != 0,
+        }
+    }
+}
+
+// Players on a pad of their own, so not bots or the training dummy
+type PadPlayers<'a> = Query<
+    'a,
+    (
+        &'static Gamepad,
+        &'static PlayerSlot,
+        &'static StickInput,
+        &'static mut PlayerActions,
+    ),
+    (With<Player>, Without<Bot>, Without<TrainingDummy>),
+>;
+
+// Button presses are latched until a tick consumes them, so a press on a frame without a
+// tick isn't lost and one with two ticks doesn't count twice. Nothing is latched while paused
+// or while the settings menu is open, those presses are for the menu.
+fn collect_gamepad_actions(
+    source: Res<ActionSource>,
+    settings: Res<Settings>,
+    pause: Res<MatchPause>,
+    menu: Res<OptionsMenu>,
+    buttons: Res<Input<GamepadButton>>,
+    mut query: PadPlayers,
+) {
+    if *source == ActionSource::Replay {
+        return;
+    }
+
+    let latching = !pause.is_paused() && !menu.open;
+    for (gamepad, slot, sticks, mut actions) in query.iter_mut() {
+        let jump = GamepadButton(*gamepad, settings.input.bindings.jump.into());
+        let fire = GamepadButton(*gamepad, settings.input.bindings.fire.into());
+
+        // Snapped before it's recorded, so replays don't need to know about the setting
+        let aim = if settings.input.sticks(slot.0).eight_way_aim {
+            snap_eight_way(sticks.aim)
+        } else {
+            sticks.aim
+        };
+
+        let latched = PlayerActions {
+            movement: sticks.movement,
+            aim,
+            jump: actions.jump || (latching && buttons.just_pressed(jump)),
+            fire: actions.fire || (latching && buttons.just_pressed(fire)),
+        };
+        *actions = latched.pack().unpack();
+    }
+}
+
+fn clear_pressed_actions(mut query: Query<&mut PlayerActions>) {
+    for mut actions in query.iter_mut() {
+        actions.jump = false;
+        actions.fire = false;
+    }
+}
@@ -0,0 +1,145 @@
+use super::actions::*;
+use super::player::*;
+use super::simulation::*;
+use bevy::prelude::*;
+use std::f32::consts::FRAC_PI_4;
+
+// How far in front of the player the reticle floats
+const RETICLE_DISTANCE: f32 = 24.;
+const RETICLE_SIZE: f32 = 4.;
+// Opponents within this angle either side of the aim get helped, in radians (about 15 degrees)
+const AIM_ASSIST_CONE: f32 = 0.26;
+const AIM_ASSIST_RANGE: f32 = 350.;
+// 0 leaves the shot alone, 1 aims it straight at the target
+const AIM_ASSIST_STRENGTH: f32 = 0.5;
+
+pub struct AimPlugin;
+impl Plugin for AimPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(AimSettings::default())
+            .add_system_to_stage(
+                SIMULATION,
+                resolve_aim
+                    .system()
+                    .label("resolve_aim")
+                    .after("external_actions")
+                    .before("player_input"),
+            )
+            .add_system(spawn_reticles.system())
+            .add_system(update_reticles.system());
+    }
+}
+
+// Changes what the simulation does, so replays carry it too
+#[derive(Default)]
+pub struct AimSettings {
+    pub assist: bool,
+}
+
+// Where a shot would go right now, always unit length
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AimDirection(pub Vec2);
+
+impl Default for AimDirection {
+    fn default() -> AimDirection {
+        AimDirection(Vec2::X)
+    }
+}
+
+struct Reticle {
+    player: Entity,
+}
+
+// Rounds the stick to the nearest of the 8 compass directions, keeping how far it's pushed
+pub fn snap_eight_way(stick: Vec2) -> Vec2 {
+    if stick == Vec2::ZERO {
+        return stick;
+    }
+
+    let angle = (stick.y.atan2(stick.x) / FRAC_PI_4).round() * FRAC_PI_4;
+    Vec2::new(angle.cos(), angle.sin()) * stick.length()
+}
+
+// Bends the aim part of the way toward the closest opponent inside the cone
+fn assist(direction: Vec2, from: Vec2, opponents: impl Iterator<Item = Vec2>) -> Vec2 {
+    let target = opponents
+        .filter_map(|opponent| {
+            let offset = opponent - from;
+            let distance = offset.length();
+            if distance == 0. || distance > AIM_ASSIST_RANGE {
+                return None;
+            }
+            if direction.angle_between(offset).abs() > AIM_ASSIST_CONE {
+                return None;
+            }
+            Some((distance, offset / distance))
+        })
+        .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    match target {
+        Some((_, toward)) => direction.lerp(toward, AIM_ASSIST_STRENGTH).normalize(),
+        None => direction,
+    }
+}
+
+fn resolve_aim(
+    settings: Res<AimSettings>,
+    mut query: Query<(Entity, &Transform, &Speed, &PlayerActions, &mut AimDirection), With<Player>>,
+    opponents: Query<(Entity, &Transform), With<Player>>,
+) {
+    for (player_entity, transform, speed, actions, mut aim) in query.iter_mut() {
+        // A neutral stick shoots the way the player is facing
+        let direction = if actions.aim != Vec2::ZERO {
+            actions.aim.normalize()
+        } else if speed.0 < 0. {
+            -Vec2::X
+        } else {
+            Vec2::X
+        };
+
+        aim.0 = if settings.assist {
+            let from = transform.translation.truncate();
+            let others = opponents
+                .iter()
+                .filter(|(entity, _)| *entity != player_entity)
+                .map(|(_, transform)| transform.translation.truncate());
+            assist(direction, from, others)
+        } else {
+            direction
+        };
+    }
+}
+
+fn spawn_reticles(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    query: Query<Entity, Added<Player>>,
+) {
+    for player_entity in query.iter() {
+        commands
+            .spawn_bundle(SpriteBundle {
+                material: materials.add(Color::rgba(1., 1., 1., 0.6).into()),
+                sprite: Sprite::new(Vec2::new(RETICLE_SIZE, RETICLE_SIZE)),
+                ..Default::default()
+            })
+            .insert(Reticle {
+                player: player_entity,
+            });
+    }
+}
+
+fn update_reticles(
+    mut commands: Commands,
+    players: Query<(&Transform, &AimDirection), With<Player>>,
+    mut query: Query<(Entity, &Reticle, &mut Transform), Without<Player>>,
+) {
+    for (reticle_entity, reticle, mut transform) in query.iter_mut() {
+        match players.get(reticle.player) {
+            Ok((player_transform, aim)) => {
+                let offset = aim.0 * RETICLE_DISTANCE;
+                transform.translation = player_transform.translation + offset.extend(1.);
+            }
+            Err(_) => commands.entity(reticle_entity).despawn(),
+        }
+    }
+}
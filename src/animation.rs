@@ -0,0 +1,201 @@
+use super::character::*;
+use super::events::*;
+use super::hit_stop::*;
+use super::pause::*;
+use super::player::*;
+use super::teams::*;
+use bevy::prelude::*;
+use heron::prelude::*;
+use serde::Deserialize;
+
+// How long event driven states stick around after the event, in seconds
+const HURT_DURATION: f32 = 0.3;
+const ATTACK_DURATION: f32 = 0.15;
+const KO_DURATION: f32 = 0.5;
+
+// Vertical speed under which a player who still has both jumps counts as standing
+const GROUNDED_SPEED: f32 = 5.;
+
+pub struct AnimationPlugin;
+impl Plugin for AnimationPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_system(track_animation_events.system().label("track_animation_events"))
+            .add_system(
+                choose_animation_state
+                    .system()
+                    .label("choose_animation_state")
+                    .after("track_animation_events"),
+            )
+            .add_system(animate_sprites.system().after("choose_animation_state"));
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum AnimationState {
+    Idle,
+    Run,
+    Jump,
+    Fall,
+    Hurt,
+    Attack,
+    Ko,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct AnimationClip {
+    pub frames: Vec<u32>,
+    pub fps: f32,
+    #[serde(default = "default_looping")]
+    pub looping: bool,
+    #[serde(default = "default_tint")]
+    pub tint: (f32, f32, f32),
+}
+
+fn default_looping() -> bool {
+    true
+}
+
+fn default_tint() -> (f32, f32, f32) {
+    (1., 1., 1.)
+}
+
+pub struct Animator {
+    pub state: AnimationState,
+    frame: usize,
+    elapsed: f32,
+    last_x: f32,
+    hurt_time: f32,
+    attack_time: f32,
+    ko_time: f32,
+}
+
+impl Default for Animator {
+    fn default() -> Animator {
+        Animator {
+            state: AnimationState::Idle,
+            frame: 0,
+            elapsed: 0.,
+            last_x: 0.,
+            hurt_time: 0.,
+            attack_time: 0.,
+            ko_time: 0.,
+        }
+    }
+}
+
+fn track_animation_events(
+    mut hit_events: EventReader<PlayerHitEvent>,
+    mut fire_events: EventReader<PlayerFireEvent>,
+    mut ko_events: EventReader<PlayerKoEvent>,
+    mut query: Query<&mut Animator>,
+) {
+    for event in hit_events.iter() {
+        if let Ok(mut animator) = query.get_mut(event.victim) {
+            animator.hurt_time = HURT_DURATION;
+        }
+    }
+    for event in fire_events.iter() {
+        if let Ok(mut animator) = query.get_mut(event.player) {
+            animator.attack_time = ATTACK_DURATION;
+        }
+    }
+    for event in ko_events.iter() {
+        if let Ok(mut animator) = query.get_mut(event.player) {
+            animator.ko_time = KO_DURATION;
+        }
+    }
+}
+
+// What a player's doing, as far as which animation to show goes
+type AnimatedPlayers<'a> = Query<
+    'a,
+    (
+        &'static Transform,
+        &'static Velocity,
+        &'static AvailableJumps,
+        Option<&'static HitStop>,
+        &'static mut Animator,
+    ),
+    With<Player>,
+>;
+
+fn choose_animation_state(time: Res<Time>, pause: Res<MatchPause>, mut query: AnimatedPlayers) {
+    if pause.is_paused() {
+        return;
+    }
+    let delta = time.delta_seconds();
+
+    for (transform, velocity, available_jumps, hit_stop, mut animator) in query.iter_mut() {
+        animator.hurt_time = (animator.hurt_time - delta).max(0.);
+        animator.attack_time = (animator.attack_time - delta).max(0.);
+        animator.ko_time = (animator.ko_time - delta).max(0.);
+
+        let moved_x = (transform.translation.x - animator.last_x).abs() > 0.01;
+        animator.last_x = transform.translation.x;
+
+        let grounded =
+            available_jumps.0 == MAX_JUMPS && velocity.linear.y.abs() < GROUNDED_SPEED;
+
+        let state = if animator.ko_time > 0. {
+            AnimationState::Ko
+        } else if hit_stop.is_some() || animator.hurt_time > 0. {
+            AnimationState::Hurt
+        } else if animator.attack_time > 0. {
+            AnimationState::Attack
+        } else if !grounded && velocity.linear.y > 0. {
+            AnimationState::Jump
+        } else if !grounded {
+            AnimationState::Fall
+        } else if moved_x {
+            AnimationState::Run
+        } else {
+            AnimationState::Idle
+        };
+
+        if state != animator.state {
+            animator.state = state;
+            animator.frame = 0;
+            animator.elapsed = 0.;
+        }
+    }
+}
+
+// Facing is handled by flipping, so every clip is drawn facing right
+fn animate_sprites(
+    time: Res<Time>,
+    pause: Res<MatchPause>,
+    library: Res<CharacterLibrary>,
+    mut query: Query<(&mut Animator, &PlayerCharacter, &Speed, Option<&Team>, &mut TextureAtlasSprite)>,
+) {
+    for (mut animator, character, speed, team, mut sprite) in query.iter_mut() {
+        sprite.flip_x = speed.0 < 0.;
+
+        let clips = match library.get(character.0) {
+            Some(character) => &character.definition.clips,
+            None => continue,
+        };
+        let clip = match clips
+            .get(&animator.state)
+            .or_else(|| clips.get(&AnimationState::Idle))
+        {
+            Some(clip) if !clip.frames.is_empty() => clip,
+            _ => continue,
+        };
+
+        if !pause.is_paused() {
+            animator.elapsed += time.delta_seconds();
+        }
+        let frames_played = (animator.elapsed * clip.fps) as usize;
+        animator.frame = if clip.looping {
+            frames_played % clip.frames.len()
+        } else {
+            frames_played.min(clip.frames.len() - 1)
+        };
+
+        // Teammates share a colour on top of whatever the clip does
+        let (r, g, b) = clip.tint;
+        let team = team.map_or(Color::WHITE, |team| team.color());
+        sprite.index = clip.frames[animator.frame];
+        sprite.color = Color::rgb(r * team.r(), g * team.g(), b * team.b());
+    }
+}
@@ -0,0 +1,10 @@
+// Where the game as a whole is at. Gameplay only ticks during Match.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum AppState {
+    Lobby,
+    Match,
+    // Passed straight through on the way from a match back into a new one
+    Restarting,
+    // How the last match went
+    Results,
+}
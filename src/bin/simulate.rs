@@ -0,0 +1,314 @@
+use bevy::app::{Events, ManualEventReader};
+use bevy::prelude::*;
+use bevy_playground::app_state::*;
+use bevy_playground::bot::*;
+use bevy_playground::character::*;
+use bevy_playground::events::*;
+use bevy_playground::headless_app;
+use bevy_playground::lobby::*;
+use bevy_playground::player::*;
+use bevy_playground::simulation::*;
+use bevy_playground::stats::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
+
+// Plays bot-only matches with no window, as fast as the machine allows, and writes how each
+// character got on to a CSV. For checking what a change to knockback, gravity or the weapons
+// does to the balance.
+//
+//   cargo run --release --bin simulate -- --matches 200 --bots hard,medium --out balance.csv
+//
+// Characters take turns in each slot, so every one gets played about as often. `--characters`
+// narrows it down to some of them, `--seed` picks the first match's MatchSeed and
+// `--max-seconds` ends a match that's going nowhere.
+
+const USAGE: &str = "Usage: simulate [--matches N] [--bots hard,easy,...] [--characters Pig,Rat,...] \
+                     [--seed N] [--max-seconds N] [--out FILE]";
+
+struct Config {
+    matches: usize,
+    bots: Vec<BotDifficulty>,
+    // Names, or empty for everyone
+    characters: Vec<String>,
+    seed: u64,
+    max_ticks: u64,
+    out: PathBuf,
+}
+
+impl Config {
+    fn from_args() -> Result<Config, String> {
+        let args: Vec<String> = std::env::args().collect();
+        let value = |name: &str| {
+            args.iter()
+                .position(|arg| arg == name)
+                .and_then(|index| args.get(index + 1))
+        };
+        let number = |name: &str, default: u64| match value(name) {
+            Some(number) => number.parse().map_err(|_| format!("{} needs a number, got {}", name, number)),
+            None => Ok(default),
+        };
+
+        let bots = value("--bots")
+            .map_or("hard,hard", |bots| bots.as_str())
+            .split(',')
+            .map(|name| {
+                BotDifficulty::from_name(name).ok_or_else(|| format!("No bot difficulty called {}", name))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if bots.len() < 2 || bots.len() > MAX_PLAYERS {
+            return Err(format!("Need 2 to {} bots", MAX_PLAYERS));
+        }
+
+        Ok(Config {
+            matches: number("--matches", 100)? as usize,
+            bots,
+            characters: value("--characters").map_or(Vec::new(), |names| {
+                names.split(',').map(|name| name.to_string()).collect()
+            }),
+            seed: number("--seed", 0)?,
+            max_ticks: number("--max-seconds", 180)? * TICK_RATE as u64,
+            out: PathBuf::from(value("--out").map_or("simulation.csv", |out| out.as_str())),
+        })
+    }
+}
+
+// How one player did in one match
+#[derive(Default)]
+struct PlayerResult {
+    character: String,
+    won: bool,
+    stocks_lost: u32,
+    // Added up over every stock they lost
+    stock_ticks: u64,
+    damage_dealt: f32,
+    damage_taken: f32,
+    kos_scored: u32,
+}
+
+#[derive(Default)]
+struct CharacterStats {
+    matches: u32,
+    wins: u32,
+    stocks_lost: u32,
+    stock_ticks: u64,
+    damage_dealt: f32,
+    damage_taken: f32,
+    kos_scored: u32,
+}
+
+impl CharacterStats {
+    fn add(&mut self, result: &PlayerResult) {
+        self.matches += 1;
+        self.wins += result.won as u32;
+        self.stocks_lost += result.stocks_lost;
+        self.stock_ticks += result.stock_ticks;
+        self.damage_dealt += result.damage_dealt;
+        self.damage_taken += result.damage_taken;
+        self.kos_scored += result.kos_scored;
+    }
+}
+
+fn main() {
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let started = Instant::now();
+    let mut stats: BTreeMap<String, CharacterStats> = BTreeMap::new();
+    let mut draws = 0;
+    let mut total_ticks = 0;
+
+    for index in 0..config.matches {
+        let (results, ticks) = match run_match(&config, index) {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                eprintln!("Match {} failed. {}", index + 1, err);
+                process::exit(1);
+            }
+        };
+        total_ticks += ticks;
+        if !results.iter().any(|result| result.won) {
+            draws += 1;
+        }
+        for result in results.iter() {
+            stats.entry(result.character.clone()).or_default().add(result);
+        }
+        println!("Match {}/{} over after {:.1}s", index + 1, config.matches, ticks as f64 / TICK_RATE);
+    }
+
+    if let Err(err) = write_csv(&config.out, &stats) {
+        eprintln!("Failed to write {}. {}", config.out.display(), err);
+        process::exit(1);
+    }
+
+    let simulated = total_ticks as f64 / TICK_RATE;
+    let elapsed = started.elapsed().as_secs_f64();
+    println!(
+        "{} matches, {} draws, {:.0}s of play in {:.1}s ({:.0}x real time). Written to {}",
+        config.matches,
+        draws,
+        simulated,
+        elapsed,
+        simulated / elapsed.max(0.001),
+        config.out.display(),
+    );
+}
+
+fn run_match(config: &Config, index: usize) -> Result<(Vec<PlayerResult>, u64), String> {
+    let mut builder = headless_app();
+    builder.insert_resource(MatchSeed(config.seed + index as u64));
+    let mut app = builder.app;
+    // Characters load on the first update
+    app.update();
+
+    let library = app.world.get_resource::<CharacterLibrary>().ok_or("No characters loaded")?;
+    let pool: Vec<usize> = if config.characters.is_empty() {
+        (0..library.characters.len()).collect()
+    } else {
+        config
+            .characters
+            .iter()
+            .map(|name| {
+                library
+                    .characters
+                    .iter()
+                    .position(|character| character.definition.name == *name)
+                    .ok_or_else(|| format!("No character called {}", name))
+            })
+            .collect::<Result<_, _>>()?
+    };
+    if pool.is_empty() {
+        return Err("No characters to play as".to_string());
+    }
+    let roster: Vec<usize> = (0..config.bots.len())
+        .map(|slot| pool[(index * config.bots.len() + slot) % pool.len()])
+        .collect();
+    let names: Vec<String> = roster
+        .iter()
+        .map(|character| library.characters[*character].definition.name.clone())
+        .collect();
+
+    let mut lobby = app.world.get_resource_mut::<Lobby>().unwrap();
+    for (difficulty, character) in config.bots.iter().zip(roster.iter()) {
+        lobby.add_bot(*difficulty, *character);
+    }
+    // Nobody's there to ready up, so skip straight past the lobby
+    app.world
+        .get_resource_mut::<State<AppState>>()
+        .unwrap()
+        .set(AppState::Match)
+        .map_err(|err| format!("{:?}", err))?;
+    app.update();
+
+    let players: HashMap<Entity, usize> = app
+        .world
+        .query_filtered::<(Entity, &PlayerSlot), With<Player>>()
+        .iter(&app.world)
+        .map(|(entity, slot)| (entity, slot.0))
+        .collect();
+    let mut results: Vec<PlayerResult> = names
+        .into_iter()
+        .map(|character| PlayerResult {
+            character,
+            ..Default::default()
+        })
+        .collect();
+
+    let start_tick = app.world.get_resource::<SimulationClock>().unwrap().tick;
+    let mut stock_started = vec![start_tick; results.len()];
+    let mut hit_reader: ManualEventReader<PlayerHitEvent> = Default::default();
+    let mut ko_reader: ManualEventReader<PlayerKoEvent> = Default::default();
+
+    loop {
+        app.update();
+        let tick = app.world.get_resource::<SimulationClock>().unwrap().tick;
+
+        let hits = app.world.get_resource::<Events<PlayerHitEvent>>().unwrap();
+        for hit in hit_reader.iter(hits) {
+            if let Some(victim) = players.get(&hit.victim) {
+                results[*victim].damage_taken += hit.damage;
+            }
+            if let Some(slot) = hit.attacker.and_then(|attacker| players.get(&attacker)) {
+                results[*slot].damage_dealt += hit.damage;
+            }
+        }
+
+        let kos = app.world.get_resource::<Events<PlayerKoEvent>>().unwrap();
+        for ko in ko_reader.iter(kos) {
+            if let Some(slot) = players.get(&ko.player) {
+                results[*slot].stocks_lost += 1;
+                results[*slot].stock_ticks += tick - stock_started[*slot];
+                stock_started[*slot] = tick;
+            }
+            // Falling off without being hit since the last stock doesn't count for anyone
+            if let Some(slot) = ko.last_attacker.and_then(|attacker| players.get(&attacker)) {
+                results[*slot].kos_scored += 1;
+            }
+        }
+
+        // The game ends it itself once there's one left
+        let state = app.world.get_resource::<State<AppState>>().unwrap();
+        if *state.current() == AppState::Results {
+            if let Some(slot) = app.world.get_resource::<MatchStats>().unwrap().winner() {
+                results[slot].won = true;
+            }
+            return Ok((results, tick - start_tick));
+        }
+
+        if tick - start_tick < config.max_ticks {
+            continue;
+        }
+        let mut standing: Vec<(usize, i8, f32)> = app
+            .world
+            .query_filtered::<(&PlayerSlot, &Lives, &DamageTaken), With<Player>>()
+            .iter(&app.world)
+            .map(|(slot, lives, damage)| (slot.0, lives.0, damage.0))
+            .collect();
+
+        // Out of time goes to whoever has the most lives, then the least damage
+        standing.sort_by(|a, b| b.1.cmp(&a.1).then(a.2.partial_cmp(&b.2).unwrap()));
+        let tied = standing.len() > 1 && standing[0].1 == standing[1].1 && standing[0].2 == standing[1].2;
+        if let Some((slot, _, _)) = standing.first() {
+            results[*slot].won = !tied;
+        }
+        return Ok((results, tick - start_tick));
+    }
+}
+
+fn write_csv(path: &Path, stats: &BTreeMap<String, CharacterStats>) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    writeln!(
+        file,
+        "character,matches,wins,win_rate,kos_scored,stocks_lost,avg_stock_seconds,avg_damage_dealt,avg_damage_taken"
+    )?;
+    for (character, stats) in stats.iter() {
+        let matches = stats.matches.max(1) as f32;
+        let stock_seconds = if stats.stocks_lost > 0 {
+            stats.stock_ticks as f64 / stats.stocks_lost as f64 / TICK_RATE
+        } else {
+            0.
+        };
+        writeln!(
+            file,
+            "{},{},{},{:.3},{},{},{:.2},{:.2},{:.2}",
+            character,
+            stats.matches,
+            stats.wins,
+            stats.wins as f32 / matches,
+            stats.kos_scored,
+            stats.stocks_lost,
+            stock_seconds,
+            stats.damage_dealt / matches,
+            stats.damage_taken / matches,
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,365 @@
+use super::actions::*;
+use super::navigation::*;
+use super::player::*;
+use super::projectile::*;
+use super::simulation::*;
+use super::teams::*;
+use super::virtual_gamepad::*;
+use bevy::prelude::*;
+use heron::prelude::*;
+use rand::Rng;
+use std::collections::VecDeque;
+
+// How far ahead, past its reaction time, a bot looks for bullets coming its way
+const DODGE_LOOKAHEAD_TICKS: f32 = 10.;
+// Bullets passing closer than this count as a hit
+const DODGE_MARGIN: f32 = PLAYER_HALF_EXTENT * 2.;
+// Worth walking a bit further to finish off a damaged opponent
+const TARGET_DAMAGE_BIAS: f32 = 10.;
+// Higher than this above the bot and it jumps to get level
+const JUMP_TO_REACH: f32 = 40.;
+// Don't back off closer to the edge than this
+const EDGE_MARGIN: f32 = 24.;
+
+// CPU players fill in PlayerActions just like a gamepad would, so everything after that
+// can't tell the difference
+pub struct BotPlugin;
+impl Plugin for BotPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_system_to_stage(
+            SIMULATION,
+            bot_actions
+                .system()
+                .label("bot_actions")
+                .after("external_actions")
+                .after("reset_jumps")
+                .before("record_actions")
+                .before("resolve_aim"),
+        );
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BotDifficulty {
+    Easy,
+    Medium,
+    Hard,
+}
+
+impl BotDifficulty {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BotDifficulty::Easy => "easy",
+            BotDifficulty::Medium => "medium",
+            BotDifficulty::Hard => "hard",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BotDifficulty> {
+        match name {
+            "easy" => Some(BotDifficulty::Easy),
+            "medium" => Some(BotDifficulty::Medium),
+            "hard" => Some(BotDifficulty::Hard),
+            _ => None,
+        }
+    }
+
+    pub fn harder(&self) -> BotDifficulty {
+        match self {
+            BotDifficulty::Easy => BotDifficulty::Medium,
+            _ => BotDifficulty::Hard,
+        }
+    }
+
+    pub fn easier(&self) -> BotDifficulty {
+        match self {
+            BotDifficulty::Hard => BotDifficulty::Medium,
+            _ => BotDifficulty::Easy,
+        }
+    }
+
+    pub fn skill(&self) -> BotSkill {
+        match self {
+            BotDifficulty::Easy => BotSkill {
+                reaction_ticks: 30,
+                aim_error: 0.35,
+                dodge_chance: 0.1,
+                fire_interval: 45,
+                preferred_range: 160.,
+            },
+            BotDifficulty::Medium => BotSkill {
+                reaction_ticks: 15,
+                aim_error: 0.15,
+                dodge_chance: 0.4,
+                fire_interval: 25,
+                preferred_range: 200.,
+            },
+            BotDifficulty::Hard => BotSkill {
+                reaction_ticks: 6,
+                aim_error: 0.04,
+                dodge_chance: 0.85,
+                fire_interval: 12,
+                preferred_range: 240.,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BotSkill {
+    // Ticks between taking a look around and acting on it
+    pub reaction_ticks: u32,
+    // Most a shot can be off by either way, in radians
+    pub aim_error: f32,
+    // 0 to 1, chance of jumping out of the way of a bullet it has seen coming
+    pub dodge_chance: f32,
+    // Ticks between shots
+    pub fire_interval: u32,
+    // Distance it tries to keep from its target
+    pub preferred_range: f32,
+}
+
+pub struct Bot {
+    pub skill: BotSkill,
+    target: Option<Entity>,
+    // What it decided last time it looked, held until it looks again
+    movement: Vec2,
+    aim: Vec2,
+    next_decision: u32,
+    next_shot: u32,
+    // Moves left to get to the target's platform, one per tick
+    route: VecDeque<PlayerActions>,
+}
+
+impl Bot {
+    pub fn new(difficulty: BotDifficulty) -> Bot {
+        Bot::with_skill(difficulty.skill())
+    }
+
+    pub fn with_skill(skill: BotSkill) -> Bot {
+        Bot {
+            skill,
+            target: None,
+            movement: Vec2::ZERO,
+            aim: Vec2::X,
+            next_decision: 0,
+            next_shot: skill.fire_interval,
+            route: VecDeque::new(),
+        }
+    }
+
+    pub fn target(&self) -> Option<Entity> {
+        self.target
+    }
+}
+
+// One per slot, from the made up range for bots
+pub fn bot_gamepad(slot: usize) -> Gamepad {
+    Gamepad(FIRST_BOT_GAMEPAD + slot)
+}
+
+// The stage as far as a bot cares, the outside edges and the main floor
+struct StageBounds {
+    left: f32,
+    right: f32,
+    floor: f32,
+}
+
+impl StageBounds {
+    fn from_platforms(platforms: &[Platform]) -> Option<StageBounds> {
+        let mut bounds: Option<StageBounds> = None;
+        for platform in platforms {
+            bounds = Some(match bounds {
+                Some(bounds) => StageBounds {
+                    left: bounds.left.min(platform.left),
+                    right: bounds.right.max(platform.right),
+                    floor: bounds.floor.min(platform.top),
+                },
+                None => StageBounds {
+                    left: platform.left,
+                    right: platform.right,
+                    floor: platform.top,
+                },
+            });
+        }
+        bounds
+    }
+
+    fn center(&self) -> f32 {
+        (self.left + self.right) / 2.
+    }
+
+    fn is_off(&self, position: Vec2) -> bool {
+        position.x < self.left || position.x > self.right || position.y < self.floor
+    }
+}
+
+// Whether any bullet someone else fired will pass through the bot soon
+fn bullet_incoming<'a>(
+    bot: Entity,
+    position: Vec2,
+    within_ticks: f32,
+    projectiles: impl Iterator<Item = (&'a Transform, &'a Velocity, &'a ProjectileOwner)>,
+) -> bool {
+    projectiles
+        .filter(|(_, _, owner)| owner.0 != bot)
+        .any(|(transform, velocity, _)| {
+            let per_tick = velocity.linear.truncate() / TICK_RATE as f32;
+            let speed = per_tick.length();
+            if speed == 0. {
+                return false;
+            }
+
+            let offset = position - transform.translation.truncate();
+            let direction = per_tick / speed;
+            let along = offset.dot(direction);
+            let across = (offset - direction * along).length();
+            along > 0. && along / speed <= within_ticks && across < DODGE_MARGIN
+        })
+}
+
+// The bots themselves, and what they go on to pick their next move
+type Bots<'a> = Query<
+    'a,
+    (
+        Entity,
+        &'static mut Bot,
+        &'static Transform,
+        &'static Velocity,
+        &'static AvailableJumps,
+        &'static mut PlayerActions,
+    ),
+    With<Player>,
+>;
+
+fn bot_actions(
+    source: Res<ActionSource>,
+    mut rng: ResMut<GameRng>,
+    graph: Res<NavGraph>,
+    teams: Res<Teams>,
+    players: Query<(Entity, &Transform, &DamageTaken, Option<&Team>), With<Player>>,
+    projectiles: Query<(&Transform, &Velocity, &ProjectileOwner), With<Projectile>>,
+    mut bots: Bots,
+) {
+    // A replay already knows what the bots did
+    if *source == ActionSource::Replay {
+        return;
+    }
+
+    let bounds = match StageBounds::from_platforms(&graph.platforms) {
+        Some(bounds) => bounds,
+        None => return,
+    };
+
+    for (bot_entity, mut bot, transform, velocity, jumps, mut actions) in bots.iter_mut() {
+        let position = transform.translation.truncate();
+        let skill = bot.skill;
+        let mut jump = false;
+        let mut fire = false;
+
+        bot.next_shot = bot.next_shot.saturating_sub(1);
+        let off_stage = bounds.is_off(position);
+
+        // Getting back is a reflex, every tick: head for the middle and jump on the way down
+        let mut routing = false;
+        if off_stage {
+            bot.route.clear();
+            bot.movement = Vec2::new((bounds.center() - position.x).signum(), 0.);
+            jump = velocity.linear.y < 0. && jumps.0 > 0;
+        } else if let Some(step) = bot.route.pop_front() {
+            bot.movement = step.movement;
+            jump = step.jump;
+            routing = true;
+        }
+
+        if bot.next_decision > 0 {
+            bot.next_decision -= 1;
+        } else {
+            bot.next_decision = skill.reaction_ticks + rng.0.gen_range(0..=skill.reaction_ticks / 3);
+
+            // Closest opponent, leaning toward whoever has taken the most damage
+            let bot_team = players.get(bot_entity).ok().and_then(|(_, _, _, team)| team);
+            bot.target = players
+                .iter()
+                .filter(|(entity, _, _, team)| {
+                    *entity != bot_entity && !teams.are_teammates(bot_team, *team)
+                })
+                .map(|(entity, transform, damage, _)| {
+                    let distance = transform.translation.truncate().distance(position);
+                    (entity, distance - damage.0 * TARGET_DAMAGE_BIAS)
+                })
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+                .map(|(entity, _)| entity);
+            let target = bot.target.and_then(|target| players.get(target).ok());
+
+            if let Some((_, target_transform, _, _)) = target {
+                let target_position = target_transform.translation.truncate();
+                let offset = target_position - position;
+                let distance = offset.length();
+
+                // On another platform, work out how to get over there and follow that for a bit
+                let elsewhere = graph.platform_under(position) != graph.platform_under(target_position);
+                if !off_stage && !routing && elsewhere {
+                    if let Some(path) = graph.find_path(position, target_position) {
+                        bot.route = path.actions();
+                        routing = true;
+                    }
+                }
+
+                if !off_stage && !routing {
+                    // Close in to its favourite range, or back off if it's too close, but
+                    // not off the edge
+                    let toward = offset.x.signum();
+                    let away_room = if toward > 0. {
+                        position.x - bounds.left
+                    } else {
+                        bounds.right - position.x
+                    };
+                    let x = if offset.x.abs() > skill.preferred_range {
+                        toward
+                    } else if offset.x.abs() < skill.preferred_range / 2. && away_room > EDGE_MARGIN {
+                        -toward
+                    } else {
+                        0.
+                    };
+                    bot.movement = Vec2::new(x, 0.);
+                    jump = offset.y > JUMP_TO_REACH && jumps.0 == MAX_JUMPS;
+                }
+
+                if distance > 0. {
+                    let error = if skill.aim_error > 0. {
+                        rng.0.gen_range(-skill.aim_error..=skill.aim_error)
+                    } else {
+                        0.
+                    };
+                    let angle = offset.y.atan2(offset.x) + error;
+                    bot.aim = Vec2::new(angle.cos(), angle.sin());
+                }
+
+                if bot.next_shot == 0 && distance < skill.preferred_range * 2. {
+                    fire = true;
+                    bot.next_shot = skill.fire_interval;
+                }
+            }
+
+            let lookahead = skill.reaction_ticks as f32 + DODGE_LOOKAHEAD_TICKS;
+            if !off_stage
+                && !routing
+                && jumps.0 > 0
+                && bullet_incoming(bot_entity, position, lookahead, projectiles.iter())
+                && rng.0.gen::<f32>() < skill.dodge_chance
+            {
+                jump = true;
+            }
+        }
+
+        // Same rounding as a pad, so a replay of a bot comes out the same
+        let decided = PlayerActions {
+            movement: bot.movement,
+            aim: bot.aim,
+            jump: actions.jump || jump,
+            fire: actions.fire || fire,
+        };
+        *actions = decided.pack().unpack();
+    }
+}
@@ -0,0 +1,147 @@
+use super::options_menu::*;
+use super::settings::*;
+use super::stick::*;
+use bevy::prelude::*;
+
+// How long both sticks are sampled for
+const CALIBRATION_TIME: f32 = 2.;
+// Any more than this and the stick was almost certainly being held, not resting
+const MAX_REST_NOISE: f32 = 0.5;
+
+pub struct CalibrationPlugin;
+impl Plugin for CalibrationPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_event::<StartCalibrationEvent>()
+            .insert_resource(Calibration::default())
+            .add_startup_system(setup_calibration_text.system())
+            .add_system(start_calibration.system().label("start_calibration"))
+            .add_system(measure_rest_noise.system().after("start_calibration"))
+            .add_system(update_calibration_text.system());
+    }
+}
+
+// Starts measuring how far a player's sticks drift while nobody is touching them
+pub struct StartCalibrationEvent {
+    pub player: usize,
+    pub gamepad: Gamepad,
+}
+
+#[derive(Default)]
+pub struct Calibration {
+    run: Option<CalibrationRun>,
+}
+
+impl Calibration {
+    pub fn is_running(&self) -> bool {
+        self.run.is_some()
+    }
+}
+
+struct CalibrationRun {
+    player: usize,
+    gamepad: Gamepad,
+    elapsed: f32,
+    // Largest stick lengths seen so far
+    left: f32,
+    right: f32,
+}
+
+struct CalibrationText;
+
+fn setup_calibration_text(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    bottom: Val::Px(20.),
+                    left: Val::Px(20.),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: asset_server.load(MENU_FONT),
+                    font_size: 20.,
+                    color: Color::YELLOW,
+                },
+                Default::default(),
+            ),
+            visible: Visible {
+                is_visible: false,
+                is_transparent: true,
+            },
+            ..Default::default()
+        })
+        .insert(CalibrationText);
+}
+
+fn start_calibration(
+    mut calibration: ResMut<Calibration>,
+    mut start_events: EventReader<StartCalibrationEvent>,
+) {
+    if let Some(event) = start_events.iter().last() {
+        calibration.run = Some(CalibrationRun {
+            player: event.player,
+            gamepad: event.gamepad,
+            elapsed: 0.,
+            left: 0.,
+            right: 0.,
+        });
+    }
+}
+
+fn measure_rest_noise(
+    time: Res<Time>,
+    axes: Res<Axis<GamepadAxis>>,
+    file: Res<SettingsFile>,
+    mut calibration: ResMut<Calibration>,
+    mut settings: ResMut<Settings>,
+) {
+    let run = match calibration.run.as_mut() {
+        Some(run) => run,
+        None => return,
+    };
+
+    let gamepad = run.gamepad;
+    let left = raw_stick(&axes, gamepad, GamepadAxisType::LeftStickX, GamepadAxisType::LeftStickY);
+    let right = raw_stick(&axes, gamepad, GamepadAxisType::RightStickX, GamepadAxisType::RightStickY);
+    run.left = run.left.max(left.length());
+    run.right = run.right.max(right.length());
+    run.elapsed += time.delta_seconds();
+
+    if run.elapsed < CALIBRATION_TIME {
+        return;
+    }
+
+    let (player, left, right) = (run.player, run.left, run.right);
+    calibration.run = None;
+
+    if left > MAX_REST_NOISE || right > MAX_REST_NOISE {
+        warn!("P{} sticks moved during calibration, keeping the old values", player + 1);
+        return;
+    }
+
+    info!("P{} stick rest noise is {:.3} left, {:.3} right", player + 1, left, right);
+    settings.input.sticks_mut(player).rest_noise = (left, right);
+    settings.save(&file);
+}
+
+fn update_calibration_text(
+    calibration: Res<Calibration>,
+    mut query: Query<(&mut Text, &mut Visible), With<CalibrationText>>,
+) {
+    for (mut text, mut visible) in query.iter_mut() {
+        visible.is_visible = calibration.is_running();
+
+        if let Some(run) = &calibration.run {
+            text.sections[0].value = format!(
+                "Calibrating P{}. Let go of both sticks... {:.1}",
+                run.player + 1,
+                (CALIBRATION_TIME - run.elapsed).max(0.),
+            );
+        }
+    }
+}
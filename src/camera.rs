@@ -1,14 +1,188 @@
+use super::events::*;
+use super::pause::*;
+use super::window::*;
 use bevy::prelude::*;
+use bevy::render::camera::{Camera, CameraProjection, OrthographicProjection, ScalingMode};
+use bevy::window::{WindowCreated, WindowResized};
 
 pub struct CameraPlugin;
 impl Plugin for CameraPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        app.add_startup_system(setup_camera.system());
+        app.insert_resource(ScreenShakeSettings::default())
+            .insert_resource(ScreenShake::default())
+            .add_startup_system(setup_camera.system())
+            .add_system(fit_camera_to_window.system())
+            .add_system(add_trauma.system().label("add_trauma"))
+            .add_system(shake_camera.system().after("add_trauma"));
     }
 }
 
-fn setup_camera(mut commands: Commands, mut windows: ResMut<Windows>) {
-    commands.spawn_bundle(OrthographicCameraBundle::new_2d());
-    let window = windows.get_primary_mut().unwrap();
-    window.set_position(IVec2::new(100, 100));
+pub struct MainCamera;
+
+// Black bars covering everything outside the virtual resolution
+struct Letterbox;
+
+// Big enough to cover any window once scaled down
+const LETTERBOX_SIZE: f32 = 10000.;
+
+pub struct ScreenShakeSettings {
+    pub enabled: bool,
+    pub max_offset: f32,
+    pub max_roll: f32,
+    // Trauma lost per second
+    pub decay: f32,
+    pub hit_trauma: f32,
+    pub ko_trauma: f32,
+    // A projectile bursting on the map
+    pub explosion_trauma: f32,
+}
+
+impl Default for ScreenShakeSettings {
+    fn default() -> ScreenShakeSettings {
+        ScreenShakeSettings {
+            enabled: true,
+            max_offset: 12.,
+            max_roll: 0.05,
+            decay: 1.5,
+            hit_trauma: 0.4,
+            ko_trauma: 0.7,
+            explosion_trauma: 0.2,
+        }
+    }
+}
+
+// Trauma goes from 0 to 1, the actual shake is trauma squared so small bumps stay subtle
+#[derive(Default)]
+pub struct ScreenShake {
+    pub trauma: f32,
+}
+
+impl ScreenShake {
+    pub fn add_trauma(&mut self, amount: f32) {
+        self.trauma = (self.trauma + amount).min(1.);
+    }
+}
+
+// Knockback at which a hit adds its full trauma
+const FULL_TRAUMA_KNOCKBACK: f32 = 400.;
+
+fn setup_camera(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let mut camera = OrthographicCameraBundle::new_2d();
+    camera.orthographic_projection.scaling_mode = ScalingMode::None;
+
+    let black = materials.add(Color::BLACK.into());
+    let half_width = VIRTUAL_WIDTH / 2. + LETTERBOX_SIZE / 2.;
+    let half_height = VIRTUAL_HEIGHT / 2. + LETTERBOX_SIZE / 2.;
+    let bar_offsets = [
+        Vec2::new(-half_width, 0.),
+        Vec2::new(half_width, 0.),
+        Vec2::new(0., -half_height),
+        Vec2::new(0., half_height),
+    ];
+
+    commands
+        .spawn_bundle(camera)
+        .insert(MainCamera)
+        .with_children(|parent| {
+            for offset in bar_offsets.iter() {
+                parent
+                    .spawn_bundle(SpriteBundle {
+                        material: black.clone(),
+                        sprite: Sprite::new(Vec2::splat(LETTERBOX_SIZE)),
+                        // Just in front of the camera so the bars draw over everything
+                        transform: Transform::from_xyz(offset.x, offset.y, -1.),
+                        ..Default::default()
+                    })
+                    .insert(Letterbox);
+            }
+        });
+
+    commands.spawn_bundle(UiCameraBundle::default());
+}
+
+// Shows the virtual resolution at the largest whole-number scale that fits the window, so
+// pixel art stays crisp. Windows smaller than the virtual resolution get scaled down smoothly.
+fn fit_camera_to_window(
+    windows: Res<Windows>,
+    mut resized_events: EventReader<WindowResized>,
+    mut created_events: EventReader<WindowCreated>,
+    mut query: Query<(&mut Camera, &mut OrthographicProjection), With<MainCamera>>,
+) {
+    let resized = resized_events.iter().count() > 0;
+    let created = created_events.iter().count() > 0;
+    if !resized && !created {
+        return;
+    }
+
+    let window = if let Some(window) = windows.get_primary() {
+        window
+    } else {
+        return;
+    };
+
+    let width = window.physical_width() as f32;
+    let height = window.physical_height() as f32;
+    let fit = (width / VIRTUAL_WIDTH).min(height / VIRTUAL_HEIGHT);
+    let scale = if fit >= 1. { fit.floor() } else { fit };
+
+    for (mut camera, mut projection) in query.iter_mut() {
+        projection.left = -width / 2. / scale;
+        projection.right = width / 2. / scale;
+        projection.bottom = -height / 2. / scale;
+        projection.top = height / 2. / scale;
+        camera.projection_matrix = projection.get_projection_matrix();
+    }
+}
+
+fn add_trauma(
+    settings: Res<ScreenShakeSettings>,
+    mut shake: ResMut<ScreenShake>,
+    mut hit_events: EventReader<PlayerHitEvent>,
+    mut ko_events: EventReader<PlayerKoEvent>,
+    mut impact_events: EventReader<ProjectileImpactEvent>,
+) {
+    for event in hit_events.iter().filter(|event| !event.rerun) {
+        let strength = (event.knockback / FULL_TRAUMA_KNOCKBACK).min(1.);
+        shake.add_trauma(settings.hit_trauma * strength);
+    }
+    for _ in ko_events.iter().filter(|event| !event.rerun) {
+        shake.add_trauma(settings.ko_trauma);
+    }
+    for _ in impact_events.iter().filter(|event| !event.rerun) {
+        shake.add_trauma(settings.explosion_trauma);
+    }
+}
+
+fn shake_camera(
+    time: Res<Time>,
+    pause: Res<MatchPause>,
+    settings: Res<ScreenShakeSettings>,
+    mut shake: ResMut<ScreenShake>,
+    mut query: Query<&mut Transform, With<MainCamera>>,
+) {
+    // Holds still rather than shaking on the same spot
+    if pause.is_paused() {
+        return;
+    }
+    shake.trauma = (shake.trauma - settings.decay * time.delta_seconds()).max(0.);
+
+    let amount = if settings.enabled {
+        shake.trauma * shake.trauma
+    } else {
+        0.
+    };
+
+    for mut transform in query.iter_mut() {
+        let offset = Vec2::new(random_unit(), random_unit()) * settings.max_offset * amount;
+        transform.translation.x = offset.x;
+        transform.translation.y = offset.y;
+        transform.rotation = Quat::from_rotation_z(random_unit() * settings.max_roll * amount);
+    }
+}
+
+fn random_unit() -> f32 {
+    rand::random::<f32>() * 2. - 1.
 }
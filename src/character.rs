@@ -0,0 +1,109 @@
+use super::animation::*;
+use bevy::prelude::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+
+const CHARACTER_DIR: &str = "assets/characters";
+pub const DEFAULT_CHARACTER: &str = "Pig";
+
+pub struct CharacterPlugin;
+impl Plugin for CharacterPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_startup_system_to_stage(StartupStage::PreStartup, load_characters.system());
+    }
+}
+
+// One file per character in assets/characters
+#[derive(Deserialize)]
+pub struct CharacterDefinition {
+    pub name: String,
+    pub sprite: String,
+    pub frame_size: (f32, f32),
+    pub columns: usize,
+    pub rows: usize,
+    pub clips: HashMap<AnimationState, AnimationClip>,
+}
+
+pub struct Character {
+    pub definition: CharacterDefinition,
+    pub atlas: Handle<TextureAtlas>,
+}
+
+pub struct CharacterLibrary {
+    pub characters: Vec<Character>,
+}
+
+impl CharacterLibrary {
+    pub fn get(&self, index: usize) -> Option<&Character> {
+        self.characters.get(index)
+    }
+
+    pub fn default_index(&self) -> usize {
+        self.characters
+            .iter()
+            .position(|character| character.definition.name == DEFAULT_CHARACTER)
+            .unwrap_or(0)
+    }
+}
+
+// Index into the CharacterLibrary
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PlayerCharacter(pub usize);
+
+fn read_definitions() -> Vec<CharacterDefinition> {
+    let entries = match fs::read_dir(CHARACTER_DIR) {
+        Ok(entries) => entries,
+        Err(err) => {
+            error!("Failed to read {}. {}", CHARACTER_DIR, err);
+            return Vec::new();
+        }
+    };
+
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "ron"))
+        .collect();
+    // Keep the roster order stable between runs
+    paths.sort();
+
+    paths
+        .iter()
+        .filter_map(|path| {
+            let contents = fs::read_to_string(path).ok()?;
+            match ron::de::from_str(&contents) {
+                Ok(definition) => Some(definition),
+                Err(err) => {
+                    warn!("Skipping invalid character {}. {}", path.display(), err);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn load_characters(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+) {
+    let characters = read_definitions()
+        .into_iter()
+        .map(|definition| {
+            let (width, height) = definition.frame_size;
+            let texture = asset_server.load(definition.sprite.as_str());
+            let atlas = TextureAtlas::from_grid(
+                texture,
+                Vec2::new(width, height),
+                definition.columns,
+                definition.rows,
+            );
+            Character {
+                atlas: texture_atlases.add(atlas),
+                definition,
+            }
+        })
+        .collect();
+
+    commands.insert_resource(CharacterLibrary { characters });
+}
@@ -0,0 +1,62 @@
+use bevy::prelude::*;
+
+pub struct EventsPlugin;
+impl Plugin for EventsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_event::<PlayerHitEvent>()
+            .add_event::<PlayerKoEvent>()
+            .add_event::<PlayerFireEvent>()
+            .add_event::<PlayerJumpEvent>()
+            .add_event::<MatchStartEvent>()
+            .add_event::<ProjectileImpactEvent>();
+    }
+}
+
+// Everything sent from a simulation tick says whether that tick is being run again after a
+// netplay rollback. The simulation takes those in like any other, but they were already seen,
+// heard and felt the first time round, so sound, particles, shake and rumble skip them.
+
+// Sent when a projectile connects with a player. Knockback is the length of the velocity applied.
+pub struct PlayerHitEvent {
+    pub attacker: Option<Entity>,
+    pub victim: Entity,
+    // Added to the victim's DamageTaken
+    pub damage: f32,
+    pub knockback: f32,
+    pub direction: Vec3,
+    pub position: Vec3,
+    pub rerun: bool,
+}
+
+// Sent when a player leaves the blast zone, whether or not they have lives left. The last
+// attacker is whoever hit them last this stock, None if they went off on their own.
+pub struct PlayerKoEvent {
+    pub player: Entity,
+    pub position: Vec3,
+    pub last_attacker: Option<Entity>,
+    pub rerun: bool,
+}
+
+pub struct PlayerFireEvent {
+    pub player: Entity,
+    pub position: Vec3,
+    pub direction: Vec3,
+    pub rerun: bool,
+}
+
+// Jumps left counts the jump that was just used, so 0 means this was the last one
+pub struct PlayerJumpEvent {
+    pub player: Entity,
+    pub position: Vec3,
+    pub jumps_left: i8,
+    pub rerun: bool,
+}
+
+pub struct MatchStartEvent;
+
+// A projectile hitting the map. Direction is the way it was travelling.
+pub struct ProjectileImpactEvent {
+    pub position: Vec3,
+    pub direction: Vec3,
+    pub rerun: bool,
+}
@@ -1,12 +1,16 @@
 use bevy::ecs::world::WorldBorrowMut;
-use gilrs::ff::{BaseEffect, BaseEffectType, EffectBuilder, Replay, Ticks};
 use bevy::input::gamepad::GamepadEventRaw;
 use bevy::app::Events;
 use bevy::prelude::*;
 use bevy::prelude::{Gamepad};
 use gilrs::*;
-use std::thread;
-use std::time::Duration;
+use super::mapping::*;
+use std::fs;
+
+// Kept in assets so it can be updated without a rebuild
+const CONTROLLER_DB: &str = "assets/gamecontrollerdb.txt";
+// Same variable SDL itself reads, pointing at an extra mapping file
+const CONTROLLER_DB_ENV: &str = "SDL_GAMECONTROLLERCONFIG_FILE";
 
 pub fn convert_gamepad_id(gamepad_id: gilrs::GamepadId) -> Gamepad {
     Gamepad(gamepad_id.into())
@@ -51,11 +55,52 @@ pub fn convert_axis(axis: gilrs::Axis) -> Option<GamepadAxisType> {
     }
 }
 
+fn uuid_string(uuid: [u8; 16]) -> String {
+    uuid.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn track_pad(pads: &mut Option<WorldBorrowMut<ConnectedPads>>, mappings: Option<&CustomMappings>, gamepad: &gilrs::Gamepad) {
+    let info = PadInfo {
+        name: gamepad.name().to_string(),
+        uuid: uuid_string(gamepad.uuid()),
+    };
+
+    let custom = mappings.map_or(false, |mappings| mappings.get(&info.uuid).is_some());
+    if gamepad.mapping_source() != MappingSource::SdlMappings && !custom {
+        info!("No controller mapping for {}, use Map controller in the options if buttons are missing", info.name);
+    }
+
+    if let Some(pads) = pads.as_mut() {
+        pads.pads.insert(convert_gamepad_id(gamepad.id()), info);
+    }
+}
+
+fn read_mapping_files() -> Vec<String> {
+    let mut paths = vec![CONTROLLER_DB.to_string()];
+    if let Ok(path) = std::env::var(CONTROLLER_DB_ENV) {
+        paths.push(path);
+    }
+
+    paths
+        .into_iter()
+        .filter_map(|path| match fs::read_to_string(&path) {
+            Ok(mappings) => Some(mappings),
+            Err(err) => {
+                warn!("Failed to read controller mappings {}. {}", path, err);
+                None
+            }
+        })
+        .collect()
+}
+
 pub fn gilrs_event_startup_system(world: &mut World) {
     let world = world.cell();
     let gilrs = world.get_non_send::<Gilrs>().unwrap();
     let mut event = world.get_resource_mut::<Events<GamepadEventRaw>>().unwrap();
-    for (id, _) in gilrs.gamepads() {
+    let mut pads = world.get_resource_mut::<ConnectedPads>();
+    let mappings = world.get_resource::<CustomMappings>();
+    for (id, gamepad) in gilrs.gamepads() {
+        track_pad(&mut pads, mappings.as_deref(), &gamepad);
         event.send(GamepadEventRaw(
             convert_gamepad_id(id),
             GamepadEventType::Connected,
@@ -63,64 +108,65 @@ pub fn gilrs_event_startup_system(world: &mut World) {
     }
 }
 
-pub fn rumble(gamepad_ids: &[GamepadId]) {
-    let mut gilrs = Gilrs::new().unwrap();
-
-    println!("rumbling");
-    let duration = Ticks::from_ms(1000);
-    let effect = EffectBuilder::new()
-    .add_effect(BaseEffect {
-        kind: BaseEffectType::Strong { magnitude: 60_000 },
-        scheduling: Replay {
-            play_for: duration,
-            ..Default::default()
-        },
-        envelope: Default::default(),
-    })
-    .gamepads(gamepad_ids)
-    .finish(&mut gilrs)
-    .unwrap();
-    effect.play().unwrap();
-    println!("rumbling over");
-    // thread::sleep(Duration::from_secs(1));
-    // effect.stop().unwrap();
-}
-
 pub fn gilrs_event_system(world: &mut World) {
     let world = world.cell();
     let mut gilrs = world.get_non_send_mut::<Gilrs>().unwrap();
     let mut event = world.get_resource_mut::<Events<GamepadEventRaw>>().unwrap();
+    let mut raw_events = world.get_resource_mut::<Events<RawPadEvent>>();
+    let mut pads = world.get_resource_mut::<ConnectedPads>();
+    let mappings = world.get_resource::<CustomMappings>();
 
     event.update();
     while let Some(gilrs_event) = gilrs.next_event() {
+        let gamepad = convert_gamepad_id(gilrs_event.id);
+        let custom = pads
+            .as_ref()
+            .and_then(|pads| pads.pads.get(&gamepad))
+            .and_then(|pad| mappings.as_ref()?.get(&pad.uuid));
+
         match gilrs_event.event {
             EventType::Connected => {
+                track_pad(&mut pads, mappings.as_deref(), &gilrs.gamepad(gilrs_event.id));
                 event.send(GamepadEventRaw(
                     convert_gamepad_id(gilrs_event.id),
                     GamepadEventType::Connected,
                 ));
             }
             EventType::Disconnected => {
+                if let Some(pads) = pads.as_mut() {
+                    pads.pads.remove(&gamepad);
+                }
                 event.send(GamepadEventRaw(
                     convert_gamepad_id(gilrs_event.id),
                     GamepadEventType::Disconnected,
                 ));
             }
-            EventType::ButtonChanged(gilrs_button, value, _) => {
-                if let Some(button_type) = convert_button(gilrs_button) {
-                    event.send(GamepadEventRaw(
-                        convert_gamepad_id(gilrs_event.id),
-                        GamepadEventType::ButtonChanged(button_type, value),
-                    ));
-                    
+            EventType::ButtonChanged(gilrs_button, value, code) => {
+                if let Some(raw_events) = raw_events.as_mut() {
+                    raw_events.send(RawPadEvent { gamepad, code, value, is_axis: false });
+                }
+
+                let mapped = match custom.and_then(|custom| custom.translate(code, value, false)) {
+                    Some(mapped) => Some(mapped),
+                    None => convert_button(gilrs_button)
+                        .map(|button_type| GamepadEventType::ButtonChanged(button_type, value)),
+                };
+                if let Some(mapped) = mapped {
+                    event.send(GamepadEventRaw(gamepad, mapped));
                 }
             }
-            EventType::AxisChanged(gilrs_axis, value, _) => {
-                if let Some(axis_type) = convert_axis(gilrs_axis) {
-                    event.send(GamepadEventRaw(
-                        convert_gamepad_id(gilrs_event.id),
-                        GamepadEventType::AxisChanged(axis_type, value),
-                    ));
+            EventType::AxisChanged(gilrs_axis, value, code) => {
+                if let Some(raw_events) = raw_events.as_mut() {
+                    raw_events.send(RawPadEvent { gamepad, code, value, is_axis: true });
+                }
+
+                let mapped = match custom.and_then(|custom| custom.translate(code, value, true)) {
+                    Some(mapped) => Some(mapped),
+                    None => convert_axis(gilrs_axis)
+                        .map(|axis_type| GamepadEventType::AxisChanged(axis_type, value)),
+                };
+                if let Some(mapped) = mapped {
+                    event.send(GamepadEventRaw(gamepad, mapped));
                 }
             }
             _ => (),
@@ -129,47 +175,44 @@ pub fn gilrs_event_system(world: &mut World) {
     gilrs.inc();
 }
 
+// Gilrs is only inserted when a backend could be started, so anything that needs it should
+// check for it rather than assume it's there
 #[derive(Default)]
 pub struct GilrsPlugin;
 
 impl Plugin for GilrsPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        match GilrsBuilder::new()
+        let mut builder = GilrsBuilder::new()
             .with_default_filters(false)
             .set_update_state(false)
-            .build()
+            // SDL_GAMECONTROLLERCONFIG, the mapping strings themselves
+            .add_env_mappings(true);
+        for mappings in read_mapping_files() {
+            builder = builder.add_mappings(&mappings);
+        }
+
+        let gilrs = match builder.build()
         {
-            Ok(mut gilrs) => {
-
-                let test: Vec<GamepadId> = gilrs.gamepads().map(|(_id, _)| _id).collect();
-
-                let rumble = EffectBuilder::new()
-                .add_effect(BaseEffect {
-                    kind: BaseEffectType::Strong { magnitude: 60_000 },
-                    scheduling: Replay {
-                        play_for: Ticks::from_ms(200),
-                        ..Default::default()
-                    },
-                    envelope: Default::default(),
-                })
-                .gamepads(&test)
-                .finish(&mut gilrs)
-                .unwrap();
-
-                app
-                .insert_non_send_resource(gilrs)
-                .insert_non_send_resource(rumble)
-                    .add_startup_system_to_stage(
-                        StartupStage::PreStartup,
-                        gilrs_event_startup_system.exclusive_system(),
-                    )
-                    .add_system_to_stage(
-                        CoreStage::PreUpdate,
-                        gilrs_event_system.exclusive_system(),
-                    );
+            Ok(gilrs) => gilrs,
+            // Still usable, it just never reports any gamepads
+            Err(gilrs::Error::NotImplemented(dummy)) => {
+                warn!("Gamepads aren't supported on this platform, running without them");
+                dummy
             }
-            Err(err) => error!("Failed to start Gilrs. {}", err),
-        }
+            Err(err) => {
+                warn!("Failed to start Gilrs, running without gamepads. {}", err);
+                return;
+            }
+        };
 
+        app.insert_non_send_resource(gilrs)
+            .add_startup_system_to_stage(
+                StartupStage::PreStartup,
+                gilrs_event_startup_system.exclusive_system(),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                gilrs_event_system.exclusive_system().label("gilrs_events"),
+            );
     }
-}
\ No newline at end of file
+}
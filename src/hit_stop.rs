@@ -0,0 +1,111 @@
+use super::events::*;
+use super::player::*;
+use super::simulation::*;
+use bevy::prelude::*;
+use heron::prelude::*;
+
+pub struct HitStopPlugin;
+impl Plugin for HitStopPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(HitStopSettings::default())
+            .add_system_to_stage(
+                SIMULATION,
+                start_hit_stop
+                    .system()
+                    .label("start_hit_stop")
+                    .after("projectile_hit_player"),
+            )
+            .add_system_to_stage(
+                SIMULATION,
+                hold_hit_stop.system().label("hold_hit_stop").after("start_hit_stop"),
+            );
+    }
+}
+
+pub struct HitStopSettings {
+    pub enabled: bool,
+    // Frames of freeze per unit of knockback, so harder hits hang for longer
+    pub frames_per_knockback: f32,
+    pub min_frames: u32,
+    pub max_frames: u32,
+}
+
+impl Default for HitStopSettings {
+    fn default() -> HitStopSettings {
+        HitStopSettings {
+            enabled: true,
+            frames_per_knockback: 0.02,
+            min_frames: 2,
+            max_frames: 12,
+        }
+    }
+}
+
+impl HitStopSettings {
+    pub fn frames_for(&self, knockback: f32) -> u32 {
+        ((knockback * self.frames_per_knockback).round() as u32)
+            .max(self.min_frames)
+            .min(self.max_frames)
+    }
+}
+
+// Players carrying this are frozen in place and ignore input until it runs out
+#[derive(Clone, Copy)]
+pub struct HitStop {
+    pub frames_left: u32,
+    translation: Vec3,
+    velocity: Vec3,
+}
+
+fn start_hit_stop(
+    mut commands: Commands,
+    settings: Res<HitStopSettings>,
+    mut hit_events: EventReader<PlayerHitEvent>,
+    query: Query<(&Transform, &Velocity, Option<&HitStop>), With<Player>>,
+) {
+    for event in hit_events.iter() {
+        if !settings.enabled {
+            continue;
+        }
+
+        let frames = settings.frames_for(event.knockback);
+        let frozen = std::iter::once(event.victim).chain(event.attacker);
+
+        for entity in frozen {
+            if let Ok((transform, velocity, hit_stop)) = query.get(entity) {
+                // The victim resumes with the knockback it was just given, anyone else
+                // keeps whatever velocity they were frozen with
+                let velocity = match hit_stop {
+                    Some(hit_stop) if entity != event.victim => hit_stop.velocity,
+                    _ => velocity.linear,
+                };
+                let translation = match hit_stop {
+                    Some(hit_stop) => hit_stop.translation,
+                    None => transform.translation,
+                };
+
+                commands.entity(entity).insert(HitStop {
+                    frames_left: frames,
+                    translation,
+                    velocity,
+                });
+            }
+        }
+    }
+}
+
+fn hold_hit_stop(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut HitStop, &mut Transform, &mut Velocity)>,
+) {
+    for (entity, mut hit_stop, mut transform, mut velocity) in query.iter_mut() {
+        if hit_stop.frames_left == 0 {
+            velocity.linear = hit_stop.velocity;
+            commands.entity(entity).remove::<HitStop>();
+        } else {
+            hit_stop.frames_left -= 1;
+            transform.translation = hit_stop.translation;
+            velocity.linear = Vec3::ZERO;
+        }
+    }
+}
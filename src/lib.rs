@@ -0,0 +1,125 @@
+use bevy::app::PluginGroupBuilder;
+use bevy::log::LogPlugin;
+use bevy::prelude::*;
+use bevy::ecs::schedule::ShouldRun;
+use bevy::render::RenderStage;
+use bevy::render::renderer::{HeadlessRenderResourceContext, RenderResourceContext, SharedBuffers};
+use bevy::wgpu::WgpuPlugin;
+use bevy::winit::WinitPlugin;
+use std::sync::Once;
+
+pub mod _heron;
+pub mod actions;
+pub mod aim;
+pub mod animation;
+pub mod app_state;
+pub mod bot;
+pub mod calibration;
+pub mod camera;
+pub mod character;
+pub mod events;
+pub mod gamepad;
+pub mod gilrs_plugin;
+pub mod hit_stop;
+pub mod lobby;
+pub mod map;
+pub mod mapping;
+pub mod my_defaults;
+pub mod navigation;
+pub mod netplay;
+pub mod options_menu;
+pub mod particles;
+pub mod pause;
+pub mod player;
+pub mod projectile;
+pub mod replay;
+pub mod results;
+pub mod rumble;
+pub mod settings;
+pub mod simulation;
+pub mod snapshot;
+pub mod sound;
+pub mod stats;
+pub mod stick;
+pub mod teams;
+pub mod training;
+pub mod transport;
+pub mod virtual_gamepad;
+pub mod window;
+
+// Everything that makes up the game itself. Needs DefaultPlugins (or the headless subset)
+// and SettingsPlugin added first, and GilrsPlugin too if there should be real gamepads.
+pub struct GamePlugins;
+impl PluginGroup for GamePlugins {
+    fn build(&mut self, group: &mut PluginGroupBuilder) {
+        group
+            .add(simulation::SimulationPlugin)
+            .add(mapping::MappingPlugin)
+            .add(rumble::RumblePlugin)
+            .add(stick::StickPlugin)
+            .add(events::EventsPlugin)
+            .add(_heron::HeronPlugin)
+            .add(camera::CameraPlugin)
+            .add(map::MapPlugin)
+            .add(navigation::NavigationPlugin)
+            .add(character::CharacterPlugin)
+            .add(player::PlayerPlugin)
+            .add(lobby::LobbyPlugin)
+            .add(teams::TeamsPlugin)
+            .add(actions::ActionsPlugin)
+            .add(bot::BotPlugin)
+            .add(training::TrainingPlugin)
+            .add(aim::AimPlugin)
+            .add(replay::ReplayPlugin)
+            .add(netplay::NetplayPlugin)
+            .add(animation::AnimationPlugin)
+            .add(gamepad::GamepadPlugin)
+            .add(projectile::ProjectilePlugin)
+            .add(stats::StatsPlugin)
+            .add(results::ResultsPlugin)
+            .add(hit_stop::HitStopPlugin)
+            .add(pause::PausePlugin)
+            .add(particles::ParticlePlugin)
+            .add(options_menu::OptionsMenuPlugin)
+            .add(calibration::CalibrationPlugin)
+            .add(sound::SoundPlugin)
+            .add(virtual_gamepad::VirtualGamepadPlugin);
+    }
+}
+
+// The game without a window, a renderer or real gamepads, running one
+// simulation tick and one physics step per update. Settings are the defaults and match stats
+// aren't written to disk.
+// Drive it with VirtualGamepads and call `app.app.update()` to step it.
+pub fn headless_app() -> AppBuilder {
+    // Logging can only be set up once per process, and tests and simulations make plenty of these
+    static LOGGING: Once = Once::new();
+    let mut first = false;
+    LOGGING.call_once(|| first = true);
+
+    let mut app = App::build();
+    app.insert_resource(settings::SettingsFile(None))
+        .add_plugin(settings::SettingsPlugin)
+        .add_plugins_with(DefaultPlugins, |group| {
+            group.disable::<WinitPlugin>().disable::<WgpuPlugin>();
+            if !first {
+                group.disable::<LogPlugin>();
+            }
+            group
+        })
+        .add_plugins(GamePlugins)
+        // Stands in for the GPU so the render systems still have something to talk to
+        .insert_resource::<Box<dyn RenderResourceContext>>(Box::new(HeadlessRenderResourceContext::default()))
+        .insert_resource(SharedBuffers::new(4096))
+        .insert_resource(simulation::SimulationClock::stepped())
+        .insert_resource(stats::StatsExport(None));
+    // Drawing needs shaders compiled for a real GPU
+    app.app.schedule.stage(RenderStage::Draw, |stage: &mut SystemStage| {
+        stage.set_run_criteria(never.system())
+    });
+    app
+}
+
+fn never() -> ShouldRun {
+    ShouldRun::No
+}
@@ -0,0 +1,319 @@
+use super::app_state::*;
+use super::bot::*;
+use super::character::*;
+use super::options_menu::*;
+use super::player::*;
+use super::teams::*;
+use super::training::*;
+use bevy::prelude::*;
+
+// Pads have to press Start to get a slot, so a controller lying on the table doesn't end up
+// as a player
+pub struct LobbyPlugin;
+impl Plugin for LobbyPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_state(AppState::Lobby)
+            .insert_resource(Lobby::default())
+            .add_startup_system(setup_lobby_text.system())
+            .add_system_set(SystemSet::on_enter(AppState::Lobby).with_system(unready_everyone.system()))
+            .add_system_set(
+                SystemSet::on_update(AppState::Lobby)
+                    .with_system(leave_on_disconnect.system().label("lobby_connections"))
+                    .with_system(lobby_input.system().after("lobby_connections")),
+            )
+            .add_system_set(SystemSet::on_enter(AppState::Match).with_system(spawn_lobby_players.system()))
+            .add_system_set(SystemSet::on_exit(AppState::Match).with_system(despawn_players.system()))
+            .add_system(update_lobby_text.system());
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LobbySlot {
+    pub gamepad: Gamepad,
+    pub character: usize,
+    pub ready: bool,
+    // A CPU player, which has a made up gamepad and is always ready
+    pub bot: Option<BotDifficulty>,
+    // Only matters when teams are on
+    pub team: Team,
+}
+
+// Who's playing as P1 to P4. Kept between matches so everyone stays where they were.
+#[derive(Default)]
+pub struct Lobby {
+    pub slots: [Option<LobbySlot>; MAX_PLAYERS],
+}
+
+impl Lobby {
+    pub fn slot_of(&self, gamepad: Gamepad) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|slot| slot.map_or(false, |slot| slot.gamepad == gamepad))
+    }
+
+    // Takes the lowest free slot
+    pub fn join(&mut self, gamepad: Gamepad, character: usize) -> Option<usize> {
+        let index = self.slots.iter().position(|slot| slot.is_none())?;
+        self.slots[index] = Some(LobbySlot {
+            gamepad,
+            character,
+            ready: false,
+            bot: None,
+            team: Team(index % TEAM_COUNT),
+        });
+        Some(index)
+    }
+
+    // Takes the lowest free slot too
+    pub fn add_bot(&mut self, difficulty: BotDifficulty, character: usize) -> Option<usize> {
+        let index = self.slots.iter().position(|slot| slot.is_none())?;
+        self.slots[index] = Some(LobbySlot {
+            gamepad: bot_gamepad(index),
+            character,
+            ready: true,
+            bot: Some(difficulty),
+            team: Team(index % TEAM_COUNT),
+        });
+        Some(index)
+    }
+
+    // The bot in the highest slot, which is the one the difficulty buttons change
+    pub fn last_bot(&self) -> Option<usize> {
+        self.slots
+            .iter()
+            .rposition(|slot| slot.map_or(false, |slot| slot.bot.is_some()))
+    }
+
+    // Nobody has joined or someone is still picking. Bots are always ready, but a person has
+    // to be there to start it.
+    pub fn everyone_ready(&self) -> bool {
+        let mut joined = self.slots.iter().flatten();
+        joined.clone().any(|slot| slot.bot.is_none()) && joined.all(|slot| slot.ready)
+    }
+
+    // A team match needs someone on each side
+    pub fn teams_in_play(&self) -> usize {
+        let mut teams: Vec<Team> = self.slots.iter().flatten().map(|slot| slot.team).collect();
+        teams.sort_by_key(|team| team.0);
+        teams.dedup();
+        teams.len()
+    }
+}
+
+struct LobbyText;
+
+fn setup_lobby_text(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(120.),
+                    left: Val::Px(60.),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: asset_server.load(MENU_FONT),
+                    font_size: 24.,
+                    color: Color::WHITE,
+                },
+                Default::default(),
+            ),
+            visible: Visible {
+                is_visible: false,
+                is_transparent: true,
+            },
+            ..Default::default()
+        })
+        .insert(LobbyText);
+}
+
+// Coming back from a match, everyone picks again
+fn unready_everyone(mut lobby: ResMut<Lobby>) {
+    for slot in lobby.slots.iter_mut().flatten() {
+        slot.ready = slot.bot.is_some();
+    }
+}
+
+fn leave_on_disconnect(mut lobby: ResMut<Lobby>, mut gamepad_evr: EventReader<GamepadEvent>) {
+    for GamepadEvent(gamepad, kind) in gamepad_evr.iter() {
+        if *kind != GamepadEventType::Disconnected {
+            continue;
+        }
+        if let Some(index) = lobby.slot_of(*gamepad) {
+            lobby.slots[index] = None;
+        }
+    }
+}
+
+// Start joins, then readies up. Back un-readies, then leaves. Left and right pick a character.
+// North adds a bot and East takes one away, up and down make the last one harder or easier.
+// West switches training on and off. The left stick button switches teams on and off and the
+// right one friendly fire, R1 changes side and L1 changes the last bot's side.
+fn lobby_input(
+    buttons: Res<Input<GamepadButton>>,
+    library: Res<CharacterLibrary>,
+    menu: Res<OptionsMenu>,
+    mut training: ResMut<Training>,
+    mut teams: ResMut<Teams>,
+    mut lobby: ResMut<Lobby>,
+    mut state: ResMut<State<AppState>>,
+) {
+    if menu.open {
+        return;
+    }
+
+    let character_count = library.characters.len().max(1);
+    // Pads that press Start on the same frame get slots in pad order, not hash order, so the
+    // same inputs always seat the same players
+    let mut pressed: Vec<&GamepadButton> = buttons.get_just_pressed().collect();
+    pressed.sort_by_key(|GamepadButton(gamepad, _)| gamepad.0);
+    for GamepadButton(gamepad, button) in pressed {
+        let index = match lobby.slot_of(*gamepad) {
+            Some(index) => index,
+            None => {
+                if *button == GamepadButtonType::Start && lobby.join(*gamepad, library.default_index()).is_none() {
+                    info!("All {} player slots are taken", MAX_PLAYERS);
+                }
+                continue;
+            }
+        };
+
+        let slot = lobby.slots[index].as_mut().unwrap();
+        match button {
+            GamepadButtonType::Start => slot.ready = true,
+            GamepadButtonType::Select if slot.ready => slot.ready = false,
+            GamepadButtonType::Select => lobby.slots[index] = None,
+            GamepadButtonType::DPadLeft if !slot.ready => {
+                slot.character = (slot.character + character_count - 1) % character_count;
+            }
+            GamepadButtonType::DPadRight if !slot.ready => {
+                slot.character = (slot.character + 1) % character_count;
+            }
+            GamepadButtonType::North if !slot.ready => {
+                // Bots take turns through the characters so they're easy to tell apart
+                let character = lobby.slots.iter().flatten().count() % character_count;
+                if lobby.add_bot(BotDifficulty::Medium, character).is_none() {
+                    info!("All {} player slots are taken", MAX_PLAYERS);
+                }
+            }
+            GamepadButtonType::East if !slot.ready => {
+                if let Some(bot) = lobby.last_bot() {
+                    lobby.slots[bot] = None;
+                }
+            }
+            GamepadButtonType::West if !slot.ready => training.cycle(),
+            GamepadButtonType::LeftThumb if !slot.ready => teams.cycle(),
+            GamepadButtonType::RightThumb if !slot.ready => teams.friendly_fire = !teams.friendly_fire,
+            GamepadButtonType::RightTrigger if !slot.ready => slot.team = slot.team.next(),
+            GamepadButtonType::LeftTrigger if !slot.ready => {
+                if let Some(index) = lobby.last_bot() {
+                    if let Some(bot) = lobby.slots[index].as_mut() {
+                        bot.team = bot.team.next();
+                    }
+                }
+            }
+            GamepadButtonType::DPadUp | GamepadButtonType::DPadDown if !slot.ready => {
+                let harder = *button == GamepadButtonType::DPadUp;
+                if let Some(index) = lobby.last_bot() {
+                    if let Some(difficulty) = lobby.slots[index].as_mut().and_then(|slot| slot.bot.as_mut()) {
+                        *difficulty = if harder { difficulty.harder() } else { difficulty.easier() };
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if lobby.everyone_ready() && (!teams.enabled || lobby.teams_in_play() > 1) {
+        if let Err(err) = state.set(AppState::Match) {
+            warn!("Failed to start the match. {:?}", err);
+        }
+    }
+}
+
+fn spawn_lobby_players(
+    mut commands: Commands,
+    library: Res<CharacterLibrary>,
+    lobby: Res<Lobby>,
+    teams: Res<Teams>,
+) {
+    for (index, slot) in lobby.slots.iter().enumerate() {
+        if let Some(slot) = slot {
+            let player = match spawn_player(&mut commands, &library, slot.gamepad, index, slot.character) {
+                Some(player) => player,
+                None => continue,
+            };
+            if let Some(difficulty) = slot.bot {
+                commands.entity(player).insert(Bot::new(difficulty));
+            }
+            if teams.enabled {
+                commands.entity(player).insert(slot.team);
+            }
+        }
+    }
+}
+
+// Whoever's left when a match ends, so the next one starts from scratch
+fn despawn_players(mut commands: Commands, query: Query<Entity, With<Player>>) {
+    for player in query.iter() {
+        commands.entity(player).despawn();
+    }
+}
+
+fn update_lobby_text(
+    state: Res<State<AppState>>,
+    library: Res<CharacterLibrary>,
+    lobby: Res<Lobby>,
+    training: Res<Training>,
+    teams: Res<Teams>,
+    mut query: Query<(&mut Text, &mut Visible), With<LobbyText>>,
+) {
+    let in_lobby = *state.current() == AppState::Lobby;
+    for (mut text, mut visible) in query.iter_mut() {
+        visible.is_visible = in_lobby;
+        if !in_lobby {
+            continue;
+        }
+
+        let lines: Vec<String> = lobby
+            .slots
+            .iter()
+            .enumerate()
+            .map(|(index, slot)| match slot {
+                Some(slot) => {
+                    let name = library
+                        .get(slot.character)
+                        .map_or("?", |character| character.definition.name.as_str());
+                    let status = match slot.bot {
+                        Some(difficulty) => format!("CPU {}", difficulty.name()),
+                        None if slot.ready => "READY".to_string(),
+                        None => "< pick >".to_string(),
+                    };
+                    let team = if teams.enabled {
+                        format!("{:<5}", slot.team.name())
+                    } else {
+                        String::new()
+                    };
+                    format!("P{}  {}{:<10} {}", index + 1, team, name, status)
+                }
+                None => format!("P{}  press Start to join", index + 1),
+            })
+            .collect();
+
+        text.sections[0].value = format!(
+            "{}\n\nTraining: {}\nTeams: {}{}   Friendly fire: {}\n\n\
+             Start: join / ready   Back: leave\nNorth: add CPU   East: remove CPU   Up/Down: CPU level\n\
+             West: training   L3: teams   R3: friendly fire   R1: side   L1: CPU side",
+            lines.join("\n"),
+            training.describe(),
+            teams.describe(),
+            if teams.enabled && lobby.teams_in_play() < 2 { " (pick both sides)" } else { "" },
+            if teams.friendly_fire { "on" } else { "off" },
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use super::app_state::*;
+use super::events::*;
 use bevy::prelude::*;
 use heron::prelude::*;
 const BLOCK_SPRITE: &str = "block.png";
@@ -5,10 +7,41 @@ const BLOCK_SPRITE: &str = "block.png";
 pub struct MapPlugin;
 impl Plugin for MapPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        app.add_startup_system(add_block.system());
+        app.insert_resource(CurrentStage(Stage::Battlefield))
+            .add_startup_system(add_block.system())
+            .add_system_set(SystemSet::on_enter(AppState::Match).with_system(start_match.system()));
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stage {
+    Battlefield,
+}
+
+impl Stage {
+    // Stable number for the stage in replay files
+    pub fn id(&self) -> u8 {
+        match self {
+            Stage::Battlefield => 0,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Stage> {
+        match id {
+            0 => Some(Stage::Battlefield),
+            _ => None,
+        }
+    }
+
+    pub fn music(&self) -> &'static str {
+        match self {
+            Stage::Battlefield => "sounds/battlefield.wav",
+        }
+    }
+}
+
+pub struct CurrentStage(pub Stage);
+
 #[derive(Bundle)]
 pub struct MapBundle {
     _m: Map,
@@ -113,3 +146,8 @@ fn add_block(
             border_radius: Some(0.),
         });
 }
+
+// Every time a match starts, rematches and restarts included, not once at boot
+fn start_match(mut match_start_events: EventWriter<MatchStartEvent>) {
+    match_start_events.send(MatchStartEvent);
+}
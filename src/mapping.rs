@@ -0,0 +1,368 @@
+use super::gilrs_plugin::*;
+use super::options_menu::*;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use gilrs::ev::Code;
+use gilrs::{Axis, Button};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+const MAPPINGS_FILE: &str = "mappings.ron";
+// Raw axis movement needed before the wizard takes it as an answer
+const AXIS_THRESHOLD: f32 = 0.7;
+// Gives the player time to let go before the next prompt listens
+const STEP_COOLDOWN: f32 = 0.4;
+
+// Mappings made with the in-game wizard. These sit on top of SDL mappings because gilrs
+// refuses to remap a gamepad while it's connected.
+pub struct MappingPlugin;
+impl Plugin for MappingPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(CustomMappings::load())
+            .insert_resource(ConnectedPads::default())
+            .insert_resource(MappingWizard::default())
+            .add_event::<RawPadEvent>()
+            .add_event::<StartMappingEvent>()
+            .add_startup_system(setup_wizard_text.system())
+            .add_system(start_mapping_wizard.system().label("start_mapping_wizard"))
+            .add_system(run_mapping_wizard.system().after("start_mapping_wizard"))
+            .add_system(update_wizard_text.system());
+    }
+}
+
+// Every button or axis change straight from gilrs, before any mapping is applied
+pub struct RawPadEvent {
+    pub gamepad: Gamepad,
+    pub code: Code,
+    pub value: f32,
+    pub is_axis: bool,
+}
+
+pub struct StartMappingEvent {
+    pub gamepad: Gamepad,
+}
+
+pub struct PadInfo {
+    pub name: String,
+    pub uuid: String,
+}
+
+// Kept up to date by the gilrs plugin
+#[derive(Default)]
+pub struct ConnectedPads {
+    pub pads: HashMap<Gamepad, PadInfo>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum MappedInput {
+    Button(Button),
+    Axis { axis: Axis, inverted: bool },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MappingEntry {
+    pub code: Code,
+    pub input: MappedInput,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PadMapping {
+    pub name: String,
+    pub uuid: String,
+    pub entries: Vec<MappingEntry>,
+}
+
+impl PadMapping {
+    // None means the code isn't mapped here and gilrs' own mapping should be used
+    pub fn translate(&self, code: Code, value: f32, is_axis: bool) -> Option<GamepadEventType> {
+        let entry = self.entries.iter().find(|entry| entry.code == code)?;
+        match entry.input {
+            // Triggers often show up as axes, anything past centre counts as pressed
+            MappedInput::Button(button) => {
+                let value = if is_axis { value.max(0.) } else { value };
+                convert_button(button).map(|button| GamepadEventType::ButtonChanged(button, value))
+            }
+            MappedInput::Axis { axis, inverted } if is_axis => {
+                let value = if inverted { -value } else { value };
+                convert_axis(axis).map(|axis| GamepadEventType::AxisChanged(axis, value))
+            }
+            MappedInput::Axis { .. } => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct CustomMappings {
+    pub pads: Vec<PadMapping>,
+}
+
+impl CustomMappings {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("smashbubs").join(MAPPINGS_FILE))
+    }
+
+    pub fn get(&self, uuid: &str) -> Option<&PadMapping> {
+        self.pads.iter().find(|pad| pad.uuid == uuid)
+    }
+
+    fn load() -> CustomMappings {
+        let path = match CustomMappings::path() {
+            Some(path) => path,
+            None => return CustomMappings::default(),
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => ron::de::from_str(&contents).unwrap_or_else(|err| {
+                warn!("Ignoring invalid controller mappings {}. {}", path.display(), err);
+                CustomMappings::default()
+            }),
+            Err(_) => CustomMappings::default(),
+        }
+    }
+
+    fn save(&self) {
+        let path = match CustomMappings::path() {
+            Some(path) => path,
+            None => {
+                warn!("No config directory to save controller mappings to");
+                return;
+            }
+        };
+
+        let contents = match ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()) {
+            Ok(contents) => contents,
+            Err(err) => {
+                error!("Failed to serialize controller mappings. {}", err);
+                return;
+            }
+        };
+
+        if let Some(dir) = path.parent() {
+            if let Err(err) = fs::create_dir_all(dir) {
+                error!("Failed to create {}. {}", dir.display(), err);
+                return;
+            }
+        }
+
+        if let Err(err) = fs::write(&path, contents) {
+            error!("Failed to save controller mappings to {}. {}", path.display(), err);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum WizardTarget {
+    Button(Button),
+    Axis(Axis),
+}
+
+struct WizardStep {
+    target: WizardTarget,
+    prompt: &'static str,
+}
+
+const WIZARD_STEPS: [WizardStep; 18] = [
+    WizardStep { target: WizardTarget::Button(Button::South), prompt: "Press the bottom face button (A / Cross)" },
+    WizardStep { target: WizardTarget::Button(Button::East), prompt: "Press the right face button (B / Circle)" },
+    WizardStep { target: WizardTarget::Button(Button::West), prompt: "Press the left face button (X / Square)" },
+    WizardStep { target: WizardTarget::Button(Button::North), prompt: "Press the top face button (Y / Triangle)" },
+    WizardStep { target: WizardTarget::Button(Button::LeftTrigger), prompt: "Press the left bumper" },
+    WizardStep { target: WizardTarget::Button(Button::RightTrigger), prompt: "Press the right bumper" },
+    WizardStep { target: WizardTarget::Button(Button::LeftTrigger2), prompt: "Press the left trigger" },
+    WizardStep { target: WizardTarget::Button(Button::RightTrigger2), prompt: "Press the right trigger" },
+    WizardStep { target: WizardTarget::Button(Button::Select), prompt: "Press Select / Back" },
+    WizardStep { target: WizardTarget::Button(Button::Start), prompt: "Press Start" },
+    WizardStep { target: WizardTarget::Button(Button::DPadUp), prompt: "Press d-pad up" },
+    WizardStep { target: WizardTarget::Button(Button::DPadDown), prompt: "Press d-pad down" },
+    WizardStep { target: WizardTarget::Button(Button::DPadLeft), prompt: "Press d-pad left" },
+    WizardStep { target: WizardTarget::Button(Button::DPadRight), prompt: "Press d-pad right" },
+    WizardStep { target: WizardTarget::Axis(Axis::LeftStickX), prompt: "Push the left stick right" },
+    WizardStep { target: WizardTarget::Axis(Axis::LeftStickY), prompt: "Push the left stick up" },
+    WizardStep { target: WizardTarget::Axis(Axis::RightStickX), prompt: "Push the right stick right" },
+    WizardStep { target: WizardTarget::Axis(Axis::RightStickY), prompt: "Push the right stick up" },
+];
+
+#[derive(Default)]
+pub struct MappingWizard {
+    run: Option<WizardRun>,
+}
+
+impl MappingWizard {
+    pub fn is_running(&self) -> bool {
+        self.run.is_some()
+    }
+}
+
+struct WizardRun {
+    gamepad: Gamepad,
+    step: usize,
+    cooldown: f32,
+    entries: Vec<MappingEntry>,
+}
+
+struct WizardText;
+
+fn setup_wizard_text(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    bottom: Val::Px(50.),
+                    left: Val::Px(20.),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: asset_server.load(MENU_FONT),
+                    font_size: 20.,
+                    color: Color::YELLOW,
+                },
+                Default::default(),
+            ),
+            visible: Visible {
+                is_visible: false,
+                is_transparent: true,
+            },
+            ..Default::default()
+        })
+        .insert(WizardText);
+}
+
+fn start_mapping_wizard(
+    pads: Res<ConnectedPads>,
+    mut wizard: ResMut<MappingWizard>,
+    mut start_events: EventReader<StartMappingEvent>,
+) {
+    if let Some(event) = start_events.iter().last() {
+        if !pads.pads.contains_key(&event.gamepad) {
+            warn!("Can't map {:?}, it isn't connected", event.gamepad);
+            return;
+        }
+
+        wizard.run = Some(WizardRun {
+            gamepad: event.gamepad,
+            step: 0,
+            cooldown: STEP_COOLDOWN,
+            entries: Vec::new(),
+        });
+    }
+}
+
+// Space skips a prompt, Backspace gives up without saving
+fn run_mapping_wizard(
+    time: Res<Time>,
+    keys: Res<Input<KeyCode>>,
+    pads: Res<ConnectedPads>,
+    mut wizard: ResMut<MappingWizard>,
+    mut mappings: ResMut<CustomMappings>,
+    mut raw_events: EventReader<RawPadEvent>,
+) {
+    let run = match wizard.run.as_mut() {
+        Some(run) => run,
+        None => return,
+    };
+
+    if keys.just_pressed(KeyCode::Back) {
+        wizard.run = None;
+        return;
+    }
+
+    run.cooldown = (run.cooldown - time.delta_seconds()).max(0.);
+    if keys.just_pressed(KeyCode::Space) {
+        run.step += 1;
+        run.cooldown = STEP_COOLDOWN;
+    }
+
+    for event in raw_events.iter() {
+        if run.cooldown > 0. || run.step >= WIZARD_STEPS.len() || event.gamepad != run.gamepad {
+            continue;
+        }
+        // Each physical control can only answer one prompt
+        if run.entries.iter().any(|entry| entry.code == event.code) {
+            continue;
+        }
+
+        let input = match WIZARD_STEPS[run.step].target {
+            WizardTarget::Button(button) if !event.is_axis && event.value > 0.5 => {
+                MappedInput::Button(button)
+            }
+            WizardTarget::Button(button) if event.is_axis && event.value > AXIS_THRESHOLD => {
+                MappedInput::Button(button)
+            }
+            WizardTarget::Axis(axis) if event.is_axis && event.value.abs() > AXIS_THRESHOLD => {
+                MappedInput::Axis {
+                    axis,
+                    inverted: event.value < 0.,
+                }
+            }
+            _ => continue,
+        };
+
+        run.entries.push(MappingEntry {
+            code: event.code,
+            input,
+        });
+        run.step += 1;
+        run.cooldown = STEP_COOLDOWN;
+    }
+
+    if run.step < WIZARD_STEPS.len() {
+        return;
+    }
+
+    let run = match wizard.run.take() {
+        Some(run) => run,
+        None => return,
+    };
+    let pad = match pads.pads.get(&run.gamepad) {
+        Some(pad) => pad,
+        None => {
+            warn!("{:?} disconnected before its mapping was saved", run.gamepad);
+            return;
+        }
+    };
+
+    info!("Saved a controller mapping for {}", pad.name);
+    mappings.pads.retain(|mapping| mapping.uuid != pad.uuid);
+    mappings.pads.push(PadMapping {
+        name: pad.name.clone(),
+        uuid: pad.uuid.clone(),
+        entries: run.entries,
+    });
+    mappings.save();
+}
+
+fn update_wizard_text(
+    wizard: Res<MappingWizard>,
+    pads: Res<ConnectedPads>,
+    mut query: Query<(&mut Text, &mut Visible), With<WizardText>>,
+) {
+    if !wizard.is_changed() {
+        return;
+    }
+
+    for (mut text, mut visible) in query.iter_mut() {
+        visible.is_visible = wizard.is_running();
+
+        if let Some(run) = &wizard.run {
+            let name = pads
+                .pads
+                .get(&run.gamepad)
+                .map_or("Unknown pad", |pad| pad.name.as_str());
+            let prompt = WIZARD_STEPS.get(run.step).map_or("", |step| step.prompt);
+            text.sections[0].value = format!(
+                "Mapping {} ({}/{})\n{}\nSpace to skip, Backspace to cancel",
+                name,
+                (run.step + 1).min(WIZARD_STEPS.len()),
+                WIZARD_STEPS.len(),
+                prompt,
+            );
+        }
+    }
+}
@@ -0,0 +1,437 @@
+use super::actions::*;
+use super::gamepad::*;
+use super::map::*;
+use super::player::*;
+use super::simulation::*;
+use bevy::prelude::*;
+use heron::prelude::*;
+use std::collections::VecDeque;
+
+// How far over a platform's top a jump has to get before it's worth steering onto it
+const JUMP_CLEARANCE: f32 = 4.;
+
+// Works out how to get between the platforms of whatever stage is loaded, for bots. Rebuilt
+// whenever the map or gravity changes.
+pub struct NavigationPlugin;
+impl Plugin for NavigationPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(NavGraph::default())
+            .add_system(build_nav_graph.system());
+    }
+}
+
+// The top of something solid, as far as standing on it goes
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Platform {
+    pub left: f32,
+    pub right: f32,
+    pub top: f32,
+}
+
+impl Platform {
+    fn from_shape(transform: &Transform, shape: &CollisionShape) -> Option<Platform> {
+        match shape {
+            CollisionShape::Cuboid { half_extends, .. } => Some(Platform {
+                left: transform.translation.x - half_extends.x,
+                right: transform.translation.x + half_extends.x,
+                top: transform.translation.y + half_extends.y,
+            }),
+            _ => None,
+        }
+    }
+
+    // A player centred at x would be held up by it
+    fn catches(&self, x: f32) -> bool {
+        x > self.left - PLAYER_HALF_EXTENT && x < self.right + PLAYER_HALF_EXTENT
+    }
+}
+
+// How a player gets around, in pixels and ticks
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub struct Movement {
+    pub run_speed: f32,
+    pub jump_speed: f32,
+    // Downward, so positive
+    pub gravity: f32,
+}
+
+impl Movement {
+    pub fn new(gravity: Gravity) -> Movement {
+        let tick_rate = TICK_RATE as f32;
+        Movement {
+            run_speed: TIME_STEP,
+            jump_speed: JUMP_SPEED / tick_rate,
+            gravity: -gravity.vector().y / (tick_rate * tick_rate),
+        }
+    }
+
+    pub fn jump_height(&self) -> f32 {
+        self.jump_speed * self.jump_speed / (2. * self.gravity)
+    }
+
+    // Ticks after a jump until the player is back down to `height` above where they left
+    fn falls_to(&self, height: f32) -> Option<f32> {
+        let root = self.root(height)?;
+        Some((self.jump_speed + root) / self.gravity)
+    }
+
+    // Ticks after a jump until the player first gets `height` up
+    fn rises_to(&self, height: f32) -> Option<f32> {
+        let root = self.root(height)?;
+        Some(((self.jump_speed - root) / self.gravity).max(0.))
+    }
+
+    fn root(&self, height: f32) -> Option<f32> {
+        let discriminant = self.jump_speed * self.jump_speed - 2. * self.gravity * height;
+        if self.gravity <= 0. || discriminant < 0. {
+            None
+        } else {
+            Some(discriminant.sqrt())
+        }
+    }
+
+    // Ticks to fall `height` from standing still
+    fn drop_ticks(&self, height: f32) -> Option<f32> {
+        if self.gravity <= 0. {
+            None
+        } else {
+            Some((2. * height / self.gravity).sqrt())
+        }
+    }
+
+    fn walk_ticks(&self, distance: f32) -> u32 {
+        (distance.abs() / self.run_speed).ceil() as u32
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MoveKind {
+    Walk,
+    Jump,
+    DoubleJump,
+    Drop,
+}
+
+// One way from a spot on one platform to a spot on another
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NavEdge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: MoveKind,
+    pub takeoff_x: f32,
+    pub landing_x: f32,
+    pub air_ticks: u32,
+    // Ticks to go straight up before steering, so it doesn't clip the edge it's aiming for
+    pub hold_ticks: u32,
+    pub second_jump_tick: Option<u32>,
+}
+
+#[derive(Default)]
+pub struct NavGraph {
+    pub platforms: Vec<Platform>,
+    pub edges: Vec<NavEdge>,
+    pub movement: Movement,
+}
+
+// The edges to take, in order, walking to each one's takeoff first
+#[derive(Clone, PartialEq, Debug)]
+pub struct NavPath {
+    pub from_x: f32,
+    pub target_x: f32,
+    pub edges: Vec<NavEdge>,
+    // Roughly how long it all takes
+    pub ticks: u32,
+    run_speed: f32,
+}
+
+impl NavGraph {
+    pub fn new(platforms: Vec<Platform>, movement: Movement) -> NavGraph {
+        let mut edges = Vec::new();
+        for (from, a) in platforms.iter().enumerate() {
+            for (to, b) in platforms.iter().enumerate() {
+                if from == to {
+                    continue;
+                }
+                edges.extend(walk_edge(from, a, to, b));
+                edges.extend(jump_edges(&movement, from, a, to, b));
+                edges.extend(drop_edges(&movement, &platforms, from, a, to, b));
+            }
+        }
+
+        NavGraph {
+            platforms,
+            edges,
+            movement,
+        }
+    }
+
+    // The platform a player at this point is standing on, or would land on first
+    pub fn platform_under(&self, point: Vec2) -> Option<usize> {
+        let feet = point.y - PLAYER_HALF_EXTENT;
+        self.platforms
+            .iter()
+            .enumerate()
+            .filter(|(_, platform)| platform.catches(point.x) && platform.top <= feet + 1.)
+            .max_by(|a, b| a.1.top.partial_cmp(&b.1.top).unwrap())
+            .map(|(index, _)| index)
+    }
+
+    // Quickest way from one point to another, both on or over a platform
+    pub fn find_path(&self, from: Vec2, to: Vec2) -> Option<NavPath> {
+        let start = self.platform_under(from)?;
+        let goal = self.platform_under(to)?;
+        let goal_platform = self.platforms[goal];
+        let target_x = to.x.max(goal_platform.left).min(goal_platform.right);
+
+        // Dijkstra over places to stand. 0 is where it starts, i + 1 is where edge i lands.
+        let spot = |index: usize| match index {
+            0 => (start, from.x),
+            _ => (self.edges[index - 1].to, self.edges[index - 1].landing_x),
+        };
+        let count = self.edges.len() + 1;
+        let mut best = vec![u32::MAX; count];
+        let mut via = vec![None; count];
+        let mut done = vec![false; count];
+        best[0] = 0;
+
+        loop {
+            let current = (0..count)
+                .filter(|index| !done[*index] && best[*index] != u32::MAX)
+                .min_by_key(|index| best[*index]);
+            let current = match current {
+                Some(current) => current,
+                None => break,
+            };
+            done[current] = true;
+            let (platform, x) = spot(current);
+            for (index, edge) in self.edges.iter().enumerate() {
+                if edge.from != platform {
+                    continue;
+                }
+                let ticks = best[current] + self.movement.walk_ticks(x - edge.takeoff_x) + edge.air_ticks;
+                if ticks < best[index + 1] {
+                    best[index + 1] = ticks;
+                    via[index + 1] = Some(current);
+                }
+            }
+        }
+
+        let (end, ticks) = (0..count)
+            .filter(|index| best[*index] != u32::MAX && spot(*index).0 == goal)
+            .map(|index| (index, best[index] + self.movement.walk_ticks(spot(index).1 - target_x)))
+            .min_by_key(|(_, ticks)| *ticks)?;
+
+        let mut edges = Vec::new();
+        let mut index = end;
+        while let Some(previous) = via[index] {
+            edges.push(self.edges[index - 1]);
+            index = previous;
+        }
+        edges.reverse();
+
+        Some(NavPath {
+            from_x: from.x,
+            target_x,
+            edges,
+            ticks,
+            run_speed: self.movement.run_speed,
+        })
+    }
+}
+
+impl NavPath {
+    // What to press on each tick to follow the path, assuming nothing knocks it off course
+    pub fn actions(&self) -> VecDeque<PlayerActions> {
+        let mut actions = VecDeque::new();
+        let mut x = self.from_x;
+        for edge in self.edges.iter() {
+            walk(&mut actions, x, edge.takeoff_x, self.run_speed);
+
+            let steering_ticks = edge.air_ticks.saturating_sub(edge.hold_ticks).max(1);
+            let stick = ((edge.landing_x - edge.takeoff_x) / (steering_ticks as f32 * self.run_speed)).clamp(-1., 1.);
+            let jumps = matches!(edge.kind, MoveKind::Jump | MoveKind::DoubleJump);
+            for tick in 0..edge.air_ticks {
+                let movement = if tick >= edge.hold_ticks { stick } else { 0. };
+                actions.push_back(PlayerActions {
+                    movement: Vec2::new(movement, 0.),
+                    jump: (jumps && tick == 0) || edge.second_jump_tick == Some(tick),
+                    ..Default::default()
+                });
+            }
+            x = edge.landing_x;
+        }
+        walk(&mut actions, x, self.target_x, self.run_speed);
+        actions
+    }
+}
+
+fn walk(actions: &mut VecDeque<PlayerActions>, from: f32, to: f32, run_speed: f32) {
+    if run_speed <= 0. {
+        return;
+    }
+
+    let mut x = from;
+    while (to - x).abs() > 0.5 {
+        let stick = ((to - x) / run_speed).clamp(-1., 1.);
+        x += stick * run_speed;
+        actions.push_back(PlayerActions {
+            movement: Vec2::new(stick, 0.),
+            ..Default::default()
+        });
+    }
+}
+
+// Platforms at the same height that touch are one floor
+fn walk_edge(from: usize, a: &Platform, to: usize, b: &Platform) -> Option<NavEdge> {
+    if (a.top - b.top).abs() > 1. || a.right < b.left || b.right < a.left {
+        return None;
+    }
+
+    let x = (a.left.max(b.left) + a.right.min(b.right)) / 2.;
+    Some(NavEdge {
+        from,
+        to,
+        kind: MoveKind::Walk,
+        takeoff_x: x,
+        landing_x: x,
+        air_ticks: 0,
+        hold_ticks: 0,
+        second_jump_tick: None,
+    })
+}
+
+// Onto either end of b, with one jump if that's enough and two if not
+fn jump_edges(movement: &Movement, from: usize, a: &Platform, to: usize, b: &Platform) -> Vec<NavEdge> {
+    let mut edges = Vec::new();
+    let rise = b.top - a.top;
+    let needed = rise + JUMP_CLEARANCE;
+    let height = movement.jump_height();
+
+    for outward in [-1., 1.] {
+        let edge = if outward < 0. { b.left } else { b.right };
+        let landing_x = edge - outward * PLAYER_HALF_EXTENT;
+        let takeoff_x = (edge + outward * PLAYER_HALF_EXTENT * 2.).max(a.left).min(a.right);
+
+        // Going up from underneath b would hit it, and anything under a lower b is a drop
+        if (takeoff_x - edge) * outward < PLAYER_HALF_EXTENT {
+            continue;
+        }
+        // Hopping down to somewhere still over a just lands back on a
+        if rise <= 0. && a.catches(landing_x) {
+            continue;
+        }
+        let distance = (landing_x - takeoff_x).abs();
+
+        let single = movement.falls_to(rise).and_then(|air| {
+            let hold = if rise > 0. { movement.rises_to(needed)? } else { 0. };
+            Some((air, hold, None))
+        });
+        let double = || {
+            let apex = movement.jump_speed / movement.gravity;
+            let air = apex + movement.falls_to(rise - height)?;
+            let hold = if rise <= 0. {
+                0.
+            } else if needed > height {
+                apex + movement.rises_to(needed - height)?
+            } else {
+                movement.rises_to(needed)?
+            };
+            Some((air, hold, Some(apex.round() as u32)))
+        };
+
+        let reaches = |(air, hold, _): &(f32, f32, Option<u32>)| distance <= movement.run_speed * (air - hold);
+        let jump = match single.filter(|jump| needed <= height && reaches(jump)) {
+            Some(jump) => Some((MoveKind::Jump, jump)),
+            None => double().filter(reaches).map(|jump| (MoveKind::DoubleJump, jump)),
+        };
+
+        if let Some((kind, (air, hold, second_jump_tick))) = jump {
+            edges.push(NavEdge {
+                from,
+                to,
+                kind,
+                takeoff_x,
+                landing_x,
+                air_ticks: air.ceil() as u32,
+                hold_ticks: hold.ceil() as u32,
+                second_jump_tick,
+            });
+        }
+    }
+    edges
+}
+
+// Off either end of a, down onto b, as long as nothing in between gets in the way
+fn drop_edges(
+    movement: &Movement,
+    platforms: &[Platform],
+    from: usize,
+    a: &Platform,
+    to: usize,
+    b: &Platform,
+) -> Vec<NavEdge> {
+    let mut edges = Vec::new();
+    let height = a.top - b.top;
+    if height <= 0. {
+        return edges;
+    }
+
+    for outward in [-1., 1.] {
+        let edge = if outward < 0. { a.left } else { a.right };
+        let takeoff_x = edge + outward * PLAYER_HALF_EXTENT;
+        let landing_x = takeoff_x
+            .max(b.left + PLAYER_HALF_EXTENT)
+            .min(b.right - PLAYER_HALF_EXTENT);
+
+        // Steering back under a would hit it
+        if (landing_x - takeoff_x) * outward < 0. {
+            continue;
+        }
+        let air = match movement.drop_ticks(height) {
+            Some(air) if (landing_x - takeoff_x).abs() <= movement.run_speed * air => air,
+            _ => continue,
+        };
+        let blocked = platforms.iter().enumerate().any(|(index, platform)| {
+            index != from
+                && index != to
+                && platform.top < a.top
+                && platform.top > b.top
+                && (platform.catches(takeoff_x) || platform.catches(landing_x))
+        });
+        if blocked {
+            continue;
+        }
+
+        edges.push(NavEdge {
+            from,
+            to,
+            kind: MoveKind::Drop,
+            takeoff_x,
+            landing_x,
+            air_ticks: air.ceil() as u32,
+            hold_ticks: 0,
+            second_jump_tick: None,
+        });
+    }
+    edges
+}
+
+fn build_nav_graph(
+    gravity: Res<Gravity>,
+    added: Query<Entity, Added<Map>>,
+    removed: RemovedComponents<Map>,
+    platforms: Query<(&Transform, &CollisionShape), With<Map>>,
+    mut graph: ResMut<NavGraph>,
+) {
+    let map_changed = added.iter().next().is_some() || removed.iter().next().is_some();
+    if !map_changed && !gravity.is_changed() {
+        return;
+    }
+
+    let platforms = platforms
+        .iter()
+        .filter_map(|(transform, shape)| Platform::from_shape(transform, shape))
+        .collect();
+    *graph = NavGraph::new(platforms, Movement::new(*gravity));
+    debug!("Navigation graph rebuilt with {} edges", graph.edges.len());
+}
@@ -0,0 +1,589 @@
+use super::actions::*;
+use super::app_state::*;
+use super::character::*;
+use super::player::*;
+use super::simulation::*;
+use super::snapshot::*;
+use super::transport::*;
+use super::virtual_gamepad::*;
+use bevy::prelude::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+
+// Ticks we're allowed to run ahead of the last tick everyone's inputs are in for
+pub const MAX_PREDICTION: u64 = 8;
+// Unacknowledged inputs are resent in every packet, so a lost one is covered by the next
+const MAX_INPUTS_PER_PACKET: usize = 32;
+const PROTOCOL_MAGIC: &[u8; 2] = b"SB";
+const PROTOCOL_VERSION: u8 = 1;
+const INPUTS_MESSAGE: u8 = 0;
+const CHECKSUM_MESSAGE: u8 = 1;
+
+// Rollback netplay for 2 to 4 players, started with
+//   --netplay-bind <addr> --netplay-slot <our slot> --netplay-peer <slot>@<addr> ...
+// and --netplay-pad <id> to play on a pad other than the first one.
+// Everyone runs the match on their own inputs plus a guess at everyone else's, then rewinds
+// and replays the ticks when the real inputs turn up different.
+pub struct NetplayPlugin;
+impl Plugin for NetplayPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        let config = match NetplayConfig::from_args() {
+            Some(config) => config,
+            None => return,
+        };
+        let transport = match UdpTransport::bind(config.bind) {
+            Ok(transport) => transport,
+            Err(err) => {
+                error!("Failed to open {} for netplay, starting a local match. {}", config.bind, err);
+                return;
+            }
+        };
+
+        info!("Netplay as P{} on {}", config.slot + 1, config.bind);
+        let session = RollbackSession::new(config.slot, config.peers);
+        app.insert_resource(ActionSource::Network)
+            .insert_resource(MatchSeed(config.seed))
+            .insert_resource(Netplay {
+                session,
+                gamepad: config.gamepad,
+                transport: Box::new(transport),
+                snapshots: VecDeque::new(),
+                held: PlayerActions::default(),
+            })
+            .add_startup_system(spawn_netplay_players.system())
+            .add_startup_system(start_netplay_match.system())
+            .add_system(hold_local_actions.system().after("collect_gamepad_actions"))
+            .add_stage_before(SIMULATION, "rollback", SystemStage::single(rollback.exclusive_system()))
+            .add_system_to_stage(
+                SIMULATION,
+                network_actions
+                    .system()
+                    .label("external_actions")
+                    .before("record_actions"),
+            )
+            .add_system_to_stage(
+                SIMULATION,
+                save_snapshot.exclusive_system().at_end().after("state_checksum"),
+            )
+            .add_system_to_stage(CoreStage::Last, send_netplay.system());
+    }
+}
+
+pub struct NetplayConfig {
+    pub bind: SocketAddr,
+    pub slot: usize,
+    // The pad we play on here
+    pub gamepad: Gamepad,
+    pub peers: Vec<(usize, SocketAddr)>,
+    // Has to match on every machine
+    pub seed: u64,
+}
+
+impl NetplayConfig {
+    fn from_args() -> Option<NetplayConfig> {
+        let args: Vec<String> = std::env::args().collect();
+        let value = |name: &str| {
+            args.iter()
+                .position(|arg| arg == name)
+                .and_then(|index| args.get(index + 1))
+        };
+
+        let bind = value("--netplay-bind")?;
+        let bind = match bind.parse() {
+            Ok(bind) => bind,
+            Err(_) => {
+                error!("Bad netplay address {}", bind);
+                return None;
+            }
+        };
+        let slot = value("--netplay-slot").and_then(|slot| slot.parse::<usize>().ok()).unwrap_or(1);
+        let slot = slot.saturating_sub(1);
+        let seed = value("--netplay-seed").and_then(|seed| seed.parse().ok()).unwrap_or(0);
+        let gamepad = Gamepad(value("--netplay-pad").and_then(|pad| pad.parse().ok()).unwrap_or(0));
+
+        let mut peers = Vec::new();
+        for (index, arg) in args.iter().enumerate() {
+            if arg != "--netplay-peer" {
+                continue;
+            }
+            let peer = args.get(index + 1).and_then(|peer| {
+                let (slot, addr) = peer.split_once('@')?;
+                Some((slot.parse::<usize>().ok()?.checked_sub(1)?, addr.parse().ok()?))
+            });
+            match peer {
+                Some((slot, addr)) if slot < MAX_PLAYERS => peers.push((slot, addr)),
+                _ => error!("Bad netplay peer, expected <slot>@<addr> like 2@192.168.1.5:7000"),
+            }
+        }
+
+        if peers.is_empty() || slot >= MAX_PLAYERS {
+            error!("Netplay needs a slot from 1 to {} and at least one peer", MAX_PLAYERS);
+            return None;
+        }
+
+        Some(NetplayConfig {
+            bind,
+            slot,
+            gamepad,
+            peers,
+            seed,
+        })
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Message {
+    // Inputs for `first_tick` onwards, plus the last tick we have everything from them for
+    Inputs {
+        slot: u8,
+        first_tick: u32,
+        ack: u32,
+        inputs: Vec<PackedActions>,
+    },
+    Checksum {
+        tick: u32,
+        checksum: u64,
+    },
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = PROTOCOL_MAGIC.to_vec();
+        bytes.push(PROTOCOL_VERSION);
+        match self {
+            Message::Inputs {
+                slot,
+                first_tick,
+                ack,
+                inputs,
+            } => {
+                bytes.push(INPUTS_MESSAGE);
+                bytes.push(*slot);
+                bytes.extend_from_slice(&first_tick.to_le_bytes());
+                bytes.extend_from_slice(&ack.to_le_bytes());
+                bytes.push(inputs.len() as u8);
+                for input in inputs.iter() {
+                    bytes.extend_from_slice(&[
+                        input.movement[0] as u8,
+                        input.movement[1] as u8,
+                        input.aim[0] as u8,
+                        input.aim[1] as u8,
+                        input.buttons,
+                    ]);
+                }
+            }
+            Message::Checksum { tick, checksum } => {
+                bytes.push(CHECKSUM_MESSAGE);
+                bytes.extend_from_slice(&tick.to_le_bytes());
+                bytes.extend_from_slice(&checksum.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    // None for anything that isn't a whole message from this version
+    pub fn decode(bytes: &[u8]) -> Option<Message> {
+        let u32_at = |at: usize| Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?));
+
+        if bytes.get(..2)? != PROTOCOL_MAGIC || *bytes.get(2)? != PROTOCOL_VERSION {
+            return None;
+        }
+        match *bytes.get(3)? {
+            INPUTS_MESSAGE => {
+                let slot = *bytes.get(4)?;
+                let first_tick = u32_at(5)?;
+                let ack = u32_at(9)?;
+                let count = *bytes.get(13)? as usize;
+                let body = bytes.get(14..14 + count * 5)?;
+                let inputs = body
+                    .chunks(5)
+                    .map(|input| PackedActions {
+                        movement: [input[0] as i8, input[1] as i8],
+                        aim: [input[2] as i8, input[3] as i8],
+                        buttons: input[4],
+                    })
+                    .collect();
+                Some(Message::Inputs {
+                    slot,
+                    first_tick,
+                    ack,
+                    inputs,
+                })
+            }
+            CHECKSUM_MESSAGE => Some(Message::Checksum {
+                tick: u32_at(4)?,
+                checksum: u64::from_le_bytes(bytes.get(8..16)?.try_into().ok()?),
+            }),
+            _ => None,
+        }
+    }
+}
+
+struct Peer {
+    addr: SocketAddr,
+    slot: usize,
+    // Last of our ticks they've told us they have
+    acked: u64,
+}
+
+// Confirmed inputs for one slot. Index 0 is tick 1.
+#[derive(Default)]
+struct SlotInputs {
+    inputs: Vec<Option<PackedActions>>,
+    // Every tick up to and including this one is in
+    contiguous: u64,
+}
+
+impl SlotInputs {
+    fn get(&self, tick: u64) -> Option<PackedActions> {
+        self.inputs.get(tick as usize - 1).copied().flatten()
+    }
+
+    fn insert(&mut self, tick: u64, input: PackedActions) {
+        if self.inputs.len() < tick as usize {
+            self.inputs.resize(tick as usize, None);
+        }
+        self.inputs[tick as usize - 1] = Some(input);
+        while self.get(self.contiguous + 1).is_some() {
+            self.contiguous += 1;
+        }
+    }
+}
+
+// The input side of rollback with no knowledge of the game, so it can be tested on its own
+pub struct RollbackSession {
+    local_slot: usize,
+    peers: Vec<Peer>,
+    slots: Vec<SlotInputs>,
+    // What we guessed for inputs that hadn't arrived, by tick
+    predictions: BTreeMap<u64, [Option<PackedActions>; MAX_PLAYERS]>,
+    // Earliest tick simulated with a wrong guess
+    rollback_to: Option<u64>,
+    local_checksums: BTreeMap<u64, u64>,
+    remote_checksums: BTreeMap<u64, u64>,
+    checksums_sent: u64,
+    pub desynced_at: Option<u64>,
+}
+
+impl RollbackSession {
+    pub fn new(local_slot: usize, peers: Vec<(usize, SocketAddr)>) -> RollbackSession {
+        RollbackSession {
+            local_slot,
+            peers: peers
+                .into_iter()
+                .map(|(slot, addr)| Peer { addr, slot, acked: 0 })
+                .collect(),
+            slots: (0..MAX_PLAYERS).map(|_| SlotInputs::default()).collect(),
+            predictions: BTreeMap::new(),
+            rollback_to: None,
+            local_checksums: BTreeMap::new(),
+            remote_checksums: BTreeMap::new(),
+            checksums_sent: 0,
+            desynced_at: None,
+        }
+    }
+
+    pub fn local_slot(&self) -> usize {
+        self.local_slot
+    }
+
+    // Every slot in the match, ours first
+    pub fn player_slots(&self) -> Vec<usize> {
+        let mut slots = vec![self.local_slot];
+        slots.extend(self.peers.iter().map(|peer| peer.slot));
+        slots
+    }
+
+    // Last tick we have every player's inputs for
+    pub fn confirmed_tick(&self) -> u64 {
+        self.player_slots()
+            .iter()
+            .map(|slot| self.slots[*slot].contiguous)
+            .min()
+            .unwrap_or(0)
+    }
+
+    // Whether a tick has ours already, which it does when it is being run again after a rollback
+    pub fn has_local_input(&self, tick: u64) -> bool {
+        self.slots[self.local_slot].get(tick).is_some()
+    }
+
+    pub fn add_local_input(&mut self, tick: u64, input: PackedActions) {
+        if !self.has_local_input(tick) {
+            self.slots[self.local_slot].insert(tick, input);
+        }
+    }
+
+    // The real input if it's in, otherwise a guess: the sticks stay where they were and no
+    // buttons get pressed
+    pub fn input(&mut self, tick: u64, slot: usize) -> PackedActions {
+        if let Some(input) = self.slots[slot].get(tick) {
+            return input;
+        }
+
+        let last = self.slots[slot].contiguous;
+        let guess = match last {
+            0 => PackedActions::default(),
+            last => PackedActions {
+                buttons: 0,
+                ..self.slots[slot].get(last).unwrap_or_default()
+            },
+        };
+        self.predictions.entry(tick).or_default()[slot] = Some(guess);
+        guess
+    }
+
+    // The earliest tick that has to be run again, if any
+    pub fn take_rollback(&mut self) -> Option<u64> {
+        self.rollback_to.take()
+    }
+
+    pub fn add_local_checksum(&mut self, tick: u64, checksum: u64) {
+        // Anything after it came from before a rollback and is about to be redone
+        self.local_checksums.split_off(&(tick + 1));
+        self.local_checksums.insert(tick, checksum);
+        self.compare_checksums();
+    }
+
+    // Last tick our checksum is final for: everyone's inputs are in, it has been simulated with
+    // them, and no rollback is still waiting to redo it
+    fn settled_tick(&self) -> u64 {
+        let simulated = self.local_checksums.keys().next_back().copied().unwrap_or(0);
+        let settled = self.confirmed_tick().min(simulated);
+        match self.rollback_to {
+            Some(rollback_to) => settled.min(rollback_to - 1),
+            None => settled,
+        }
+    }
+
+    pub fn receive(&mut self, from: SocketAddr, bytes: &[u8]) {
+        let peer = match self.peers.iter_mut().find(|peer| peer.addr == from) {
+            Some(peer) => peer,
+            None => return,
+        };
+
+        match Message::decode(bytes) {
+            Some(Message::Inputs {
+                slot,
+                first_tick,
+                ack,
+                inputs,
+            }) => {
+                if slot as usize != peer.slot {
+                    return;
+                }
+                peer.acked = peer.acked.max(ack as u64);
+                let slot = slot as usize;
+
+                // Nobody honest can be further ahead than this, and anything past it would have
+                // us allocating for ticks that never come
+                let confirmed = self.confirmed_tick();
+                let expected = confirmed + 1..=confirmed + MAX_PREDICTION + MAX_INPUTS_PER_PACKET as u64;
+                for (offset, input) in inputs.into_iter().enumerate() {
+                    let tick = first_tick as u64 + offset as u64;
+                    if !expected.contains(&tick) || self.slots[slot].get(tick).is_some() {
+                        continue;
+                    }
+                    self.slots[slot].insert(tick, input);
+
+                    let guess = self.predictions.get(&tick).and_then(|guesses| guesses[slot]);
+                    if guess.is_some_and(|guess| guess != input) {
+                        self.rollback_to = Some(self.rollback_to.map_or(tick, |earliest| earliest.min(tick)));
+                    }
+                }
+
+                // Guesses for ticks everyone's inputs are in for are settled now
+                let confirmed = self.confirmed_tick();
+                self.predictions = self.predictions.split_off(&(confirmed + 1));
+            }
+            Some(Message::Checksum { tick, checksum }) => {
+                self.remote_checksums.insert(tick as u64, checksum);
+                self.compare_checksums();
+            }
+            None => {}
+        }
+    }
+
+    // Only ticks simulated from real inputs all round are worth comparing
+    fn compare_checksums(&mut self) {
+        let settled_tick = self.settled_tick();
+        let settled: Vec<u64> = self
+            .remote_checksums
+            .range(..=settled_tick)
+            .map(|(tick, _)| *tick)
+            .filter(|tick| self.local_checksums.contains_key(tick))
+            .collect();
+
+        for tick in settled {
+            let remote = self.remote_checksums.remove(&tick);
+            if remote != self.local_checksums.get(&tick).copied() && self.desynced_at.is_none() {
+                error!("Netplay desync at tick {}", tick);
+                self.desynced_at = Some(tick);
+            }
+        }
+    }
+
+    pub fn send(&mut self, transport: &mut dyn Transport) {
+        let local = &self.slots[self.local_slot];
+        let confirmed = self.confirmed_tick();
+        // Only what's final, so a checksum we'd still redo never makes the other side think
+        // we've desynced
+        let unsent = self.checksums_sent + 1..self.settled_tick().max(self.checksums_sent) + 1;
+
+        for peer in self.peers.iter() {
+            let first_tick = (peer.acked + 1).max((local.contiguous + 1).saturating_sub(MAX_INPUTS_PER_PACKET as u64));
+            let inputs: Vec<PackedActions> = (first_tick..=local.contiguous)
+                .filter_map(|tick| local.get(tick))
+                .collect();
+            let message = Message::Inputs {
+                slot: self.local_slot as u8,
+                first_tick: first_tick as u32,
+                ack: self.slots[peer.slot].contiguous as u32,
+                inputs,
+            };
+            transport.send(peer.addr, &message.encode());
+
+            for (tick, checksum) in self.local_checksums.range(unsent.clone()) {
+                let message = Message::Checksum {
+                    tick: *tick as u32,
+                    checksum: *checksum,
+                };
+                transport.send(peer.addr, &message.encode());
+            }
+        }
+
+        self.checksums_sent = unsent.end - 1;
+        // Nobody can ask about ticks that far back any more
+        let keep_from = confirmed.saturating_sub(MAX_PREDICTION * 4);
+        self.local_checksums = self.local_checksums.split_off(&keep_from);
+    }
+
+    pub fn poll(&mut self, transport: &mut dyn Transport) {
+        while let Some((from, bytes)) = transport.receive() {
+            self.receive(from, &bytes);
+        }
+    }
+}
+
+pub struct Netplay {
+    pub session: RollbackSession,
+    // The pad the local player is on
+    pub gamepad: Gamepad,
+    transport: Box<dyn Transport>,
+    // State after each of the last few ticks, newest at the back
+    snapshots: VecDeque<Snapshot>,
+    // The local pad as of this frame, with its presses kept until a tick that's being run for the
+    // first time takes them. Rerun ticks replay what was sent and would clear them otherwise.
+    held: PlayerActions,
+}
+
+fn spawn_netplay_players(mut commands: Commands, library: Res<CharacterLibrary>, netplay: Res<Netplay>) {
+    // Everyone plays the default character until the lobby works over the network
+    for slot in netplay.session.player_slots() {
+        let gamepad = if slot == netplay.session.local_slot() {
+            netplay.gamepad
+        } else {
+            Gamepad(FIRST_REMOTE_GAMEPAD + slot)
+        };
+        spawn_player(&mut commands, &library, gamepad, slot, library.default_index());
+    }
+}
+
+fn start_netplay_match(mut state: ResMut<State<AppState>>) {
+    if let Err(err) = state.set(AppState::Match) {
+        warn!("Failed to start the netplay match. {:?}", err);
+    }
+}
+
+// Takes in whatever arrived, and if a guess turned out wrong puts the world back to just
+// before it and has the simulation run those ticks again
+fn rollback(world: &mut World) {
+    world.resource_scope(|world, mut netplay: Mut<Netplay>| {
+        let netplay = &mut *netplay;
+        netplay.session.poll(&mut *netplay.transport);
+
+        let mut clock = world.get_resource_mut::<SimulationClock>().unwrap();
+        clock.max_tick = Some(netplay.session.confirmed_tick() + MAX_PREDICTION);
+        let tick = clock.tick;
+
+        // Somewhere to go back to before the first tick
+        if netplay.snapshots.is_empty() {
+            netplay.snapshots.push_back(Snapshot::capture(world, tick));
+            return;
+        }
+
+        let rollback_to = match netplay.session.take_rollback() {
+            Some(rollback_to) if rollback_to <= tick => rollback_to,
+            _ => return,
+        };
+        let index = match netplay
+            .snapshots
+            .iter()
+            .position(|snapshot| snapshot.tick == rollback_to - 1)
+        {
+            Some(index) => index,
+            None => {
+                error!("No snapshot to roll back to tick {} from, carrying on", rollback_to);
+                return;
+            }
+        };
+
+        let mut clock = world.get_resource_mut::<SimulationClock>().unwrap();
+        clock.resimulate += tick - (rollback_to - 1);
+        clock.tick = rollback_to - 1;
+
+        // Newer snapshots get taken again as the ticks are rerun
+        netplay.snapshots.truncate(index + 1);
+        netplay.snapshots[index].restore(world);
+    });
+}
+
+fn hold_local_actions(mut netplay: ResMut<Netplay>, query: Query<(&PlayerSlot, &PlayerActions), With<Player>>) {
+    let local_slot = netplay.session.local_slot();
+    for (slot, actions) in query.iter() {
+        if slot.0 == local_slot {
+            netplay.held = PlayerActions {
+                jump: netplay.held.jump || actions.jump,
+                fire: netplay.held.fire || actions.fire,
+                ..*actions
+            };
+        }
+    }
+}
+
+fn network_actions(
+    clock: Res<SimulationClock>,
+    mut netplay: ResMut<Netplay>,
+    mut query: Query<(&PlayerSlot, &mut PlayerActions), With<Player>>,
+) {
+    if !netplay.session.has_local_input(clock.tick) {
+        let held = netplay.held.pack();
+        netplay.session.add_local_input(clock.tick, held);
+        netplay.held.jump = false;
+        netplay.held.fire = false;
+    }
+
+    for (slot, mut actions) in query.iter_mut() {
+        *actions = netplay.session.input(clock.tick, slot.0).unpack();
+    }
+}
+
+// After the tick's physics step and checksum, rerun ticks included, so a rollback lands on a
+// world that has been stepped exactly as many times as its tick says
+fn save_snapshot(world: &mut World) {
+    let checksum = *world.get_resource::<StateChecksum>().unwrap();
+    let snapshot = Snapshot::capture(world, checksum.tick);
+
+    let mut netplay = world.get_resource_mut::<Netplay>().unwrap();
+    netplay.session.add_local_checksum(checksum.tick, checksum.checksum);
+    netplay.snapshots.push_back(snapshot);
+    // One more than the furthest back a rollback can go
+    while netplay.snapshots.len() as u64 > MAX_PREDICTION + 2 {
+        netplay.snapshots.pop_front();
+    }
+}
+
+fn send_netplay(mut netplay: ResMut<Netplay>) {
+    let netplay = &mut *netplay;
+    netplay.session.send(&mut *netplay.transport);
+}
@@ -0,0 +1,351 @@
+use super::app_state::*;
+use super::calibration::*;
+use super::lobby::*;
+use super::mapping::*;
+use super::player::*;
+use super::settings::*;
+use super::stick::*;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+
+pub const MENU_FONT: &str = "fonts/DejaVuSansMono.ttf";
+
+const RESOLUTIONS: [(u32, u32); 5] = [(600, 600), (800, 800), (1200, 1200), (1280, 720), (1920, 1080)];
+const VOLUME_STEP: f32 = 0.1;
+const DEADZONE_STEP: f32 = 0.05;
+
+pub struct OptionsMenuPlugin;
+impl Plugin for OptionsMenuPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(OptionsMenu::default())
+            .add_startup_system(setup_options_menu.system())
+            .add_system(toggle_options_menu.system().label("toggle_options_menu"))
+            .add_system(
+                navigate_options_menu
+                    .system()
+                    .label("navigate_options_menu")
+                    .after("toggle_options_menu"),
+            )
+            .add_system(update_options_text.system());
+    }
+}
+
+#[derive(Default)]
+pub struct OptionsMenu {
+    pub open: bool,
+    selected: usize,
+    // Whose controller settings are being edited
+    player: usize,
+}
+
+struct OptionsText;
+
+// The keyboard and every pad, either of which can work the menu
+#[derive(SystemParam)]
+pub struct MenuButtons<'a> {
+    keys: Res<'a, Input<KeyCode>>,
+    buttons: Res<'a, Input<GamepadButton>>,
+}
+
+// The tools the menu hands a pad over to, which take every button while they run
+#[derive(SystemParam)]
+pub struct PadTools<'a> {
+    calibration: Res<'a, Calibration>,
+    wizard: Res<'a, MappingWizard>,
+    calibration_events: EventWriter<'a, StartCalibrationEvent>,
+    mapping_events: EventWriter<'a, StartMappingEvent>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OptionsItem {
+    Resolution,
+    Fullscreen,
+    MasterVolume,
+    MusicVolume,
+    SfxVolume,
+    RumbleStrength,
+    ScreenShake,
+    HitStop,
+    JumpButton,
+    FireButton,
+    AimAssist,
+    StickPlayer,
+    InnerDeadzone,
+    OuterDeadzone,
+    ResponseCurve,
+    EightWayAim,
+    CalibrateSticks,
+    MapController,
+}
+
+const OPTIONS_ITEMS: [OptionsItem; 18] = [
+    OptionsItem::Resolution,
+    OptionsItem::Fullscreen,
+    OptionsItem::MasterVolume,
+    OptionsItem::MusicVolume,
+    OptionsItem::SfxVolume,
+    OptionsItem::RumbleStrength,
+    OptionsItem::ScreenShake,
+    OptionsItem::HitStop,
+    OptionsItem::JumpButton,
+    OptionsItem::FireButton,
+    OptionsItem::AimAssist,
+    OptionsItem::StickPlayer,
+    OptionsItem::InnerDeadzone,
+    OptionsItem::OuterDeadzone,
+    OptionsItem::ResponseCurve,
+    OptionsItem::EightWayAim,
+    OptionsItem::CalibrateSticks,
+    OptionsItem::MapController,
+];
+
+// What a key or button press means while the menu is open
+#[derive(Clone, Copy, PartialEq)]
+enum MenuInput {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+fn setup_options_menu(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(20.),
+                    left: Val::Px(20.),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: asset_server.load(MENU_FONT),
+                    font_size: 20.,
+                    color: Color::WHITE,
+                },
+                Default::default(),
+            ),
+            visible: Visible {
+                is_visible: false,
+                is_transparent: true,
+            },
+            ..Default::default()
+        })
+        .insert(OptionsText);
+}
+
+// Escape or Select opens the menu, Escape, Select or East closes it and saves
+fn toggle_options_menu(
+    input: MenuButtons,
+    settings: Res<Settings>,
+    file: Res<SettingsFile>,
+    wizard: Res<MappingWizard>,
+    state: Res<State<AppState>>,
+    lobby: Res<Lobby>,
+    mut menu: ResMut<OptionsMenu>,
+) {
+    // Every button is an answer while a pad is being mapped
+    if wizard.is_running() {
+        return;
+    }
+
+    // In the lobby Back is how a player leaves their slot
+    let in_lobby = *state.current() == AppState::Lobby;
+    let pressed_back = input.buttons.get_just_pressed().any(|GamepadButton(gamepad, button)| {
+        let leaving_lobby = in_lobby && lobby.slot_of(*gamepad).is_some();
+        (*button == GamepadButtonType::Select && !leaving_lobby)
+            || (menu.open && *button == GamepadButtonType::East)
+    });
+
+    if input.keys.just_pressed(KeyCode::Escape) || pressed_back {
+        menu.open = !menu.open;
+        if !menu.open {
+            settings.save(&file);
+        }
+    }
+}
+
+fn navigate_options_menu(
+    input: MenuButtons,
+    mut tools: PadTools,
+    mut menu: ResMut<OptionsMenu>,
+    mut settings: ResMut<Settings>,
+    players: Query<(&Gamepad, &PlayerSlot), With<Player>>,
+) {
+    if !menu.open || tools.calibration.is_running() || tools.wizard.is_running() {
+        return;
+    }
+
+    let key_inputs = input.keys.get_just_pressed().filter_map(|key| match key {
+        KeyCode::Up => Some(MenuInput::Up),
+        KeyCode::Down => Some(MenuInput::Down),
+        KeyCode::Left => Some(MenuInput::Left),
+        KeyCode::Right | KeyCode::Return => Some(MenuInput::Right),
+        _ => None,
+    });
+    let button_inputs = input.buttons.get_just_pressed().filter_map(|GamepadButton(_, button)| match button {
+        GamepadButtonType::DPadUp => Some(MenuInput::Up),
+        GamepadButtonType::DPadDown => Some(MenuInput::Down),
+        GamepadButtonType::DPadLeft => Some(MenuInput::Left),
+        GamepadButtonType::DPadRight | GamepadButtonType::South => Some(MenuInput::Right),
+        _ => None,
+    });
+    let inputs: Vec<MenuInput> = key_inputs.chain(button_inputs).collect();
+    let slot_gamepad = players
+        .iter()
+        .find(|(_, slot)| slot.0 == menu.player)
+        .map(|(gamepad, _)| *gamepad);
+
+    for input in inputs {
+        match input {
+            MenuInput::Up => {
+                menu.selected = (menu.selected + OPTIONS_ITEMS.len() - 1) % OPTIONS_ITEMS.len();
+            }
+            MenuInput::Down => {
+                menu.selected = (menu.selected + 1) % OPTIONS_ITEMS.len();
+            }
+            MenuInput::Right if OPTIONS_ITEMS[menu.selected] == OptionsItem::CalibrateSticks => {
+                match slot_gamepad {
+                    Some(gamepad) => tools.calibration_events.send(StartCalibrationEvent {
+                        player: menu.player,
+                        gamepad,
+                    }),
+                    None => warn!("Nobody is playing as P{}", menu.player + 1),
+                }
+            }
+            MenuInput::Right if OPTIONS_ITEMS[menu.selected] == OptionsItem::MapController => {
+                match slot_gamepad {
+                    Some(gamepad) => tools.mapping_events.send(StartMappingEvent { gamepad }),
+                    None => warn!("Nobody is playing as P{}", menu.player + 1),
+                }
+            }
+            MenuInput::Left | MenuInput::Right => {
+                let direction = if input == MenuInput::Left { -1 } else { 1 };
+                let item = OPTIONS_ITEMS[menu.selected];
+                if item == OptionsItem::StickPlayer {
+                    menu.player = (menu.player as i32 + direction).rem_euclid(MAX_PLAYERS as i32) as usize;
+                } else {
+                    change_option(&mut settings, menu.player, item, direction);
+                }
+            }
+        }
+    }
+}
+
+fn change_option(settings: &mut Settings, player: usize, item: OptionsItem, direction: i32) {
+    match item {
+        OptionsItem::Resolution => {
+            settings.video.resolution = cycle(&RESOLUTIONS, settings.video.resolution, direction);
+        }
+        OptionsItem::Fullscreen => settings.video.fullscreen = !settings.video.fullscreen,
+        OptionsItem::MasterVolume => step_volume(&mut settings.audio.master_volume, direction),
+        OptionsItem::MusicVolume => step_volume(&mut settings.audio.music_volume, direction),
+        OptionsItem::SfxVolume => step_volume(&mut settings.audio.sfx_volume, direction),
+        OptionsItem::RumbleStrength => step_volume(&mut settings.input.rumble_strength, direction),
+        OptionsItem::ScreenShake => settings.video.screen_shake = !settings.video.screen_shake,
+        OptionsItem::HitStop => settings.video.hit_stop = !settings.video.hit_stop,
+        OptionsItem::JumpButton => {
+            settings.input.bindings.jump = cycle(&BINDABLE_BUTTONS, settings.input.bindings.jump, direction);
+        }
+        OptionsItem::FireButton => {
+            settings.input.bindings.fire = cycle(&BINDABLE_BUTTONS, settings.input.bindings.fire, direction);
+        }
+        OptionsItem::AimAssist => settings.input.aim_assist = !settings.input.aim_assist,
+        OptionsItem::InnerDeadzone => {
+            let sticks = settings.input.sticks_mut(player);
+            sticks.inner_deadzone = (sticks.inner_deadzone + DEADZONE_STEP * direction as f32)
+                .max(0.)
+                .min(sticks.outer_deadzone - DEADZONE_STEP);
+        }
+        OptionsItem::OuterDeadzone => {
+            let sticks = settings.input.sticks_mut(player);
+            sticks.outer_deadzone = (sticks.outer_deadzone + DEADZONE_STEP * direction as f32)
+                .max(sticks.inner_deadzone + DEADZONE_STEP)
+                .min(1.);
+        }
+        OptionsItem::ResponseCurve => {
+            let sticks = settings.input.sticks_mut(player);
+            sticks.curve = cycle(&RESPONSE_CURVES, sticks.curve, direction);
+        }
+        OptionsItem::EightWayAim => {
+            let sticks = settings.input.sticks_mut(player);
+            sticks.eight_way_aim = !sticks.eight_way_aim;
+        }
+        // Handled by the menu itself
+        OptionsItem::StickPlayer | OptionsItem::CalibrateSticks | OptionsItem::MapController => {}
+    }
+}
+
+fn step_volume(volume: &mut f32, direction: i32) {
+    *volume = (*volume + VOLUME_STEP * direction as f32).clamp(0., 1.);
+}
+
+// Values that aren't in the list (e.g. a hand edited resolution) start over from the first entry
+fn cycle<T: Copy + PartialEq>(values: &[T], current: T, direction: i32) -> T {
+    let len = values.len() as i32;
+    match values.iter().position(|value| *value == current) {
+        Some(index) => values[(index as i32 + direction).rem_euclid(len) as usize],
+        None => values[0],
+    }
+}
+
+fn update_options_text(
+    menu: Res<OptionsMenu>,
+    settings: Res<Settings>,
+    mut query: Query<(&mut Text, &mut Visible), With<OptionsText>>,
+) {
+    if !menu.is_changed() && !settings.is_changed() {
+        return;
+    }
+
+    for (mut text, mut visible) in query.iter_mut() {
+        visible.is_visible = menu.open;
+
+        let mut lines = vec!["OPTIONS".to_string(), String::new()];
+        for (index, item) in OPTIONS_ITEMS.iter().enumerate() {
+            let cursor = if index == menu.selected { ">" } else { " " };
+            lines.push(format!("{} {}", cursor, describe_option(&settings, menu.player, *item)));
+        }
+        text.sections[0].value = lines.join("\n");
+    }
+}
+
+fn describe_option(settings: &Settings, player: usize, item: OptionsItem) -> String {
+    let on_off = |value: bool| if value { "On" } else { "Off" };
+    let percent = |value: f32| format!("{}%", (value * 100.).round());
+
+    match item {
+        OptionsItem::Resolution => {
+            let (width, height) = settings.video.resolution;
+            format!("Resolution      {}x{}", width, height)
+        }
+        OptionsItem::Fullscreen => format!("Fullscreen      {}", on_off(settings.video.fullscreen)),
+        OptionsItem::MasterVolume => format!("Master volume   {}", percent(settings.audio.master_volume)),
+        OptionsItem::MusicVolume => format!("Music volume    {}", percent(settings.audio.music_volume)),
+        OptionsItem::SfxVolume => format!("SFX volume      {}", percent(settings.audio.sfx_volume)),
+        OptionsItem::RumbleStrength => format!("Rumble          {}", percent(settings.input.rumble_strength)),
+        OptionsItem::ScreenShake => format!("Screen shake    {}", on_off(settings.video.screen_shake)),
+        OptionsItem::HitStop => format!("Hit stop        {}", on_off(settings.video.hit_stop)),
+        OptionsItem::JumpButton => format!("Jump            {:?}", settings.input.bindings.jump),
+        OptionsItem::FireButton => format!("Fire            {:?}", settings.input.bindings.fire),
+        OptionsItem::AimAssist => format!("Aim assist      {}", on_off(settings.input.aim_assist)),
+        OptionsItem::StickPlayer => format!("Controller      P{}", player + 1),
+        OptionsItem::InnerDeadzone => {
+            format!("  Deadzone      {}", percent(settings.input.sticks(player).inner_deadzone))
+        }
+        OptionsItem::OuterDeadzone => {
+            format!("  Outer edge    {}", percent(settings.input.sticks(player).outer_deadzone))
+        }
+        OptionsItem::ResponseCurve => format!("  Curve         {:?}", settings.input.sticks(player).curve),
+        OptionsItem::EightWayAim => format!("  8-way aim     {}", on_off(settings.input.sticks(player).eight_way_aim)),
+        OptionsItem::CalibrateSticks => {
+            let (left, right) = settings.input.sticks(player).rest_noise;
+            format!("  Calibrate     noise L {} R {}", percent(left), percent(right))
+        }
+        OptionsItem::MapController => "  Map controller".to_string(),
+    }
+}
@@ -0,0 +1,301 @@
+use super::events::*;
+use super::pause::*;
+use super::window::*;
+use bevy::prelude::*;
+use bevy::render::texture::{Extent3d, TextureDimension, TextureFormat};
+use std::f32::consts::PI;
+
+pub struct ParticlePlugin;
+impl Plugin for ParticlePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(ParticleBudget::default())
+            .add_event::<SpawnParticlesEvent>()
+            .add_startup_system(setup_particle_texture.system())
+            .add_system(emit_gameplay_particles.system().label("emit_gameplay_particles"))
+            .add_system(spawn_particles.system().after("emit_gameplay_particles"))
+            .add_system(update_particles.system());
+    }
+}
+
+// Caps how many particles can exist at once and how many can be created in a single frame.
+// Requests over budget are dropped rather than queued.
+pub struct ParticleBudget {
+    pub max_alive: usize,
+    pub max_spawned_per_frame: usize,
+}
+
+impl Default for ParticleBudget {
+    fn default() -> ParticleBudget {
+        ParticleBudget {
+            max_alive: 600,
+            max_spawned_per_frame: 120,
+        }
+    }
+}
+
+pub struct EmitterSpec {
+    pub count: usize,
+    pub lifetime: (f32, f32),
+    pub speed: (f32, f32),
+    // Half angle of the cone particles fly out in, around the emit direction
+    pub spread: f32,
+    pub gravity: f32,
+    pub start_color: Color,
+    pub end_color: Color,
+    pub start_size: f32,
+    pub end_size: f32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ParticleEffect {
+    BulletImpact,
+    Hit,
+    KoBlast,
+    JumpDust,
+    MuzzleFlash,
+}
+
+impl ParticleEffect {
+    pub fn spec(&self) -> EmitterSpec {
+        match self {
+            ParticleEffect::BulletImpact => EmitterSpec {
+                count: 6,
+                lifetime: (0.1, 0.25),
+                speed: (40., 120.),
+                spread: PI / 3.,
+                gravity: -300.,
+                start_color: Color::rgb(1., 0.9, 0.5),
+                end_color: Color::rgba(1., 0.4, 0.1, 0.),
+                start_size: 2.,
+                end_size: 1.,
+            },
+            ParticleEffect::Hit => EmitterSpec {
+                count: 14,
+                lifetime: (0.15, 0.4),
+                speed: (80., 220.),
+                spread: PI / 4.,
+                gravity: -200.,
+                start_color: Color::WHITE,
+                end_color: Color::rgba(1., 0.2, 0.2, 0.),
+                start_size: 3.,
+                end_size: 1.,
+            },
+            ParticleEffect::KoBlast => EmitterSpec {
+                count: 60,
+                lifetime: (0.4, 1.),
+                speed: (150., 500.),
+                spread: PI / 5.,
+                gravity: 0.,
+                start_color: Color::rgb(1., 1., 0.8),
+                end_color: Color::rgba(1., 0.3, 0., 0.),
+                start_size: 6.,
+                end_size: 2.,
+            },
+            ParticleEffect::JumpDust => EmitterSpec {
+                count: 8,
+                lifetime: (0.2, 0.4),
+                speed: (20., 60.),
+                spread: PI / 2.,
+                gravity: 40.,
+                start_color: Color::rgba(0.8, 0.8, 0.7, 0.8),
+                end_color: Color::rgba(0.8, 0.8, 0.7, 0.),
+                start_size: 2.,
+                end_size: 4.,
+            },
+            ParticleEffect::MuzzleFlash => EmitterSpec {
+                count: 4,
+                lifetime: (0.04, 0.08),
+                speed: (60., 140.),
+                spread: PI / 8.,
+                gravity: 0.,
+                start_color: Color::rgb(1., 1., 0.6),
+                end_color: Color::rgba(1., 0.6, 0.2, 0.),
+                start_size: 3.,
+                end_size: 1.,
+            },
+        }
+    }
+}
+
+// Any system can send this to get a burst of particles
+pub struct SpawnParticlesEvent {
+    pub effect: ParticleEffect,
+    pub position: Vec3,
+    pub direction: Vec2,
+}
+
+pub struct Particle {
+    velocity: Vec2,
+    age: f32,
+    lifetime: f32,
+    gravity: f32,
+    start_color: Color,
+    end_color: Color,
+    start_size: f32,
+    end_size: f32,
+}
+
+// Single white pixel that gets tinted and scaled per particle
+struct ParticleTexture(Handle<TextureAtlas>);
+
+const PARTICLE_Z: f32 = 5.;
+
+fn setup_particle_texture(
+    mut commands: Commands,
+    mut textures: ResMut<Assets<Texture>>,
+    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+) {
+    let texture = textures.add(Texture::new_fill(
+        Extent3d::new(1, 1, 1),
+        TextureDimension::D2,
+        &[255, 255, 255, 255],
+        TextureFormat::Rgba8UnormSrgb,
+    ));
+    let atlas = TextureAtlas::from_grid(texture, Vec2::ONE, 1, 1);
+    commands.insert_resource(ParticleTexture(texture_atlases.add(atlas)));
+}
+
+fn emit_gameplay_particles(
+    mut particle_events: EventWriter<SpawnParticlesEvent>,
+    mut impact_events: EventReader<ProjectileImpactEvent>,
+    mut hit_events: EventReader<PlayerHitEvent>,
+    mut ko_events: EventReader<PlayerKoEvent>,
+    mut jump_events: EventReader<PlayerJumpEvent>,
+    mut fire_events: EventReader<PlayerFireEvent>,
+) {
+    for event in impact_events.iter().filter(|event| !event.rerun) {
+        particle_events.send(SpawnParticlesEvent {
+            effect: ParticleEffect::BulletImpact,
+            position: event.position,
+            direction: -event.direction.truncate(),
+        });
+    }
+    for event in hit_events.iter().filter(|event| !event.rerun) {
+        particle_events.send(SpawnParticlesEvent {
+            effect: ParticleEffect::Hit,
+            position: event.position,
+            direction: event.direction.truncate(),
+        });
+    }
+    for event in ko_events.iter().filter(|event| !event.rerun) {
+        // Blast back into the stage from wherever the player crossed the edge
+        let edge = Vec2::new(
+            event.position.x.clamp(-VIRTUAL_WIDTH / 2., VIRTUAL_WIDTH / 2.),
+            event.position.y.clamp(-VIRTUAL_HEIGHT / 2., VIRTUAL_HEIGHT / 2.),
+        );
+        particle_events.send(SpawnParticlesEvent {
+            effect: ParticleEffect::KoBlast,
+            position: edge.extend(PARTICLE_Z),
+            direction: -edge.normalize_or_zero(),
+        });
+    }
+    for event in jump_events.iter().filter(|event| !event.rerun) {
+        particle_events.send(SpawnParticlesEvent {
+            effect: ParticleEffect::JumpDust,
+            position: event.position,
+            direction: -Vec2::Y,
+        });
+    }
+    for event in fire_events.iter().filter(|event| !event.rerun) {
+        particle_events.send(SpawnParticlesEvent {
+            effect: ParticleEffect::MuzzleFlash,
+            position: event.position,
+            direction: event.direction.truncate(),
+        });
+    }
+}
+
+fn spawn_particles(
+    mut commands: Commands,
+    budget: Res<ParticleBudget>,
+    texture: Res<ParticleTexture>,
+    mut particle_events: EventReader<SpawnParticlesEvent>,
+    alive: Query<&Particle>,
+) {
+    let mut alive_count = alive.iter().count();
+    let mut spawned = 0;
+
+    for event in particle_events.iter() {
+        let spec = event.effect.spec();
+        let base_angle = event.direction.y.atan2(event.direction.x);
+
+        for _ in 0..spec.count {
+            if alive_count >= budget.max_alive || spawned >= budget.max_spawned_per_frame {
+                return;
+            }
+
+            let angle = base_angle + random_range(-spec.spread, spec.spread);
+            let speed = random_range(spec.speed.0, spec.speed.1);
+
+            commands
+                .spawn_bundle(SpriteSheetBundle {
+                    texture_atlas: texture.0.clone(),
+                    sprite: TextureAtlasSprite {
+                        color: spec.start_color,
+                        ..Default::default()
+                    },
+                    transform: Transform {
+                        translation: event.position.truncate().extend(PARTICLE_Z),
+                        scale: Vec3::new(spec.start_size, spec.start_size, 1.),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })
+                .insert(Particle {
+                    velocity: Vec2::new(angle.cos(), angle.sin()) * speed,
+                    age: 0.,
+                    lifetime: random_range(spec.lifetime.0, spec.lifetime.1),
+                    gravity: spec.gravity,
+                    start_color: spec.start_color,
+                    end_color: spec.end_color,
+                    start_size: spec.start_size,
+                    end_size: spec.end_size,
+                });
+
+            alive_count += 1;
+            spawned += 1;
+        }
+    }
+}
+
+fn update_particles(
+    mut commands: Commands,
+    time: Res<Time>,
+    pause: Res<MatchPause>,
+    mut query: Query<(Entity, &mut Particle, &mut Transform, &mut TextureAtlasSprite)>,
+) {
+    // Left hanging in the air until the match carries on
+    if pause.is_paused() {
+        return;
+    }
+    let delta = time.delta_seconds();
+
+    for (entity, mut particle, mut transform, mut sprite) in query.iter_mut() {
+        particle.age += delta;
+        if particle.age >= particle.lifetime {
+            commands.entity(entity).despawn();
+            continue;
+        }
+
+        particle.velocity.y += particle.gravity * delta;
+        transform.translation += particle.velocity.extend(0.) * delta;
+
+        let t = particle.age / particle.lifetime;
+        let size = particle.start_size + (particle.end_size - particle.start_size) * t;
+        transform.scale = Vec3::new(size, size, 1.);
+        sprite.color = lerp_color(particle.start_color, particle.end_color, t);
+    }
+}
+
+fn lerp_color(from: Color, to: Color, t: f32) -> Color {
+    Color::rgba(
+        from.r() + (to.r() - from.r()) * t,
+        from.g() + (to.g() - from.g()) * t,
+        from.b() + (to.b() - from.b()) * t,
+        from.a() + (to.a() - from.a()) * t,
+    )
+}
+
+fn random_range(min: f32, max: f32) -> f32 {
+    min + rand::random::<f32>() * (max - min)
+}
@@ -0,0 +1,273 @@
+use super::actions::*;
+use super::app_state::*;
+use super::gamepad::*;
+use super::options_menu::*;
+use super::player::*;
+use bevy::prelude::*;
+
+// The match stops when a player loses their controller, or when someone presses Start. Start
+// brings up a menu that only the player who paused can use or close.
+pub struct PausePlugin;
+impl Plugin for PausePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(MatchPause::default())
+            .add_startup_system(setup_pause_text.system())
+            .add_startup_system(setup_pause_menu.system())
+            .add_system_set(SystemSet::on_exit(AppState::Match).with_system(clear_pause.system()))
+            .add_system_set(SystemSet::on_enter(AppState::Restarting).with_system(restart_match.system()))
+            .add_system(track_waiting_players.system().label("track_waiting_players"))
+            .add_system(
+                pause_menu_input
+                    .system()
+                    .label("pause_menu_input")
+                    .after("navigate_options_menu"),
+            )
+            .add_system(update_pause_text.system().after("track_waiting_players"))
+            .add_system(update_pause_menu.system().after("pause_menu_input"));
+    }
+}
+
+#[derive(Default)]
+pub struct MatchPause {
+    // A player lost their controller and the match is holding their slot
+    pub waiting_for_controller: bool,
+    // Who pressed Start. Kept by slot so it still works if they end up on another pad.
+    paused_by: Option<PlayerSlot>,
+    selected: usize,
+}
+
+impl MatchPause {
+    pub fn is_paused(&self) -> bool {
+        self.waiting_for_controller || self.paused_by.is_some()
+    }
+
+    pub fn paused_by(&self) -> Option<PlayerSlot> {
+        self.paused_by
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PauseItem {
+    Resume,
+    Settings,
+    Restart,
+    Quit,
+}
+
+const PAUSE_ITEMS: [PauseItem; 4] = [
+    PauseItem::Resume,
+    PauseItem::Settings,
+    PauseItem::Restart,
+    PauseItem::Quit,
+];
+
+impl PauseItem {
+    fn label(&self) -> &'static str {
+        match self {
+            PauseItem::Resume => "Resume",
+            PauseItem::Settings => "Settings",
+            PauseItem::Restart => "Restart match",
+            PauseItem::Quit => "Quit to character select",
+        }
+    }
+}
+
+struct PauseText;
+struct PauseMenuText;
+
+fn setup_pause_text(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    bottom: Val::Px(110.),
+                    left: Val::Px(20.),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: asset_server.load(MENU_FONT),
+                    font_size: 20.,
+                    color: Color::WHITE,
+                },
+                Default::default(),
+            ),
+            visible: Visible {
+                is_visible: false,
+                is_transparent: true,
+            },
+            ..Default::default()
+        })
+        .insert(PauseText);
+}
+
+fn track_waiting_players(
+    mut pause: ResMut<MatchPause>,
+    query: Query<Entity, (With<Player>, With<WaitingForController>)>,
+) {
+    let waiting = query.iter().next().is_some();
+    // Only write on a change so is_changed means something
+    if pause.waiting_for_controller != waiting {
+        pause.waiting_for_controller = waiting;
+    }
+}
+
+fn update_pause_text(
+    pause: Res<MatchPause>,
+    waiting: Query<&PlayerSlot, With<WaitingForController>>,
+    mut query: Query<(&mut Text, &mut Visible), With<PauseText>>,
+) {
+    for (mut text, mut visible) in query.iter_mut() {
+        visible.is_visible = pause.waiting_for_controller;
+        if !pause.waiting_for_controller {
+            continue;
+        }
+
+        let mut slots: Vec<usize> = waiting.iter().map(|slot| slot.0).collect();
+        slots.sort_unstable();
+        let lines: Vec<String> = slots
+            .iter()
+            .map(|slot| format!("P{} lost their controller", slot + 1))
+            .collect();
+        text.sections[0].value = format!(
+            "PAUSED\n{}\nReconnect it or press Start on any free pad",
+            lines.join("\n"),
+        );
+    }
+}
+
+fn setup_pause_menu(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(200.),
+                    left: Val::Px(180.),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: asset_server.load(MENU_FONT),
+                    font_size: 24.,
+                    color: Color::WHITE,
+                },
+                Default::default(),
+            ),
+            visible: Visible {
+                is_visible: false,
+                is_transparent: true,
+            },
+            ..Default::default()
+        })
+        .insert(PauseMenuText);
+}
+
+// Start pauses. Then the d-pad picks, South chooses, and Start or East resumes, but only on
+// the pausing player's pad.
+fn pause_menu_input(
+    buttons: Res<Input<GamepadButton>>,
+    source: Res<ActionSource>,
+    mut options: ResMut<OptionsMenu>,
+    mut pause: ResMut<MatchPause>,
+    mut state: ResMut<State<AppState>>,
+    // The options menu was open last frame, so the button that closed it isn't for us
+    mut options_were_open: Local<bool>,
+    players: Query<(&Gamepad, &PlayerSlot), (With<Player>, Without<WaitingForController>)>,
+) {
+    let options_open = options.open || *options_were_open;
+    *options_were_open = options.open;
+    // The other side of a netplay match can't be paused
+    if *state.current() != AppState::Match || *source == ActionSource::Network || options_open {
+        return;
+    }
+
+    for GamepadButton(gamepad, button) in buttons.get_just_pressed() {
+        let slot = match players.iter().find(|(player, _)| *player == gamepad) {
+            Some((_, slot)) => *slot,
+            None => continue,
+        };
+
+        let paused_by = match pause.paused_by {
+            Some(paused_by) => paused_by,
+            None => {
+                if *button == GamepadButtonType::Start && !pause.waiting_for_controller {
+                    pause.paused_by = Some(slot);
+                    pause.selected = 0;
+                }
+                continue;
+            }
+        };
+        if slot != paused_by {
+            continue;
+        }
+
+        match button {
+            GamepadButtonType::Start | GamepadButtonType::East => pause.paused_by = None,
+            GamepadButtonType::DPadUp => {
+                pause.selected = (pause.selected + PAUSE_ITEMS.len() - 1) % PAUSE_ITEMS.len();
+            }
+            GamepadButtonType::DPadDown => pause.selected = (pause.selected + 1) % PAUSE_ITEMS.len(),
+            GamepadButtonType::South => match PAUSE_ITEMS[pause.selected] {
+                PauseItem::Resume => pause.paused_by = None,
+                PauseItem::Settings => options.open = true,
+                PauseItem::Restart | PauseItem::Quit => {
+                    let next = if PAUSE_ITEMS[pause.selected] == PauseItem::Restart {
+                        AppState::Restarting
+                    } else {
+                        AppState::Lobby
+                    };
+                    if let Err(err) = state.set(next) {
+                        warn!("Failed to leave the match. {:?}", err);
+                    }
+                }
+            },
+            _ => {}
+        }
+        // Nothing else this frame, it's either closed or leaving
+        if options.open || pause.paused_by.is_none() {
+            break;
+        }
+    }
+}
+
+fn clear_pause(mut pause: ResMut<MatchPause>) {
+    pause.paused_by = None;
+}
+
+// Only passed through, so everything that happens on entering a match happens again
+fn restart_match(mut state: ResMut<State<AppState>>) {
+    if let Err(err) = state.set(AppState::Match) {
+        warn!("Failed to restart the match. {:?}", err);
+    }
+}
+
+fn update_pause_menu(
+    pause: Res<MatchPause>,
+    options: Res<OptionsMenu>,
+    mut query: Query<(&mut Text, &mut Visible), With<PauseMenuText>>,
+) {
+    // Settings go over the top of it
+    let showing = pause.paused_by.is_some() && !options.open;
+    for (mut text, mut visible) in query.iter_mut() {
+        visible.is_visible = showing;
+        if !showing {
+            continue;
+        }
+
+        let slot = pause.paused_by.map_or(0, |slot| slot.0);
+        let mut lines = vec![format!("PAUSED by P{}", slot + 1), String::new()];
+        for (index, item) in PAUSE_ITEMS.iter().enumerate() {
+            let cursor = if index == pause.selected { ">" } else { " " };
+            lines.push(format!("{} {}", cursor, item.label()));
+        }
+        text.sections[0].value = lines.join("\n");
+    }
+}
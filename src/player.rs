@@ -1,54 +1,60 @@
-use super::gamepad::*;
+use super::actions::*;
+use super::aim::*;
+use super::animation::*;
+use super::character::*;
+use super::events::*;
 use super::map::*;
+use super::simulation::*;
+use super::stick::*;
+use super::teams::*;
+use super::training::*;
+use super::window::*;
 use bevy::ecs::bundle::Bundle;
 use bevy::prelude::*;
 use bevy::sprite::collide_aabb::collide;
 use heron::prelude::*;
-// use rand::seq::SliceRandom;
 
-// const PLAYER_SPRITE: &str = "player.png";
-// const BAT_SPRITE: &str = "bat.png";
-// const BLOCKY_SPRITE: &str = "blocky.png";
-// const BLUE_RING_SPRITE: &str = "blue_ring.png";
-// const CRABTOPUS_SPRITE: &str = "crabtopus.png";
-// const IRON_SPRITE: &str = "iron.png";
-// const PERL_SPRITE: &str = "perl.png";
-const PIG_SPRITE: &str = "pig.png";
-// const RAT_SPRITE: &str = "rat.png";
-// const SLUG_SPRITE: &str = "slug.png";
-// const TURTLE_SPRITE: &str = "turtle.png";
-
-// const AVAILABLE_PLAYER_SPRITES: [&str; 11] = [
-//     PLAYER_SPRITE,
-//     BAT_SPRITE,
-//     BLOCKY_SPRITE,
-//     BLUE_RING_SPRITE,
-//     CRABTOPUS_SPRITE,
-//     IRON_SPRITE,
-//     PERL_SPRITE,
-//     PIG_SPRITE,
-//     RAT_SPRITE,
-//     SLUG_SPRITE,
-//     TURTLE_SPRITE,
-// ];
+// Players are drawn at 2x from an 8x8 sprite and collide as a 16x16 box
+pub const PLAYER_HALF_EXTENT: f32 = 8.;
+pub const MAX_JUMPS: i8 = 2;
+pub const MAX_PLAYERS: usize = 4;
+pub const STARTING_LIVES: i8 = 2;
 
 pub struct PlayerPlugin;
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        app.add_startup_system(setup_sprites.system())
-        .add_system(add_player.system())
-            .add_system(respawn_players_who_leave_window.system())
-            .add_system(reset_jumps.system());
+        app.add_system_to_stage(
+            SIMULATION,
+            respawn_players_who_leave_window
+                .system()
+                .label("respawn_players")
+                .after("hold_hit_stop"),
+        )
+        .add_system_to_stage(
+            SIMULATION,
+            reset_jumps
+                .system()
+                .label("reset_jumps")
+                .after("external_actions")
+                .before("player_input"),
+        );
     }
 }
 
 #[derive(Bundle)]
 pub struct PlayerBundle {
     pub gamepad: Gamepad,
+    pub slot: PlayerSlot,
     pub available_jumps: AvailableJumps,
     pub lives: Lives,
     pub damage_taken: DamageTaken,
     pub speed: Speed,
+    pub character: PlayerCharacter,
+    pub animator: Animator,
+    pub sticks: StickInput,
+    pub actions: PlayerActions,
+    pub aim: AimDirection,
+    pub last_attacker: LastAttacker,
     pub _p: Player,
 
     #[bundle]
@@ -63,17 +69,24 @@ impl Default for PlayerBundle {
     fn default() -> PlayerBundle {
         PlayerBundle {
             gamepad: Gamepad(1),
+            slot: PlayerSlot(0),
             damage_taken: DamageTaken(0.),
-            available_jumps: AvailableJumps(2),
-            lives: Lives(2),
+            available_jumps: AvailableJumps(MAX_JUMPS),
+            lives: Lives(STARTING_LIVES),
             _p: Player,
             speed: Speed(1.),
+            character: PlayerCharacter(0),
+            animator: Animator::default(),
+            sticks: StickInput::default(),
+            actions: PlayerActions::default(),
+            aim: AimDirection::default(),
+            last_attacker: LastAttacker(None),
             sprite: SpriteSheetBundle {
                 ..Default::default()
             },
             body: RigidBody::Dynamic,
             shape: CollisionShape::Cuboid {
-                half_extends: Vec3::new(8., 8., 1.),
+                half_extends: Vec3::new(PLAYER_HALF_EXTENT, PLAYER_HALF_EXTENT, 1.),
                 border_radius: Some(0.),
             },
             velocity: Velocity::from_linear(Vec3::Y * 100.),
@@ -82,79 +95,112 @@ impl Default for PlayerBundle {
     }
 }
 pub struct Player;
+// P1 to P4, stays with the player even if their controller changes
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct PlayerSlot(pub usize);
 pub struct DamageTaken(pub f32);
 pub struct Lives(pub i8);
 pub struct AvailableJumps(pub i8);
+// Who gets the credit if this player is KO'd. Cleared with each stock.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LastAttacker(pub Option<Entity>);
 pub struct Size(Vec2);
 
 pub struct Speed(pub f32);
 
-pub struct PlayerMaterials {
-	player: Handle<TextureAtlas>,
+// Where players start and come back after a KO
+pub fn spawn_point() -> Vec3 {
+    Vec3::new(0., 0., 1.)
 }
 
-pub fn setup_sprites(
-    mut commands: Commands,
-    asset_server: Res<AssetServer>,
-    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
-) {
-    let player_texture_handle = asset_server.load(PIG_SPRITE);
-	let player_texture_atlas = TextureAtlas::from_grid(player_texture_handle, Vec2::new(8.0, 8.0), 2, 1);
-    commands.insert_resource(PlayerMaterials {
-        player: texture_atlases.add(player_texture_atlas),
-	});
-
-}
-
-pub fn add_player(
-    mut commands: Commands,
-    mut ev_add_player: EventReader<AddPlayerEvent>,
-    player_materials: Res<PlayerMaterials>
-) {
+pub fn spawn_player(
+    commands: &mut Commands,
+    library: &CharacterLibrary,
+    gamepad: Gamepad,
+    slot: usize,
+    character: usize,
+) -> Option<Entity> {
+    let atlas = match library.get(character) {
+        Some(character) => character.atlas.clone(),
+        None => {
+            error!("No character {} loaded, can't add player", character);
+            return None;
+        }
+    };
 
-    for event in ev_add_player.iter() {
-        commands
-            .spawn()
-            .insert_bundle(PlayerBundle {
-                gamepad: event.0,
-                sprite: SpriteSheetBundle {
-                    // material: materials.add(asset_server.load(sprite).into()),
-                    texture_atlas: player_materials.player.clone(),
-                    transform: Transform {
-                        translation: Vec3::new(0., 0., 1.),
-                        scale: Vec3::new(2., 2., 1.),
-                        ..Default::default()
-                    },
+    let player = commands
+        .spawn()
+        .insert_bundle(PlayerBundle {
+            gamepad,
+            slot: PlayerSlot(slot),
+            character: PlayerCharacter(character),
+            sprite: SpriteSheetBundle {
+                texture_atlas: atlas,
+                transform: Transform {
+                    translation: spawn_point(),
+                    scale: Vec3::new(2., 2., 1.),
                     ..Default::default()
                 },
                 ..Default::default()
-            });
-    }
+            },
+            ..Default::default()
+        })
+        .id();
+    Some(player)
 }
 
 fn respawn_players_who_leave_window(
     mut commands: Commands,
-    window: Res<WindowDescriptor>,
+    clock: Res<SimulationClock>,
+    training: Res<Training>,
+    teams: Res<Teams>,
+    mut team_stocks: ResMut<TeamStocks>,
+    mut ko_events: EventWriter<PlayerKoEvent>,
     mut query: Query<(
         Entity,
         &mut Transform,
         &mut Lives,
         &mut DamageTaken,
         &mut Velocity,
+        &mut LastAttacker,
+        Option<&Team>,
         With<Player>,
     )>,
 ) {
-    for (player_entity, mut transform, mut lives, mut damage_taken, mut velocity, _) in query.iter_mut() {
-        if transform.translation.y.abs() > window.height / 2.
-            || transform.translation.x.abs() > window.width / 2.
+    for (player_entity, mut transform, mut lives, mut damage_taken, mut velocity, mut last_attacker, team, _) in
+        query.iter_mut()
+    {
+        if transform.translation.y.abs() > VIRTUAL_HEIGHT / 2.
+            || transform.translation.x.abs() > VIRTUAL_WIDTH / 2.
         {
-            lives.0 = lives.0 - 1;
+            ko_events.send(PlayerKoEvent {
+                player: player_entity,
+                position: transform.translation,
+                last_attacker: last_attacker.0,
+                rerun: clock.rerun,
+            });
+            last_attacker.0 = None;
+
+            if training.loses_lives() {
+                match team.filter(|_| teams.shares_stocks()) {
+                    // Back on from the team's reserve, or out once it's empty
+                    Some(team) => {
+                        let pool = &mut team_stocks.0[team.0];
+                        if *pool > 0 {
+                            *pool -= 1;
+                        } else {
+                            lives.0 = 0;
+                        }
+                    }
+                    None => lives.0 -= 1,
+                }
+            }
             damage_taken.0 = 0.;
 
-            if lives.0 == 0 {
+            if lives.0 <= 0 {
                 commands.entity(player_entity).despawn();
             } else {
-                transform.translation = Vec3::new(0., 0., 1.);
+                transform.translation = spawn_point();
                 velocity.linear = Vec3::Y * 100.;
             }
         }
@@ -169,7 +215,7 @@ fn reset_jumps(mut events: EventReader<CollisionEvent>,
             CollisionEvent::Started(collider1, collider2) => {
                 for (player_entity, mut available_jumps, _) in player_query.iter_mut() {
                     if player_entity == collider1.rigid_body_entity() || player_entity == collider2.rigid_body_entity() {
-                        available_jumps.0 = 2;
+                        available_jumps.0 = MAX_JUMPS;
                     }
                 }
             }
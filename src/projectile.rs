@@ -1,12 +1,20 @@
+use super::app_state::*;
+use super::events::*;
 use super::map::*;
+use super::window::*;
 use super::player::*;
+use super::simulation::*;
+use super::teams::*;
 use heron::prelude::*;
 use bevy::ecs::bundle::Bundle;
 use bevy::{prelude::*, sprite::collide_aabb::*};
 
+const BULLET_SPRITE: &str = "bullet.png";
+
 #[derive(Bundle)]
 pub struct ProjectileBundle {
     pub _p: Projectile,
+    pub owner: ProjectileOwner,
 
     #[bundle]
     pub sprite: SpriteBundle,
@@ -14,46 +22,148 @@ pub struct ProjectileBundle {
 
 pub struct Projectile;
 
+// The player who fired the projectile, so they can't shoot themselves
+pub struct ProjectileOwner(pub Entity);
+
 pub struct ProjectilePlugin;
 impl Plugin for ProjectilePlugin {
     fn build(&self, app: &mut AppBuilder) {
-        app.add_system(clean_up_offscreen_projectiles.system())
-            .add_system(projectile_hit_player.system())
-            .add_system(projectile_hit_map.system());
+        app.add_startup_system(setup_bullet_material.system())
+            .add_system_to_stage(
+                SIMULATION,
+                projectile_hit_player
+                    .system()
+                    .label("projectile_hit_player")
+                    .after("player_input"),
+            )
+            .add_system_to_stage(
+                SIMULATION,
+                projectile_hit_map
+                    .system()
+                    .label("projectile_hit_map")
+                    .after("projectile_hit_player"),
+            )
+            .add_system_to_stage(
+                SIMULATION,
+                clean_up_offscreen_projectiles.system().after("projectile_hit_map"),
+            )
+            .add_system_set(SystemSet::on_exit(AppState::Match).with_system(despawn_projectiles.system()));
     }
 }
 
+// Shared by every bullet rather than a new material per shot
+pub struct BulletMaterial(pub Handle<ColorMaterial>);
+
+fn setup_bullet_material(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+) {
+    let material = materials.add(asset_server.load(BULLET_SPRITE).into());
+    commands.insert_resource(BulletMaterial(material));
+}
+
+pub fn spawn_projectile(
+    commands: &mut Commands,
+    material: &BulletMaterial,
+    owner: Entity,
+    translation: Vec3,
+    velocity: Vec3,
+) -> Entity {
+    commands
+        .spawn()
+        .insert_bundle(ProjectileBundle {
+            _p: Projectile,
+            owner: ProjectileOwner(owner),
+            sprite: SpriteBundle {
+                material: material.0.clone(),
+                transform: Transform {
+                    scale: Vec3::new(2., 2., 1.),
+                    translation,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        })
+        .insert(RigidBody::Dynamic)
+        .insert(CollisionShape::Cuboid {
+            half_extends: Vec3::new(2., 2., 1.),
+            border_radius: Some(0.),
+        })
+        .insert(PhysicMaterial {
+            restitution: 0.,
+            density: 1., // Define the density. Higher value means heavier.
+            friction: 0., // Define the friction. Higher value means higher friction.
+        })
+        .insert(Velocity::from_linear(velocity))
+        .id()
+}
+
 fn clean_up_offscreen_projectiles(
     mut commands: Commands,
-    window: Res<WindowDescriptor>,
     mut query: Query<(Entity, &Transform, With<Projectile>)>,
 ) {
     for (projectile_entity, transform, _) in query.iter_mut() {
         let translation = transform.translation;
-        if translation.y.abs() > window.height / 2. || translation.x.abs() > window.width / 2. {
+        if translation.y.abs() > VIRTUAL_HEIGHT / 2. || translation.x.abs() > VIRTUAL_WIDTH / 2. {
             commands.entity(projectile_entity).despawn();
         }
     }
 }
 
+// Bullets still in the air when the match ends
+fn despawn_projectiles(mut commands: Commands, query: Query<Entity, With<Projectile>>) {
+    for projectile in query.iter() {
+        commands.entity(projectile).despawn();
+    }
+}
+
+// Anyone a bullet could hit, and everything a hit changes on them
+type HittablePlayers<'a> = Query<
+    'a,
+    (
+        Entity,
+        &'static Transform,
+        &'static mut DamageTaken,
+        &'static mut Velocity,
+        &'static mut LastAttacker,
+        Option<&'static Team>,
+    ),
+    With<Player>,
+>;
+
+// Bullets go straight through teammates unless friendly fire is on
 fn projectile_hit_player(
     mut commands: Commands,
-    mut projectile_query: Query<(Entity, &Transform, &Sprite, With<Projectile>)>,
-    mut player_query: Query<(&Transform, &Sprite, &mut DamageTaken, &mut Velocity, With<Player>)>,
+    clock: Res<SimulationClock>,
+    teams: Res<Teams>,
+    mut hit_events: EventWriter<PlayerHitEvent>,
+    mut projectile_query: Query<(Entity, &Transform, &Sprite, &ProjectileOwner, With<Projectile>)>,
+    mut player_query: HittablePlayers,
+    team_query: Query<&Team>,
 ) {
-    for (projectile_entity, projectile_transform, projectile_sprite, _) in
+    for (projectile_entity, projectile_transform, projectile_sprite, owner, _) in
         projectile_query.iter_mut()
     {
-        for (player_transform, player_sprite, mut damage_taken, mut velocity, _) in player_query.iter_mut() {
+        let owner_team = team_query.get(owner.0).ok();
+        for (player_entity, player_transform, mut damage_taken, mut velocity, mut last_attacker, team) in
+            player_query.iter_mut()
+        {
+            if player_entity == owner.0 || !teams.can_hurt(owner_team, team) {
+                continue;
+            }
+
             let collision = collide(
                 projectile_transform.translation,
                 projectile_sprite.size * Vec2::from(projectile_transform.scale * 2.),
                 player_transform.translation,
-                player_sprite.size * Vec2::from(player_transform.scale),
+                Vec2::splat(PLAYER_HALF_EXTENT * 2.),
             );
 
             if let Some(collision) = collision {
-                damage_taken.0 = damage_taken.0 + 1.;
+                let damage = 1.;
+                damage_taken.0 = damage_taken.0 + damage;
+                last_attacker.0 = Some(owner.0);
 
                 match collision {
                     Collision::Top => {
@@ -70,10 +180,19 @@ fn projectile_hit_player(
                     }  
                     
                 }
-                
-                
+
+                hit_events.send(PlayerHitEvent {
+                    attacker: Some(owner.0),
+                    victim: player_entity,
+                    damage,
+                    knockback: velocity.linear.length(),
+                    direction: velocity.linear.normalize_or_zero(),
+                    position: projectile_transform.translation,
+                    rerun: clock.rerun,
+                });
 
                 commands.entity(projectile_entity).despawn();
+                break;
             }
         }
     }
@@ -81,10 +200,12 @@ fn projectile_hit_player(
 
 fn projectile_hit_map(
     mut commands: Commands,
-    mut projectile_query: Query<(Entity, &Transform, &Sprite, With<Projectile>)>,
+    clock: Res<SimulationClock>,
+    mut impact_events: EventWriter<ProjectileImpactEvent>,
+    mut projectile_query: Query<(Entity, &Transform, &Sprite, &Velocity, With<Projectile>)>,
     mut map_query: Query<(&Transform, &Sprite, With<Map>)>,
 ) {
-    for (projectile_entity, projectile_transform, projectile_sprite, _) in
+    for (projectile_entity, projectile_transform, projectile_sprite, velocity, _) in
         projectile_query.iter_mut()
     {
         for (map_transform, map_sprite, _) in map_query.iter_mut() {
@@ -96,7 +217,13 @@ fn projectile_hit_map(
             );
 
             if let Some(_) = collision {
+                impact_events.send(ProjectileImpactEvent {
+                    position: projectile_transform.translation,
+                    direction: velocity.linear.normalize_or_zero(),
+                    rerun: clock.rerun,
+                });
                 commands.entity(projectile_entity).despawn();
+                break;
             }
         }
     }
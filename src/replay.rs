@@ -0,0 +1,596 @@
+use super::actions::*;
+use super::aim::*;
+use super::app_state::*;
+use super::character::*;
+use super::hit_stop::*;
+use super::map::{CurrentStage, Stage};
+use super::player::*;
+use super::simulation::*;
+use super::teams::*;
+use super::training::*;
+use bevy::app::AppExit;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const REPLAY_MAGIC: &[u8; 4] = b"SBRP";
+// Bump whenever the layout changes or gameplay changes enough that old inputs play out differently
+const REPLAY_VERSION: u16 = 4;
+const REPLAY_DIR: &str = "replays";
+const REPLAY_EXTENSION: &str = "sbr";
+// In a roster entry's team byte for a player on neither side
+const NO_TEAM: u8 = u8::MAX;
+
+// Every live match is recorded and saved with F9 or on exit. Run with `--replay <file>` to
+// watch one back.
+pub struct ReplayPlugin;
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        let playback = replay_argument().and_then(|path| match Replay::load(&path) {
+            Ok(replay) => Some(replay),
+            Err(err) => {
+                error!("Failed to load replay {}, starting a live match. {}", path.display(), err);
+                None
+            }
+        });
+
+        match playback {
+            Some(replay) => {
+                app.insert_resource(ActionSource::Replay)
+                    .insert_resource(CurrentStage(replay.stage))
+                    .insert_resource(MatchSeed(replay.seed))
+                    .insert_resource(ReplayPlayback {
+                        replay,
+                        spawned: 0,
+                        training_edits_made: 0,
+                        finished: false,
+                    })
+                    .add_startup_system(spawn_starting_replay_players.system())
+                    .add_startup_system(set_up_replay_training.system())
+                    .add_startup_system(set_up_replay_teams.system())
+                    .add_startup_system(skip_lobby.system());
+            }
+            None => {
+                app.insert_resource(ReplayRecorder::default())
+                    .add_system_set(SystemSet::on_enter(AppState::Match).with_system(reset_recorder.system()))
+                    .add_system_to_stage(CoreStage::Last, save_replay.system());
+            }
+        }
+
+        app.add_system_to_stage(SIMULATION, play_replay.system().label("external_actions"))
+            .add_system_to_stage(
+                SIMULATION,
+                record_replay
+                    .system()
+                    .label("record_actions")
+                    .after("external_actions")
+                    .before("player_input"),
+            );
+    }
+}
+
+fn replay_argument() -> Option<PathBuf> {
+    let mut args = std::env::args().skip_while(|arg| arg != "--replay");
+    args.next()?;
+    args.next().map(PathBuf::from)
+}
+
+#[derive(Clone)]
+pub struct RosterEntry {
+    pub slot: u8,
+    // First tick the player was around for
+    pub joined_tick: u32,
+    pub character: String,
+    // Only in a team match
+    pub team: Option<Team>,
+}
+
+// Actions for every slot on one tick, None where nobody is playing
+pub type TickActions = [Option<PackedActions>; MAX_PLAYERS];
+
+// Training as the match started, what changed during it is in the edits
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TrainingSetup {
+    pub enabled: bool,
+    pub infinite_stocks: bool,
+    pub dummy: DummyBehaviour,
+}
+
+impl TrainingSetup {
+    fn of(training: &Training) -> TrainingSetup {
+        TrainingSetup {
+            enabled: training.enabled,
+            infinite_stocks: training.infinite_stocks,
+            dummy: training.dummy,
+        }
+    }
+}
+
+pub struct Replay {
+    pub seed: u64,
+    pub stage: Stage,
+    pub hit_stop: bool,
+    pub aim_assist: bool,
+    pub training: TrainingSetup,
+    pub teams: Teams,
+    // In the order players joined
+    pub roster: Vec<RosterEntry>,
+    // Index 0 is tick 1
+    pub ticks: Vec<TickActions>,
+    // By the tick they were made on, in order
+    pub training_edits: Vec<(u32, TrainingEdit)>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u16(reader: &mut impl Read) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+impl Replay {
+    // Layout, all little endian:
+    //   magic, version u16, tick rate u16, seed u64, stage u8, hit stop u8, aim assist u8
+    //   training u8, infinite stocks u8, dummy u8, teams u8, shared stocks u8, friendly fire u8
+    //   roster count u8, then per player: slot u8, joined tick u32, team u8, name length u8, name
+    //   tick count u32, then per tick: slot mask u8, then per set bit 5 bytes of PackedActions
+    //   training edit count u32, then per edit: tick u32, 2 bytes of TrainingEdit
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(REPLAY_MAGIC)?;
+        writer.write_all(&REPLAY_VERSION.to_le_bytes())?;
+        writer.write_all(&(TICK_RATE as u16).to_le_bytes())?;
+        writer.write_all(&self.seed.to_le_bytes())?;
+        writer.write_all(&[self.stage.id(), self.hit_stop as u8, self.aim_assist as u8])?;
+        writer.write_all(&[
+            self.training.enabled as u8,
+            self.training.infinite_stocks as u8,
+            self.training.dummy.id(),
+            self.teams.enabled as u8,
+            self.teams.shared_stocks as u8,
+            self.teams.friendly_fire as u8,
+        ])?;
+
+        writer.write_all(&[self.roster.len() as u8])?;
+        for entry in self.roster.iter() {
+            let name = entry.character.as_bytes();
+            let name = &name[..name.len().min(u8::MAX as usize)];
+            writer.write_all(&[entry.slot])?;
+            writer.write_all(&entry.joined_tick.to_le_bytes())?;
+            writer.write_all(&[entry.team.map_or(NO_TEAM, |team| team.0 as u8)])?;
+            writer.write_all(&[name.len() as u8])?;
+            writer.write_all(name)?;
+        }
+
+        writer.write_all(&(self.ticks.len() as u32).to_le_bytes())?;
+        for tick in self.ticks.iter() {
+            let mask = tick
+                .iter()
+                .enumerate()
+                .filter(|(_, actions)| actions.is_some())
+                .fold(0u8, |mask, (slot, _)| mask | 1 << slot);
+            writer.write_all(&[mask])?;
+
+            for actions in tick.iter().flatten() {
+                writer.write_all(&[
+                    actions.movement[0] as u8,
+                    actions.movement[1] as u8,
+                    actions.aim[0] as u8,
+                    actions.aim[1] as u8,
+                    actions.buttons,
+                ])?;
+            }
+        }
+
+        writer.write_all(&(self.training_edits.len() as u32).to_le_bytes())?;
+        for (tick, edit) in self.training_edits.iter() {
+            writer.write_all(&tick.to_le_bytes())?;
+            writer.write_all(&edit.to_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read(reader: &mut impl Read) -> io::Result<Replay> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != REPLAY_MAGIC {
+            return Err(invalid("not a replay file"));
+        }
+        let version = read_u16(reader)?;
+        if version != REPLAY_VERSION {
+            return Err(invalid(&format!(
+                "replay is version {}, this build plays version {}",
+                version, REPLAY_VERSION
+            )));
+        }
+        if read_u16(reader)? != TICK_RATE as u16 {
+            return Err(invalid("replay was recorded at a different tick rate"));
+        }
+
+        let seed = read_u64(reader)?;
+        let stage = Stage::from_id(read_u8(reader)?).ok_or_else(|| invalid("unknown stage"))?;
+        let hit_stop = read_u8(reader)? != 0;
+        let aim_assist = read_u8(reader)? != 0;
+        let training = TrainingSetup {
+            enabled: read_u8(reader)? != 0,
+            infinite_stocks: read_u8(reader)? != 0,
+            dummy: DummyBehaviour::from_id(read_u8(reader)?).ok_or_else(|| invalid("unknown dummy behaviour"))?,
+        };
+        let teams = Teams {
+            enabled: read_u8(reader)? != 0,
+            shared_stocks: read_u8(reader)? != 0,
+            friendly_fire: read_u8(reader)? != 0,
+        };
+
+        let roster_count = read_u8(reader)?;
+        let mut roster = Vec::with_capacity(roster_count as usize);
+        for _ in 0..roster_count {
+            let slot = read_u8(reader)?;
+            if slot as usize >= MAX_PLAYERS {
+                return Err(invalid("player slot out of range"));
+            }
+            let joined_tick = read_u32(reader)?;
+            let team = match read_u8(reader)? {
+                NO_TEAM => None,
+                team if (team as usize) < TEAM_COUNT => Some(Team(team as usize)),
+                _ => return Err(invalid("team out of range")),
+            };
+            let mut name = vec![0; read_u8(reader)? as usize];
+            reader.read_exact(&mut name)?;
+            let character = String::from_utf8(name).map_err(|_| invalid("character name isn't utf-8"))?;
+            roster.push(RosterEntry {
+                slot,
+                joined_tick,
+                character,
+                team,
+            });
+        }
+
+        let tick_count = read_u32(reader)?;
+        let mut ticks = Vec::with_capacity(tick_count as usize);
+        for _ in 0..tick_count {
+            let mask = read_u8(reader)?;
+            let mut tick: TickActions = Default::default();
+            for (slot, actions) in tick.iter_mut().enumerate() {
+                if mask & 1 << slot == 0 {
+                    continue;
+                }
+                let mut bytes = [0; 5];
+                reader.read_exact(&mut bytes)?;
+                *actions = Some(PackedActions {
+                    movement: [bytes[0] as i8, bytes[1] as i8],
+                    aim: [bytes[2] as i8, bytes[3] as i8],
+                    buttons: bytes[4],
+                });
+            }
+            ticks.push(tick);
+        }
+
+        let edit_count = read_u32(reader)?;
+        let mut training_edits = Vec::new();
+        for _ in 0..edit_count {
+            let tick = read_u32(reader)?;
+            let bytes = [read_u8(reader)?, read_u8(reader)?];
+            let edit = TrainingEdit::from_bytes(bytes).ok_or_else(|| invalid("unknown training edit"))?;
+            training_edits.push((tick, edit));
+        }
+
+        Ok(Replay {
+            seed,
+            stage,
+            hit_stop,
+            aim_assist,
+            training,
+            teams,
+            roster,
+            ticks,
+            training_edits,
+        })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Replay> {
+        let bytes = fs::read(path)?;
+        Replay::read(&mut bytes.as_slice())
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut bytes = Vec::new();
+        self.write(&mut bytes)?;
+        fs::write(path, bytes)
+    }
+}
+
+pub fn replay_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("smashbubs").join(REPLAY_DIR))
+}
+
+#[derive(Default)]
+pub struct ReplayRecorder {
+    // Taken as the match starts
+    training: Option<TrainingSetup>,
+    roster: Vec<RosterEntry>,
+    ticks: Vec<TickActions>,
+    training_edits: Vec<(u32, TrainingEdit)>,
+    present: [bool; MAX_PLAYERS],
+}
+
+pub struct ReplayPlayback {
+    pub replay: Replay,
+    // Roster entries spawned so far
+    spawned: usize,
+    training_edits_made: usize,
+    finished: bool,
+}
+
+fn character_index(library: &CharacterLibrary, name: &str) -> usize {
+    match library
+        .characters
+        .iter()
+        .position(|character| character.definition.name == name)
+    {
+        Some(index) => index,
+        None => {
+            warn!("Replay uses missing character {}, using the default", name);
+            library.default_index()
+        }
+    }
+}
+
+// Spawns everyone who has to be around by `tick`. Entities spawned now only show up on the
+// next tick, so callers ask one tick ahead.
+fn spawn_replay_players(
+    commands: &mut Commands,
+    library: &CharacterLibrary,
+    playback: &mut ReplayPlayback,
+    tick: u64,
+) {
+    while let Some(entry) = playback.replay.roster.get(playback.spawned) {
+        if entry.joined_tick as u64 > tick {
+            break;
+        }
+
+        let character = character_index(library, &entry.character);
+        let slot = entry.slot as usize;
+        let player = spawn_player(commands, library, Gamepad(slot), slot, character);
+        if let (Some(player), Some(team)) = (player, entry.team) {
+            commands.entity(player).insert(team);
+        }
+        playback.spawned += 1;
+    }
+}
+
+fn spawn_starting_replay_players(
+    mut commands: Commands,
+    library: Res<CharacterLibrary>,
+    mut playback: ResMut<ReplayPlayback>,
+) {
+    spawn_replay_players(&mut commands, &library, &mut playback, 1);
+}
+
+// Before the match starts, so it doesn't spawn a dummy of its own
+fn set_up_replay_training(playback: Res<ReplayPlayback>, mut training: ResMut<Training>) {
+    let setup = playback.replay.training;
+    training.enabled = setup.enabled;
+    training.infinite_stocks = setup.infinite_stocks;
+    training.dummy = setup.dummy;
+}
+
+// Everyone who starts the match brings their stocks to the pool, like they did from the lobby
+fn set_up_replay_teams(playback: Res<ReplayPlayback>, mut teams: ResMut<Teams>, mut stocks: ResMut<TeamStocks>) {
+    let replay = &playback.replay;
+    *teams = replay.teams;
+    if teams.shares_stocks() {
+        *stocks = TeamStocks::for_players(
+            replay
+                .roster
+                .iter()
+                .filter(|entry| entry.joined_tick <= 1)
+                .filter_map(|entry| entry.team),
+        );
+    }
+}
+
+// The roster is already known, straight into the match
+fn skip_lobby(mut state: ResMut<State<AppState>>) {
+    if let Err(err) = state.set(AppState::Match) {
+        warn!("Failed to start the replay. {:?}", err);
+    }
+}
+
+// The settings a replay brings with it, set as it plays
+#[derive(SystemParam)]
+pub struct PlaybackSetup<'a> {
+    hit_stop: ResMut<'a, HitStopSettings>,
+    aim: ResMut<'a, AimSettings>,
+    training: ResMut<'a, Training>,
+}
+
+fn play_replay(
+    mut commands: Commands,
+    clock: Res<SimulationClock>,
+    library: Res<CharacterLibrary>,
+    playback: Option<ResMut<ReplayPlayback>>,
+    mut setup: PlaybackSetup,
+    mut query: Query<(&PlayerSlot, &mut PlayerActions), With<Player>>,
+) {
+    let mut playback = match playback {
+        Some(playback) => playback,
+        None => return,
+    };
+
+    setup.hit_stop.enabled = playback.replay.hit_stop;
+    setup.aim.assist = playback.replay.aim_assist;
+    spawn_replay_players(&mut commands, &library, &mut playback, clock.tick + 1);
+
+    while let Some((tick, edit)) = playback.replay.training_edits.get(playback.training_edits_made).copied() {
+        if tick as u64 > clock.tick {
+            break;
+        }
+        setup.training.pending.push(edit);
+        playback.training_edits_made += 1;
+    }
+
+    let tick = playback.replay.ticks.get(clock.tick as usize - 1).copied();
+    if tick.is_none() && !playback.finished {
+        info!("Replay finished after {} ticks", playback.replay.ticks.len());
+        playback.finished = true;
+    }
+
+    for (slot, mut actions) in query.iter_mut() {
+        *actions = tick
+            .and_then(|tick| tick.get(slot.0).copied().flatten())
+            .map(|packed| packed.unpack())
+            .unwrap_or_default();
+    }
+}
+
+fn record_replay(
+    clock: Res<SimulationClock>,
+    library: Res<CharacterLibrary>,
+    training: Res<Training>,
+    recorder: Option<ResMut<ReplayRecorder>>,
+    query: Query<(&PlayerSlot, &PlayerCharacter, &PlayerActions, Option<&Team>), With<Player>>,
+) {
+    let mut recorder = match recorder {
+        Some(recorder) => recorder,
+        None => return,
+    };
+
+    // Ticks being run again after a netplay rollback replace what was recorded for them
+    if recorder.ticks.len() as u64 >= clock.tick {
+        recorder.ticks.truncate(clock.tick as usize - 1);
+        recorder.roster.retain(|entry| (entry.joined_tick as u64) < clock.tick);
+        recorder.training_edits.retain(|(tick, _)| (*tick as u64) < clock.tick);
+        recorder.present = recorder
+            .ticks
+            .last()
+            .map_or([false; MAX_PLAYERS], |tick| tick.map(|actions| actions.is_some()));
+    }
+
+    let mut tick: TickActions = Default::default();
+    let mut present = [false; MAX_PLAYERS];
+
+    for (slot, character, actions, team) in query.iter() {
+        if slot.0 >= MAX_PLAYERS {
+            continue;
+        }
+        tick[slot.0] = Some(actions.pack());
+        present[slot.0] = true;
+
+        // A slot that was empty last tick means a new player, even if someone used it before
+        if !recorder.present[slot.0] {
+            let character = library
+                .get(character.0)
+                .map(|character| character.definition.name.clone())
+                .unwrap_or_default();
+            recorder.roster.push(RosterEntry {
+                slot: slot.0 as u8,
+                joined_tick: clock.tick as u32,
+                character,
+                team: team.copied(),
+            });
+        }
+    }
+
+    // Pad out anything missed so the index always lines up with the tick
+    while (recorder.ticks.len() as u64) < clock.tick - 1 {
+        recorder.ticks.push(Default::default());
+    }
+    recorder.ticks.push(tick);
+    recorder.present = present;
+
+    // Made after this, on this tick
+    for edit in training.pending.iter() {
+        recorder.training_edits.push((clock.tick as u32, *edit));
+    }
+}
+
+// A rematch is a replay of its own, starting from tick 1 again
+fn reset_recorder(training: Res<Training>, mut recorder: ResMut<ReplayRecorder>) {
+    *recorder = ReplayRecorder {
+        training: Some(TrainingSetup::of(&training)),
+        ..Default::default()
+    };
+}
+
+// Everything about the match that goes in a replay's header
+#[derive(SystemParam)]
+pub struct MatchSetup<'a> {
+    seed: Res<'a, MatchSeed>,
+    stage: Res<'a, CurrentStage>,
+    hit_stop: Res<'a, HitStopSettings>,
+    aim: Res<'a, AimSettings>,
+    training: Res<'a, Training>,
+    teams: Res<'a, Teams>,
+}
+
+fn save_replay(
+    keys: Res<Input<KeyCode>>,
+    mut exit_events: EventReader<AppExit>,
+    setup: MatchSetup,
+    recorder: Res<ReplayRecorder>,
+) {
+    let exiting = exit_events.iter().count() > 0;
+    if !keys.just_pressed(KeyCode::F9) && !exiting {
+        return;
+    }
+    if recorder.ticks.is_empty() {
+        return;
+    }
+
+    let dir = match replay_dir() {
+        Some(dir) => dir,
+        None => {
+            warn!("No config directory to save replays to");
+            return;
+        }
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let path = dir.join(format!("replay-{}.{}", timestamp, REPLAY_EXTENSION));
+
+    let replay = Replay {
+        seed: setup.seed.0,
+        stage: setup.stage.0,
+        hit_stop: setup.hit_stop.enabled,
+        aim_assist: setup.aim.assist,
+        training: recorder.training.unwrap_or_else(|| TrainingSetup::of(&setup.training)),
+        teams: *setup.teams,
+        roster: recorder.roster.clone(),
+        ticks: recorder.ticks.clone(),
+        training_edits: recorder.training_edits.clone(),
+    };
+
+    match replay.save(&path) {
+        Ok(()) => info!("Saved replay to {}", path.display()),
+        Err(err) => error!("Failed to save replay to {}. {}", path.display(), err),
+    }
+}
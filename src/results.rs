@@ -0,0 +1,165 @@
+use super::actions::*;
+use super::app_state::*;
+use super::netplay::*;
+use super::options_menu::*;
+use super::stats::*;
+use super::teams::*;
+use bevy::prelude::*;
+
+// Long enough that someone still mashing when the match ends doesn't skip past it
+const MIN_RESULTS_SECONDS: f32 = 1.5;
+
+// A match is over once there's one player, or one team, left in it. Then everyone gets a look at how it went
+// until someone presses South to go back to the lobby.
+pub struct ResultsPlugin;
+impl Plugin for ResultsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(ResultsScreen::default())
+            .add_startup_system(setup_results_text.system())
+            .add_system_set(SystemSet::on_update(AppState::Match).with_system(end_decided_match.system()))
+            .add_system_set(SystemSet::on_enter(AppState::Results).with_system(show_results.system()))
+            .add_system_set(SystemSet::on_update(AppState::Results).with_system(leave_results.system()))
+            .add_system(update_results_text.system());
+    }
+}
+
+#[derive(Default)]
+struct ResultsScreen {
+    shown_for: f32,
+}
+
+struct ResultsText;
+
+fn setup_results_text(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(80.),
+                    left: Val::Px(20.),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: asset_server.load(MENU_FONT),
+                    font_size: 16.,
+                    color: Color::WHITE,
+                },
+                Default::default(),
+            ),
+            visible: Visible {
+                is_visible: false,
+                is_transparent: true,
+            },
+            ..Default::default()
+        })
+        .insert(ResultsText);
+}
+
+// Over netplay the last KO might still be rolled back, so it only counts once everyone's inputs
+// up to it are in
+fn end_decided_match(
+    source: Res<ActionSource>,
+    netplay: Option<Res<Netplay>>,
+    stats: Res<MatchStats>,
+    mut state: ResMut<State<AppState>>,
+) {
+    let decided_at = match stats.decided_at() {
+        Some(decided_at) => decided_at,
+        None => return,
+    };
+    if *source == ActionSource::Network {
+        let confirmed = netplay.map_or(0, |netplay| netplay.session.confirmed_tick());
+        if decided_at > confirmed {
+            return;
+        }
+    }
+
+    if let Err(err) = state.set(AppState::Results) {
+        warn!("Failed to end the match. {:?}", err);
+    }
+}
+
+fn show_results(mut screen: ResMut<ResultsScreen>) {
+    screen.shown_for = 0.;
+}
+
+// Not Start, the lobby would take that as readying up again
+fn leave_results(
+    time: Res<Time>,
+    buttons: Res<Input<GamepadButton>>,
+    mut screen: ResMut<ResultsScreen>,
+    mut state: ResMut<State<AppState>>,
+) {
+    screen.shown_for += time.delta_seconds();
+    if screen.shown_for < MIN_RESULTS_SECONDS {
+        return;
+    }
+
+    let pressed_south = buttons
+        .get_just_pressed()
+        .any(|GamepadButton(_, button)| *button == GamepadButtonType::South);
+    if pressed_south {
+        if let Err(err) = state.set(AppState::Lobby) {
+            warn!("Failed to go back to the lobby. {:?}", err);
+        }
+    }
+}
+
+fn place(index: usize) -> &'static str {
+    match index {
+        0 => "1st",
+        1 => "2nd",
+        2 => "3rd",
+        _ => "4th",
+    }
+}
+
+fn update_results_text(
+    state: Res<State<AppState>>,
+    stats: Res<MatchStats>,
+    mut query: Query<(&mut Text, &mut Visible), With<ResultsText>>,
+) {
+    let showing = *state.current() == AppState::Results;
+    for (mut text, mut visible) in query.iter_mut() {
+        visible.is_visible = showing;
+        if !showing {
+            continue;
+        }
+
+        let headline = match (stats.winning_team(), stats.winner()) {
+            (Some(team), _) => format!("GAME!  {} team wins", Team(team).name()),
+            (None, Some(slot)) => format!("GAME!  P{} wins", slot + 1),
+            (None, None) => "GAME!  No contest".to_string(),
+        };
+        let mut lines = vec![
+            headline,
+            String::new(),
+            "        Team Character  Shots Hits  Acc  Dealt Taken  KO  SD  Alive".to_string(),
+        ];
+        for (index, player) in stats.standings().into_iter().enumerate() {
+            lines.push(format!(
+                "{} P{}  {:<4} {:<10} {:>5} {:>4} {:>3.0}% {:>5.0} {:>5.0} {:>3} {:>3} {:>5.0}s",
+                place(index),
+                player.slot + 1,
+                player.team.map_or("-", |team| Team(team).name()),
+                player.character,
+                player.shots_fired,
+                player.hits,
+                player.accuracy() * 100.,
+                player.damage_dealt,
+                player.damage_taken,
+                player.kos,
+                player.self_destructs,
+                player.seconds_alive(),
+            ));
+        }
+        lines.push(String::new());
+        lines.push("South: back to character select".to_string());
+        text.sections[0].value = lines.join("\n");
+    }
+}
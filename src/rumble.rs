@@ -0,0 +1,387 @@
+use super::events::*;
+use super::gilrs_plugin::*;
+use super::pause::*;
+use super::player::*;
+use super::settings::*;
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use gilrs::ff::{BaseEffect, BaseEffectType, Effect, EffectBuilder, Envelope, Repeat, Replay, Ticks};
+use gilrs::{GamepadId, Gilrs};
+use std::cmp::Reverse;
+
+// Patterns mixed on a single pad at once. Past this the lowest priority gets dropped.
+const MAX_LAYERS: usize = 3;
+// Layers below the highest priority still play, just quieter
+const DUCKED_INTENSITY: f32 = 0.3;
+// Damage at which getting hit also sends a warning pulse
+const WARNING_DAMAGE: f32 = 15.;
+
+pub struct RumblePlugin;
+impl Plugin for RumblePlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        // Requests can always be sent, they just go nowhere without a gamepad backend
+        app.add_event::<RumbleRequest>();
+        if app.world().get_non_send_resource::<Gilrs>().is_none() {
+            info!("No gamepad backend, rumble disabled");
+            return;
+        }
+
+        app.insert_non_send_resource(RumbleManager::default())
+            .add_system(track_rumble_gamepads.system().label("track_rumble_gamepads"))
+            .add_system(rumble_gameplay_events.system().label("rumble_gameplay_events"))
+            .add_system(
+                play_rumble_requests
+                    .system()
+                    .after("track_rumble_gamepads")
+                    .after("rumble_gameplay_events"),
+            );
+    }
+}
+
+// Any system can send one of these to buzz a specific pad
+pub struct RumbleRequest {
+    pub gamepad: Gamepad,
+    pub pattern: RumblePattern,
+    // Scales the pattern's magnitudes, 1 is the pattern as designed
+    pub intensity: f32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum RumblePattern {
+    LightShot,
+    HeavyHit,
+    Ko,
+    // Slow double pulse for when a player's damage is getting dangerously high
+    LowDamageWarning,
+}
+
+impl RumblePattern {
+    pub fn priority(&self) -> u8 {
+        match self {
+            RumblePattern::LightShot => 0,
+            RumblePattern::LowDamageWarning => 1,
+            RumblePattern::HeavyHit => 2,
+            RumblePattern::Ko => 3,
+        }
+    }
+
+    pub fn duration_ms(&self) -> u32 {
+        match self {
+            RumblePattern::LightShot => 80,
+            RumblePattern::HeavyHit => 300,
+            RumblePattern::Ko => 700,
+            RumblePattern::LowDamageWarning => 600,
+        }
+    }
+
+    fn pulses(&self) -> Vec<Pulse> {
+        match self {
+            RumblePattern::LightShot => vec![pulse(Motor::Weak, 25_000, 0, 80, 0, 40)],
+            RumblePattern::HeavyHit => vec![
+                pulse(Motor::Strong, 50_000, 0, 300, 0, 200),
+                pulse(Motor::Weak, 30_000, 0, 150, 0, 100),
+            ],
+            RumblePattern::Ko => vec![
+                pulse(Motor::Strong, 65_000, 0, 700, 100, 500),
+                pulse(Motor::Weak, 65_000, 0, 400, 0, 300),
+            ],
+            RumblePattern::LowDamageWarning => vec![
+                pulse(Motor::Strong, 20_000, 0, 150, 50, 50),
+                pulse(Motor::Strong, 20_000, 300, 150, 50, 50),
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Motor {
+    Strong,
+    Weak,
+}
+
+// A single motor burst starting `after` ms in, ramping up over `attack` ms and out over `fade` ms.
+// Kept in ms rather than gilrs ticks so it can be cut short when a pattern is already underway.
+#[derive(Clone, Copy)]
+struct Pulse {
+    motor: Motor,
+    magnitude: u16,
+    after: u32,
+    play_for: u32,
+    attack: u32,
+    fade: u32,
+}
+
+fn pulse(motor: Motor, magnitude: u16, after: u32, play_for: u32, attack: u32, fade: u32) -> Pulse {
+    Pulse {
+        motor,
+        magnitude,
+        after,
+        play_for,
+        attack,
+        fade,
+    }
+}
+
+impl Pulse {
+    // What's left of it `elapsed` ms into the pattern, None if it's already over. A pulse cut
+    // off partway through its attack ramps up from zero again over what's left of it.
+    fn skip(self, elapsed: u32) -> Option<Pulse> {
+        if elapsed >= self.after + self.play_for {
+            return None;
+        }
+        let into = elapsed.saturating_sub(self.after);
+        let play_for = self.play_for - into;
+        Some(Pulse {
+            after: self.after.saturating_sub(elapsed),
+            play_for,
+            attack: self.attack.saturating_sub(into),
+            fade: self.fade.min(play_for),
+            ..self
+        })
+    }
+
+    fn effect(self, intensity: f32) -> BaseEffect {
+        let magnitude = (self.magnitude as f32 * intensity).min(u16::MAX as f32) as u16;
+        BaseEffect {
+            kind: match self.motor {
+                Motor::Strong => BaseEffectType::Strong { magnitude },
+                Motor::Weak => BaseEffectType::Weak { magnitude },
+            },
+            scheduling: Replay {
+                after: Ticks::from_ms(self.after),
+                play_for: Ticks::from_ms(self.play_for),
+                ..Default::default()
+            },
+            envelope: Envelope {
+                attack_length: Ticks::from_ms(self.attack),
+                attack_level: 0.,
+                fade_length: Ticks::from_ms(self.fade),
+                fade_level: 0.,
+            },
+        }
+    }
+}
+
+struct RumbleLayer {
+    pattern: RumblePattern,
+    intensity: f32,
+    timer: Timer,
+}
+
+#[derive(Default)]
+struct PadRumble {
+    layers: Vec<RumbleLayer>,
+    // Rebuilt whenever the layers change, with each layer picking up where it had got to.
+    // Dropping it stops the rumble.
+    effect: Option<Effect>,
+}
+
+// Tracks what's playing on every connected pad. Overlapping requests are mixed into one
+// effect instead of cancelling each other.
+#[derive(Default)]
+pub struct RumbleManager {
+    ids: HashMap<Gamepad, GamepadId>,
+    pads: HashMap<Gamepad, PadRumble>,
+    // Effects are stopped and the layer timers held while the match is paused
+    paused: bool,
+}
+
+impl RumbleManager {
+    fn rebuild(&mut self, gilrs: &mut Gilrs, gamepad: Gamepad, gain: f32) {
+        let pad = match self.pads.get_mut(&gamepad) {
+            Some(pad) => pad,
+            None => return,
+        };
+        pad.effect = None;
+
+        let id = match self.ids.get(&gamepad) {
+            Some(id) => *id,
+            None => return,
+        };
+        let top_priority = match pad.layers.iter().map(|layer| layer.pattern.priority()).max() {
+            Some(priority) => priority,
+            None => return,
+        };
+
+        let mut builder = EffectBuilder::new();
+        let mut pulses = 0;
+        let mut longest = 0;
+        for layer in pad.layers.iter() {
+            let ducking = if layer.pattern.priority() < top_priority {
+                DUCKED_INTENSITY
+            } else {
+                1.
+            };
+            let elapsed = layer.timer.elapsed().as_millis() as u32;
+            for pulse in layer.pattern.pulses().into_iter().filter_map(|pulse| pulse.skip(elapsed)) {
+                builder.add_effect(pulse.effect(layer.intensity * ducking));
+                pulses += 1;
+            }
+            longest = longest.max(layer.pattern.duration_ms().saturating_sub(elapsed));
+        }
+        // Only quiet tails left, like the gap at the end of the damage warning
+        if pulses == 0 {
+            return;
+        }
+
+        let effect = match builder
+            .gamepads(&[id])
+            .repeat(Repeat::For(Ticks::from_ms(longest)))
+            .finish(gilrs)
+        {
+            Ok(effect) => effect,
+            Err(err) => {
+                // Most likely the pad went away or its driver is refusing effects, so stop
+                // trying rather than warning on every shot
+                warn!("Failed to create rumble effect, disabling rumble for {:?}. {}", gamepad, err);
+                self.pads.remove(&gamepad);
+                return;
+            }
+        };
+        if let Err(err) = effect.set_gain(gain) {
+            warn!("Failed to set rumble strength for {:?}. {}", gamepad, err);
+        }
+        if let Err(err) = effect.play() {
+            warn!("Failed to play rumble on {:?}. {}", gamepad, err);
+        }
+        pad.effect = Some(effect);
+    }
+}
+
+fn track_rumble_gamepads(
+    gilrs: NonSend<Gilrs>,
+    mut rumble: NonSendMut<RumbleManager>,
+    mut gamepad_events: EventReader<GamepadEvent>,
+) {
+    for GamepadEvent(gamepad, kind) in gamepad_events.iter() {
+        match kind {
+            GamepadEventType::Connected => {
+                let pad = gilrs
+                    .gamepads()
+                    .find(|(id, _)| convert_gamepad_id(*id) == *gamepad);
+                match pad {
+                    Some((id, pad)) if pad.is_ff_supported() => {
+                        rumble.ids.insert(*gamepad, id);
+                        rumble.pads.insert(*gamepad, PadRumble::default());
+                    }
+                    Some((_, pad)) => info!("{} doesn't support rumble", pad.name()),
+                    None => {}
+                }
+            }
+            GamepadEventType::Disconnected => {
+                rumble.ids.remove(gamepad);
+                rumble.pads.remove(gamepad);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn rumble_gameplay_events(
+    mut requests: EventWriter<RumbleRequest>,
+    mut fire_events: EventReader<PlayerFireEvent>,
+    mut hit_events: EventReader<PlayerHitEvent>,
+    mut ko_events: EventReader<PlayerKoEvent>,
+    players: Query<(&Gamepad, &DamageTaken), With<Player>>,
+) {
+    for event in fire_events.iter().filter(|event| !event.rerun) {
+        if let Ok((gamepad, _)) = players.get(event.player) {
+            requests.send(RumbleRequest {
+                gamepad: *gamepad,
+                pattern: RumblePattern::LightShot,
+                intensity: 1.,
+            });
+        }
+    }
+    for event in hit_events.iter().filter(|event| !event.rerun) {
+        if let Ok((gamepad, damage_taken)) = players.get(event.victim) {
+            requests.send(RumbleRequest {
+                gamepad: *gamepad,
+                pattern: RumblePattern::HeavyHit,
+                intensity: (0.5 + event.knockback / 400.).min(1.),
+            });
+            if damage_taken.0 >= WARNING_DAMAGE {
+                requests.send(RumbleRequest {
+                    gamepad: *gamepad,
+                    pattern: RumblePattern::LowDamageWarning,
+                    intensity: 1.,
+                });
+            }
+        }
+    }
+    for event in ko_events.iter().filter(|event| !event.rerun) {
+        if let Ok((gamepad, _)) = players.get(event.player) {
+            requests.send(RumbleRequest {
+                gamepad: *gamepad,
+                pattern: RumblePattern::Ko,
+                intensity: 1.,
+            });
+        }
+    }
+}
+
+fn play_rumble_requests(
+    time: Res<Time>,
+    settings: Res<Settings>,
+    pause: Res<MatchPause>,
+    mut gilrs: NonSendMut<Gilrs>,
+    mut rumble: NonSendMut<RumbleManager>,
+    mut requests: EventReader<RumbleRequest>,
+) {
+    let mut changed = HashSet::default();
+
+    if pause.is_paused() {
+        if !rumble.paused {
+            rumble.paused = true;
+            for pad in rumble.pads.values_mut() {
+                pad.effect = None;
+            }
+        }
+        // Nothing happening in the match should be felt until it carries on
+        requests.iter().for_each(drop);
+        return;
+    }
+    if rumble.paused {
+        rumble.paused = false;
+        // Patterns still going pick up where they were stopped
+        changed.extend(
+            rumble
+                .pads
+                .iter()
+                .filter(|(_, pad)| !pad.layers.is_empty())
+                .map(|(gamepad, _)| *gamepad),
+        );
+    }
+
+    for (gamepad, pad) in rumble.pads.iter_mut() {
+        let before = pad.layers.len();
+        pad.layers
+            .retain_mut(|layer| !layer.timer.tick(time.delta()).finished());
+        if pad.layers.len() != before {
+            changed.insert(*gamepad);
+        }
+    }
+
+    for request in requests.iter() {
+        let pad = match rumble.pads.get_mut(&request.gamepad) {
+            Some(pad) => pad,
+            None => continue,
+        };
+
+        // Repeating a pattern that's already playing restarts it instead of stacking
+        pad.layers.retain(|layer| layer.pattern != request.pattern);
+        pad.layers.push(RumbleLayer {
+            pattern: request.pattern,
+            intensity: request.intensity,
+            timer: Timer::from_seconds(request.pattern.duration_ms() as f32 / 1000., false),
+        });
+        pad.layers.sort_by_key(|layer| Reverse(layer.pattern.priority()));
+        pad.layers.truncate(MAX_LAYERS);
+
+        changed.insert(request.gamepad);
+    }
+
+    for gamepad in changed {
+        rumble.rebuild(&mut gilrs, gamepad, settings.input.rumble_strength);
+    }
+}
@@ -0,0 +1,286 @@
+use super::aim::*;
+use super::camera::*;
+use super::hit_stop::*;
+use super::player::*;
+use super::stick::*;
+use bevy::prelude::*;
+use bevy::window::WindowMode;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+const SETTINGS_FILE: &str = "settings.ron";
+
+// Has to be added before WindowPlugin, which builds the window from these settings. Reads
+// from a SettingsFile inserted before it, or the one in the config directory if there isn't one.
+pub struct SettingsPlugin;
+impl Plugin for SettingsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        let file = match app.world().get_resource::<SettingsFile>() {
+            Some(file) => SettingsFile(file.0.clone()),
+            None => SettingsFile(Settings::path()),
+        };
+        app.insert_resource(Settings::load(&file))
+            .insert_resource(file)
+            .add_system(apply_settings.system());
+    }
+}
+
+// Where settings are loaded from and saved to, None to stick to the defaults and not touch disk
+pub struct SettingsFile(pub Option<PathBuf>);
+
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    pub video: VideoSettings,
+    pub audio: AudioSettings,
+    pub input: InputSettings,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct VideoSettings {
+    pub resolution: (u32, u32),
+    pub fullscreen: bool,
+    pub window_position: (i32, i32),
+    pub clear_color: (f32, f32, f32),
+    pub screen_shake: bool,
+    pub hit_stop: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct AudioSettings {
+    pub master_volume: f32,
+    pub music_volume: f32,
+    pub sfx_volume: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct InputSettings {
+    pub rumble_strength: f32,
+    pub bindings: Bindings,
+    // One entry per player slot
+    pub sticks: Vec<StickSettings>,
+    pub aim_assist: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Bindings {
+    pub jump: PadButton,
+    pub fire: PadButton,
+}
+
+// Mirror of GamepadButtonType that can be written to the settings file
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum PadButton {
+    South,
+    East,
+    North,
+    West,
+    LeftTrigger,
+    LeftTrigger2,
+    RightTrigger,
+    RightTrigger2,
+    LeftThumb,
+    RightThumb,
+}
+
+pub const BINDABLE_BUTTONS: [PadButton; 10] = [
+    PadButton::South,
+    PadButton::East,
+    PadButton::North,
+    PadButton::West,
+    PadButton::LeftTrigger,
+    PadButton::LeftTrigger2,
+    PadButton::RightTrigger,
+    PadButton::RightTrigger2,
+    PadButton::LeftThumb,
+    PadButton::RightThumb,
+];
+
+impl From<PadButton> for GamepadButtonType {
+    fn from(button: PadButton) -> GamepadButtonType {
+        match button {
+            PadButton::South => GamepadButtonType::South,
+            PadButton::East => GamepadButtonType::East,
+            PadButton::North => GamepadButtonType::North,
+            PadButton::West => GamepadButtonType::West,
+            PadButton::LeftTrigger => GamepadButtonType::LeftTrigger,
+            PadButton::LeftTrigger2 => GamepadButtonType::LeftTrigger2,
+            PadButton::RightTrigger => GamepadButtonType::RightTrigger,
+            PadButton::RightTrigger2 => GamepadButtonType::RightTrigger2,
+            PadButton::LeftThumb => GamepadButtonType::LeftThumb,
+            PadButton::RightThumb => GamepadButtonType::RightThumb,
+        }
+    }
+}
+
+impl Default for VideoSettings {
+    fn default() -> VideoSettings {
+        VideoSettings {
+            resolution: (600, 600),
+            fullscreen: false,
+            window_position: (100, 100),
+            clear_color: (0.04, 0.04, 0.04),
+            screen_shake: true,
+            hit_stop: true,
+        }
+    }
+}
+
+impl Default for AudioSettings {
+    fn default() -> AudioSettings {
+        AudioSettings {
+            master_volume: 1.,
+            music_volume: 0.7,
+            sfx_volume: 1.,
+        }
+    }
+}
+
+impl Default for InputSettings {
+    fn default() -> InputSettings {
+        InputSettings {
+            rumble_strength: 1.,
+            bindings: Bindings::default(),
+            sticks: vec![StickSettings::default(); MAX_PLAYERS],
+            aim_assist: false,
+        }
+    }
+}
+
+impl Default for Bindings {
+    fn default() -> Bindings {
+        Bindings {
+            jump: PadButton::South,
+            fire: PadButton::RightTrigger2,
+        }
+    }
+}
+
+impl AudioSettings {
+    pub fn sfx_gain(&self) -> f32 {
+        self.master_volume * self.sfx_volume
+    }
+
+    pub fn music_gain(&self) -> f32 {
+        self.master_volume * self.music_volume
+    }
+}
+
+impl InputSettings {
+    pub fn sticks(&self, player: usize) -> StickSettings {
+        self.sticks.get(player).copied().unwrap_or_default()
+    }
+
+    // Fills in defaults for slots an older settings file didn't have
+    pub fn sticks_mut(&mut self, player: usize) -> &mut StickSettings {
+        if self.sticks.len() <= player {
+            self.sticks.resize(player + 1, StickSettings::default());
+        }
+        &mut self.sticks[player]
+    }
+}
+
+impl Settings {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("smashbubs").join(SETTINGS_FILE))
+    }
+
+    // Falls back to defaults if the file is missing or can't be read
+    pub fn load(file: &SettingsFile) -> Settings {
+        let path = match &file.0 {
+            Some(path) => path,
+            None => return Settings::default(),
+        };
+
+        match fs::read_to_string(path) {
+            Ok(contents) => ron::de::from_str(&contents).unwrap_or_else(|err| {
+                warn!("Ignoring invalid settings file {}. {}", path.display(), err);
+                Settings::default()
+            }),
+            Err(_) => Settings::default(),
+        }
+    }
+
+    pub fn save(&self, file: &SettingsFile) {
+        let path = match &file.0 {
+            Some(path) => path,
+            None => return,
+        };
+
+        let contents = match ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()) {
+            Ok(contents) => contents,
+            Err(err) => {
+                error!("Failed to serialize settings. {}", err);
+                return;
+            }
+        };
+
+        if let Some(dir) = path.parent() {
+            if let Err(err) = fs::create_dir_all(dir) {
+                error!("Failed to create {}. {}", dir.display(), err);
+                return;
+            }
+        }
+
+        if let Err(err) = fs::write(path, contents) {
+            error!("Failed to save settings to {}. {}", path.display(), err);
+        }
+    }
+
+    pub fn clear_color(&self) -> Color {
+        let (r, g, b) = self.video.clear_color;
+        Color::rgb(r, g, b)
+    }
+
+    pub fn window_mode(&self) -> WindowMode {
+        if self.video.fullscreen {
+            WindowMode::BorderlessFullscreen
+        } else {
+            WindowMode::Windowed
+        }
+    }
+}
+
+// Only touches the window for the parts that actually changed, so tweaking the volume doesn't
+// snap a moved window back to its saved position
+fn apply_settings(
+    settings: Res<Settings>,
+    mut applied: Local<Option<Settings>>,
+    mut windows: ResMut<Windows>,
+    mut clear_color: ResMut<ClearColor>,
+    mut screen_shake: ResMut<ScreenShakeSettings>,
+    mut hit_stop: ResMut<HitStopSettings>,
+    mut aim: ResMut<AimSettings>,
+) {
+    if applied.as_ref() == Some(&*settings) {
+        return;
+    }
+
+    clear_color.0 = settings.clear_color();
+    screen_shake.enabled = settings.video.screen_shake;
+    hit_stop.enabled = settings.video.hit_stop;
+    aim.assist = settings.input.aim_assist;
+
+    if let Some(window) = windows.get_primary_mut() {
+        let previous = applied.as_ref().map(|applied| &applied.video);
+
+        if previous.is_none_or(|video| video.resolution != settings.video.resolution) {
+            let (width, height) = settings.video.resolution;
+            window.set_resolution(width as f32, height as f32);
+        }
+        if previous.is_none_or(|video| video.fullscreen != settings.video.fullscreen) {
+            window.set_mode(settings.window_mode());
+        }
+        if previous.is_none() {
+            let (x, y) = settings.video.window_position;
+            window.set_position(IVec2::new(x, y));
+        }
+    }
+
+    *applied = Some(settings.clone());
+}
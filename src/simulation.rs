@@ -0,0 +1,213 @@
+use super::actions::*;
+use super::app_state::*;
+use super::pause::*;
+use super::snapshot::*;
+use bevy::ecs::schedule::ShouldRun;
+use bevy::prelude::*;
+use bevy::reflect::TypeRegistryArc;
+use heron::rapier_plugin::RapierPlugin;
+use heron::{PhysicsSteps, PhysicsTime};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::time::Duration;
+
+// Gameplay runs in its own stage at a fixed rate so the same inputs always play out the same way,
+// whatever the frame rate. Rendering, sound and effects stay in Update and read the events.
+//
+// Every tick runs in this order, anything touching the same components is labelled so the
+// scheduler can't shuffle it between runs:
+//   external_actions      replay or network inputs replace the pads'
+//   record_actions        replay recording, alongside resolve_aim
+//   reset_jumps           landings from the last physics step
+//   player_input          player_movement, then player_jump, then player_fire
+//   projectile_hit_player then projectile_hit_map, then clean_up_offscreen_projectiles
+//   start_hit_stop        then hold_hit_stop
+//   respawn_players       knockouts and lives
+//   clear_pressed_actions
+// and at the very end one physics step, the state checksum, then the netplay snapshot.
+pub const SIMULATION: &str = "simulation";
+pub const TICK_RATE: f64 = 60.;
+// Don't try to catch up on more than this many ticks after a long stall
+const MAX_TICKS_PER_FRAME: u32 = 5;
+
+pub struct SimulationPlugin;
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        let seed = rand::random();
+        app.insert_resource(SimulationClock::default())
+            .insert_resource(MatchSeed(seed))
+            .insert_resource(GameRng::from_seed(seed))
+            .insert_resource(StateChecksum::default())
+            // Heron's own once a frame step never runs, see physics_step
+            .insert_resource(PhysicsSteps::every_frame(Duration::from_secs_f64(1. / TICK_RATE)))
+            .insert_resource(PhysicsTime::new(0.))
+            .add_stage_after(
+                CoreStage::Update,
+                SIMULATION,
+                SystemStage::parallel().with_run_criteria(run_simulation_ticks.system()),
+            )
+            .add_system_set(SystemSet::on_enter(AppState::Match).with_system(start_match.system()))
+            .add_system_to_stage(
+                SIMULATION,
+                physics_step().exclusive_system().at_end().label("physics_step"),
+            )
+            .add_system_to_stage(
+                SIMULATION,
+                update_state_checksum
+                    .exclusive_system()
+                    .at_end()
+                    .label("state_checksum")
+                    .after("physics_step"),
+            );
+    }
+}
+
+#[derive(Default)]
+pub struct SimulationClock {
+    // Number of the tick currently running, starting at 1
+    pub tick: u64,
+    // Exactly one tick per frame, however long the frame took, for headless tests and
+    // simulations that want to run as fast as they can rather than in real time
+    pub stepped: bool,
+    // Ticks to run again straight away after rewinding, on top of the usual ones
+    pub resimulate: u64,
+    // The tick running now already ran once before a rollback
+    pub rerun: bool,
+    // Don't get ahead of this tick, for netplay waiting on inputs
+    pub max_tick: Option<u64>,
+    accumulator: f64,
+    ticks_this_frame: u32,
+    in_frame: bool,
+}
+
+impl SimulationClock {
+    pub fn stepped() -> SimulationClock {
+        SimulationClock {
+            stepped: true,
+            ..Default::default()
+        }
+    }
+}
+
+// Everything random in a match is derived from this, so it goes into replays
+pub struct MatchSeed(pub u64);
+
+// The only randomness gameplay is allowed, reseeded from the MatchSeed as each match starts and
+// rewound with everything else. Effects, sounds and screen shake don't change the outcome, so
+// they stick to rand::random and leave this alone.
+#[derive(Clone)]
+pub struct GameRng(pub ChaCha8Rng);
+
+impl GameRng {
+    pub fn from_seed(seed: u64) -> GameRng {
+        GameRng(ChaCha8Rng::seed_from_u64(seed))
+    }
+}
+
+// A hash of the whole simulation after the latest tick. Two runs with the same seed and inputs
+// should agree on it every tick, which is what netplay and the regression tests check.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct StateChecksum {
+    pub tick: u64,
+    pub checksum: u64,
+}
+
+// Every match counts its ticks from 1. A live one after the first draws a seed of its own, the
+// first keeps whatever it was given, and replays and netplay only ever have the one they agreed on.
+fn start_match(
+    source: Res<ActionSource>,
+    mut clock: ResMut<SimulationClock>,
+    mut seed: ResMut<MatchSeed>,
+    mut rng: ResMut<GameRng>,
+) {
+    if clock.tick > 0 && *source == ActionSource::Gamepads {
+        seed.0 = rand::random();
+    }
+    clock.tick = 0;
+    *rng = GameRng::from_seed(seed.0);
+}
+
+// Heron steps once a frame in PostUpdate, whether that frame ran no ticks or five. Its systems
+// are taken from a plugin of its own here and run at the end of every tick instead, so a tick is
+// always exactly one physics step, rewound ticks included. The copies the game's PhysicsPlugin
+// added stay in place but only ever see a time scale of zero, which stops them running.
+fn physics_step() -> impl FnMut(&mut World) {
+    let mut physics = App::build();
+    physics
+        .insert_resource(TypeRegistryArc::default())
+        .add_plugin(RapierPlugin);
+    let schedule = &mut physics.app.schedule;
+    // Sync bodies with their Transforms and Velocities, and create and remove them
+    let update_world = std::mem::take(schedule.get_stage_mut::<Schedule>(&"heron-physics").unwrap());
+    // The step itself, then Transforms and Velocities back out of it
+    let step = std::mem::replace(
+        schedule.get_stage_mut::<SystemStage>(&CoreStage::PostUpdate).unwrap(),
+        SystemStage::parallel(),
+    );
+    let mut physics_step = Schedule::default()
+        .with_stage("update_physics_world", update_world)
+        .with_stage("step_physics", step);
+
+    move |world: &mut World| {
+        world.get_resource_mut::<PhysicsTime>().unwrap().set_scale(1.);
+        physics_step.run(world);
+        world.get_resource_mut::<PhysicsTime>().unwrap().set_scale(0.);
+    }
+}
+
+fn update_state_checksum(world: &mut World) {
+    let tick = world.get_resource::<SimulationClock>().unwrap().tick;
+    let checksum = Snapshot::capture(world, tick).checksum();
+    *world.get_resource_mut::<StateChecksum>().unwrap() = StateChecksum { tick, checksum };
+}
+
+fn run_simulation_ticks(
+    time: Res<Time>,
+    state: Res<State<AppState>>,
+    pause: Res<MatchPause>,
+    mut clock: ResMut<SimulationClock>,
+) -> ShouldRun {
+    let step = 1. / TICK_RATE;
+
+    // Called once with a fresh frame, then again after every tick until it says no
+    if !clock.in_frame {
+        if *state.current() != AppState::Match || pause.is_paused() {
+            clock.accumulator = 0.;
+            return ShouldRun::No;
+        }
+        clock.in_frame = true;
+        if clock.stepped {
+            clock.accumulator = step;
+        } else {
+            clock.accumulator += time.delta_seconds_f64();
+        }
+    }
+
+    // Catching back up after a rollback doesn't use up any time
+    if clock.resimulate > 0 {
+        clock.resimulate -= 1;
+        clock.tick += 1;
+        clock.rerun = true;
+        return ShouldRun::YesAndCheckAgain;
+    }
+
+    let waiting = clock.max_tick.is_some_and(|max_tick| clock.tick >= max_tick);
+    if clock.accumulator >= step && clock.ticks_this_frame < MAX_TICKS_PER_FRAME && !waiting {
+        clock.accumulator -= step;
+        clock.ticks_this_frame += 1;
+        clock.tick += 1;
+        clock.rerun = false;
+        ShouldRun::YesAndCheckAgain
+    } else {
+        // Drop whatever we couldn't catch up on, and don't bank time while waiting
+        if clock.ticks_this_frame == MAX_TICKS_PER_FRAME {
+            clock.accumulator = 0.;
+        }
+        if waiting {
+            clock.accumulator = clock.accumulator.min(step);
+        }
+        clock.ticks_this_frame = 0;
+        clock.in_frame = false;
+        ShouldRun::No
+    }
+}
@@ -0,0 +1,326 @@
+use super::aim::*;
+use super::character::*;
+use super::hit_stop::*;
+use super::player::*;
+use super::projectile::*;
+use super::simulation::*;
+use super::stats::*;
+use super::teams::*;
+use bevy::ecs::system::CommandQueue;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use heron::prelude::*;
+
+// Everything the simulation needs to carry on from a tick, so it can be rewound to it. Physics
+// internals aren't in here, heron picks the state back up from Transform and Velocity.
+pub struct Snapshot {
+    pub tick: u64,
+    players: Vec<PlayerSnapshot>,
+    projectiles: Vec<ProjectileSnapshot>,
+    rng: Option<GameRng>,
+    // So hits and KOs that get rolled back aren't counted twice
+    stats: Option<MatchStats>,
+    team_stocks: Option<TeamStocks>,
+}
+
+struct PlayerSnapshot {
+    entity: Entity,
+    slot: usize,
+    // Enough to spawn them again if they were knocked out for good since
+    gamepad: Gamepad,
+    character: usize,
+    team: Option<Team>,
+    translation: Vec3,
+    velocity: Velocity,
+    jumps: i8,
+    lives: i8,
+    damage: f32,
+    speed: f32,
+    aim: AimDirection,
+    hit_stop: Option<HitStop>,
+    last_attacker: LastAttacker,
+}
+
+struct ProjectileSnapshot {
+    entity: Entity,
+    owner: Entity,
+    translation: Vec3,
+    velocity: Velocity,
+}
+
+// FNV-1a, good enough to tell two machines apart and the same everywhere
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_f32(&mut self, value: f32) {
+        self.write(&value.to_bits().to_le_bytes());
+    }
+
+    fn write_vec3(&mut self, value: Vec3) {
+        self.write_f32(value.x);
+        self.write_f32(value.y);
+        self.write_f32(value.z);
+    }
+}
+
+impl Snapshot {
+    pub fn capture(world: &mut World, tick: u64) -> Snapshot {
+        let mut players: Vec<PlayerSnapshot> = world
+            .query_filtered::<(
+                Entity,
+                &PlayerSlot,
+                &Gamepad,
+                &PlayerCharacter,
+                Option<&Team>,
+                &Transform,
+                &Velocity,
+                &AvailableJumps,
+                &Lives,
+                &DamageTaken,
+                &Speed,
+                &AimDirection,
+                Option<&HitStop>,
+                &LastAttacker,
+            ), With<Player>>()
+            .iter(world)
+            .map(
+                |(
+                    entity,
+                    slot,
+                    gamepad,
+                    character,
+                    team,
+                    transform,
+                    velocity,
+                    jumps,
+                    lives,
+                    damage,
+                    speed,
+                    aim,
+                    hit_stop,
+                    last_attacker,
+                )| PlayerSnapshot {
+                    entity,
+                    slot: slot.0,
+                    gamepad: *gamepad,
+                    character: character.0,
+                    team: team.copied(),
+                    translation: transform.translation,
+                    velocity: *velocity,
+                    jumps: jumps.0,
+                    lives: lives.0,
+                    damage: damage.0,
+                    speed: speed.0,
+                    aim: *aim,
+                    hit_stop: hit_stop.copied(),
+                    last_attacker: *last_attacker,
+                },
+            )
+            .collect();
+        players.sort_by_key(|player| player.slot);
+
+        let projectiles = world
+            .query_filtered::<(Entity, &ProjectileOwner, &Transform, &Velocity), With<Projectile>>()
+            .iter(world)
+            .map(|(entity, owner, transform, velocity)| ProjectileSnapshot {
+                entity,
+                owner: owner.0,
+                translation: transform.translation,
+                velocity: *velocity,
+            })
+            .collect();
+
+        Snapshot {
+            tick,
+            players,
+            projectiles,
+            rng: world.get_resource::<GameRng>().cloned(),
+            stats: world.get_resource::<MatchStats>().cloned(),
+            team_stocks: world.get_resource::<TeamStocks>().copied(),
+        }
+    }
+
+    // Only covers what every machine agrees on, entity ids differ between them
+    pub fn checksum(&self) -> u64 {
+        let mut hash = Fnv::new();
+        for player in self.players.iter() {
+            hash.write(&[player.slot as u8, player.jumps as u8, player.lives as u8]);
+            hash.write_vec3(player.translation);
+            hash.write_vec3(player.velocity.linear);
+            hash.write_f32(player.damage);
+            hash.write_f32(player.speed);
+            hash.write_f32(player.aim.0.x);
+            hash.write_f32(player.aim.0.y);
+            hash.write(&[player.hit_stop.map_or(0, |hit_stop| hit_stop.frames_left as u8 + 1)]);
+        }
+
+        // Projectiles can come back in any order, so combine them in a way that doesn't care
+        let projectiles = self.projectiles.iter().fold(0u64, |sum, projectile| {
+            let mut hash = Fnv::new();
+            hash.write_vec3(projectile.translation);
+            hash.write_vec3(projectile.velocity.linear);
+            sum.wrapping_add(hash.0)
+        });
+        hash.write(&projectiles.to_le_bytes());
+        hash.write(&(self.projectiles.len() as u32).to_le_bytes());
+
+        // Its seed catches mismatched MatchSeeds, and where it has got to says how many
+        // numbers have been drawn from it
+        if let Some(rng) = &self.rng {
+            hash.write(&rng.0.get_seed());
+            hash.write(&rng.0.get_word_pos().to_le_bytes());
+        }
+        if let Some(stocks) = &self.team_stocks {
+            hash.write(&stocks.0.map(|lives| lives as u8));
+        }
+        hash.0
+    }
+
+    pub fn restore(&self, world: &mut World) {
+        let entities = self.respawn_players(world);
+        let moved = |entity: Entity| entities.get(&entity).copied().unwrap_or(entity);
+
+        if let Some(rng) = &self.rng {
+            world.insert_resource(rng.clone());
+        }
+        if let Some(stats) = &self.stats {
+            let mut stats = stats.clone();
+            for (from, to) in entities.iter() {
+                stats.move_player(*from, *to);
+            }
+            world.insert_resource(stats);
+        }
+        if let Some(stocks) = self.team_stocks {
+            world.insert_resource(stocks);
+        }
+
+        for player in self.players.iter() {
+            let mut entity = match world.get_entity_mut(moved(player.entity)) {
+                Some(entity) => entity,
+                None => continue,
+            };
+
+            if let Some(mut transform) = entity.get_mut::<Transform>() {
+                transform.translation = player.translation;
+            }
+            if let Some(mut velocity) = entity.get_mut::<Velocity>() {
+                *velocity = player.velocity;
+            }
+            if let Some(mut jumps) = entity.get_mut::<AvailableJumps>() {
+                jumps.0 = player.jumps;
+            }
+            if let Some(mut lives) = entity.get_mut::<Lives>() {
+                lives.0 = player.lives;
+            }
+            if let Some(mut damage) = entity.get_mut::<DamageTaken>() {
+                damage.0 = player.damage;
+            }
+            if let Some(mut speed) = entity.get_mut::<Speed>() {
+                speed.0 = player.speed;
+            }
+            if let Some(mut aim) = entity.get_mut::<AimDirection>() {
+                *aim = player.aim;
+            }
+            if let Some(mut last_attacker) = entity.get_mut::<LastAttacker>() {
+                *last_attacker = LastAttacker(player.last_attacker.0.map(moved));
+            }
+            match player.hit_stop {
+                Some(hit_stop) => {
+                    entity.insert(hit_stop);
+                }
+                None => {
+                    entity.remove::<HitStop>();
+                }
+            }
+        }
+
+        let current: Vec<Entity> = world
+            .query_filtered::<Entity, With<Projectile>>()
+            .iter(world)
+            .collect();
+        for entity in current {
+            if !self.projectiles.iter().any(|projectile| projectile.entity == entity) {
+                world.despawn(entity);
+            }
+        }
+
+        let material = match world.get_resource::<BulletMaterial>() {
+            Some(material) => BulletMaterial(material.0.clone()),
+            None => return,
+        };
+        let mut queue = CommandQueue::default();
+        let mut commands = Commands::new(&mut queue, world);
+        for projectile in self.projectiles.iter() {
+            match world.get_entity(projectile.entity) {
+                Some(_) => {
+                    commands
+                        .entity(projectile.entity)
+                        .insert(Transform {
+                            translation: projectile.translation,
+                            scale: Vec3::new(2., 2., 1.),
+                            ..Default::default()
+                        })
+                        .insert(projectile.velocity)
+                        .insert(ProjectileOwner(moved(projectile.owner)));
+                }
+                // Already hit something since, bring it back
+                None => {
+                    spawn_projectile(
+                        &mut commands,
+                        &material,
+                        moved(projectile.owner),
+                        projectile.translation,
+                        projectile.velocity.linear,
+                    );
+                }
+            }
+        }
+        queue.apply(world);
+    }
+
+    // Players are matched up by slot, not entity. Anyone knocked out for good in a tick that's
+    // being undone is spawned again, and the entities they had then map to the ones they have now.
+    fn respawn_players(&self, world: &mut World) -> HashMap<Entity, Entity> {
+        let mut current: HashMap<usize, Entity> = world
+            .query_filtered::<(Entity, &PlayerSlot), With<Player>>()
+            .iter(world)
+            .map(|(entity, slot)| (slot.0, entity))
+            .collect();
+
+        let mut queue = CommandQueue::default();
+        if let Some(library) = world.get_resource::<CharacterLibrary>() {
+            let mut commands = Commands::new(&mut queue, world);
+            for player in self.players.iter() {
+                if current.contains_key(&player.slot) {
+                    continue;
+                }
+                let entity = match spawn_player(&mut commands, library, player.gamepad, player.slot, player.character) {
+                    Some(entity) => entity,
+                    None => continue,
+                };
+                if let Some(team) = player.team {
+                    commands.entity(entity).insert(team);
+                }
+                current.insert(player.slot, entity);
+            }
+        }
+        queue.apply(world);
+
+        self.players
+            .iter()
+            .filter_map(|player| Some((player.entity, *current.get(&player.slot)?)))
+            .filter(|(from, to)| from != to)
+            .collect()
+    }
+}
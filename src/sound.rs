@@ -0,0 +1,217 @@
+use super::events::*;
+use super::map::*;
+use super::settings::*;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink, Source};
+use std::fs;
+use std::io::Cursor;
+use std::sync::Arc;
+
+const SOUND_DIR: &str = "assets";
+
+// Plays sound straight through rodio rather than bevy_audio, so a missing audio device
+// (CI, headless boxes) just means silence instead of a panic
+pub struct SoundPlugin;
+impl Plugin for SoundPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        let output = match OutputStream::try_default() {
+            Ok((stream, handle)) => Some(SoundDevice {
+                _stream: stream,
+                handle,
+            }),
+            Err(err) => {
+                warn!("No audio device, running without sound. {}", err);
+                None
+            }
+        };
+
+        app.insert_non_send_resource(SoundOutput { device: output, music: None })
+            .insert_resource(SoundBank::load())
+            .add_system(play_gameplay_sounds.system())
+            .add_system(play_stage_music.system())
+            .add_system(update_music_volume.system());
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum SoundEffect {
+    Fire,
+    Jump,
+    Hit,
+    Ko,
+    MatchStart,
+}
+
+const SOUND_EFFECTS: [SoundEffect; 5] = [
+    SoundEffect::Fire,
+    SoundEffect::Jump,
+    SoundEffect::Hit,
+    SoundEffect::Ko,
+    SoundEffect::MatchStart,
+];
+
+impl SoundEffect {
+    fn path(&self) -> &'static str {
+        match self {
+            SoundEffect::Fire => "sounds/fire.wav",
+            SoundEffect::Jump => "sounds/jump.wav",
+            SoundEffect::Hit => "sounds/hit.wav",
+            SoundEffect::Ko => "sounds/ko.wav",
+            SoundEffect::MatchStart => "sounds/match_start.wav",
+        }
+    }
+}
+
+struct SoundDevice {
+    // Dropping the stream stops all audio, so it has to live as long as the handle
+    _stream: OutputStream,
+    handle: OutputStreamHandle,
+}
+
+pub struct SoundOutput {
+    device: Option<SoundDevice>,
+    music: Option<Sink>,
+}
+
+// Raw file contents, decoded fresh every time a sound plays
+pub struct SoundBank {
+    clips: HashMap<SoundEffect, Arc<[u8]>>,
+}
+
+impl SoundBank {
+    fn load() -> SoundBank {
+        let mut clips = HashMap::default();
+        for effect in SOUND_EFFECTS.iter() {
+            if let Some(bytes) = read_sound(effect.path()) {
+                clips.insert(*effect, bytes);
+            }
+        }
+        SoundBank { clips }
+    }
+}
+
+fn read_sound(path: &str) -> Option<Arc<[u8]>> {
+    match fs::read(format!("{}/{}", SOUND_DIR, path)) {
+        Ok(bytes) => Some(bytes.into()),
+        Err(err) => {
+            warn!("Failed to load sound {}. {}", path, err);
+            None
+        }
+    }
+}
+
+fn decode(bytes: &Arc<[u8]>) -> Option<Decoder<Cursor<Arc<[u8]>>>> {
+    match Decoder::new(Cursor::new(bytes.clone())) {
+        Ok(decoder) => Some(decoder),
+        Err(err) => {
+            warn!("Failed to decode sound. {}", err);
+            None
+        }
+    }
+}
+
+impl SoundOutput {
+    // Volume is the final gain after the buses, pitch is a playback speed multiplier
+    pub fn play(&self, bank: &SoundBank, effect: SoundEffect, volume: f32, pitch: f32) {
+        let device = match &self.device {
+            Some(device) => device,
+            None => return,
+        };
+
+        if let Some(source) = bank.clips.get(&effect).and_then(decode) {
+            let source = source.speed(pitch).amplify(volume).convert_samples::<f32>();
+            if let Err(err) = device.handle.play_raw(source) {
+                warn!("Failed to play {:?}. {}", effect, err);
+            }
+        }
+    }
+}
+
+// A few percent either way so repeated sounds don't feel robotic
+fn pitch_variation(amount: f32) -> f32 {
+    1. + (rand::random::<f32>() * 2. - 1.) * amount
+}
+
+// Knockback at which hits bottom out at their lowest pitch
+const HEAVY_HIT_KNOCKBACK: f32 = 400.;
+
+// Everything in a match that makes a noise
+#[derive(SystemParam)]
+pub struct GameplayEvents<'a> {
+    fire: EventReader<'a, PlayerFireEvent>,
+    jump: EventReader<'a, PlayerJumpEvent>,
+    hit: EventReader<'a, PlayerHitEvent>,
+    ko: EventReader<'a, PlayerKoEvent>,
+    match_start: EventReader<'a, MatchStartEvent>,
+}
+
+fn play_gameplay_sounds(
+    output: NonSend<SoundOutput>,
+    bank: Res<SoundBank>,
+    settings: Res<Settings>,
+    mut events: GameplayEvents,
+) {
+    let gain = settings.audio.sfx_gain();
+
+    for _ in events.fire.iter().filter(|event| !event.rerun) {
+        output.play(&bank, SoundEffect::Fire, gain * 0.6, pitch_variation(0.08));
+    }
+    for event in events.jump.iter().filter(|event| !event.rerun) {
+        // Double jumps go up a step
+        let pitch = if event.jumps_left == 0 { 1.25 } else { 1. };
+        output.play(&bank, SoundEffect::Jump, gain * 0.7, pitch * pitch_variation(0.03));
+    }
+    for event in events.hit.iter().filter(|event| !event.rerun) {
+        // Heavier hits sound deeper and louder
+        let weight = (event.knockback / HEAVY_HIT_KNOCKBACK).min(1.);
+        let pitch = (1.2 - weight * 0.5) * pitch_variation(0.05);
+        output.play(&bank, SoundEffect::Hit, gain * (0.6 + weight * 0.4), pitch);
+    }
+    for _ in events.ko.iter().filter(|event| !event.rerun) {
+        output.play(&bank, SoundEffect::Ko, gain, pitch_variation(0.05));
+    }
+    for _ in events.match_start.iter() {
+        output.play(&bank, SoundEffect::MatchStart, gain, 1.);
+    }
+}
+
+fn play_stage_music(
+    mut output: NonSendMut<SoundOutput>,
+    stage: Res<CurrentStage>,
+    settings: Res<Settings>,
+    mut match_start_events: EventReader<MatchStartEvent>,
+) {
+    if match_start_events.iter().count() == 0 {
+        return;
+    }
+
+    let sink = match &output.device {
+        Some(device) => match Sink::try_new(&device.handle) {
+            Ok(sink) => sink,
+            Err(err) => {
+                warn!("Failed to start music. {}", err);
+                return;
+            }
+        },
+        None => return,
+    };
+
+    if let Some(music) = read_sound(stage.0.music()).as_ref().and_then(decode) {
+        sink.set_volume(settings.audio.music_gain());
+        sink.append(music.repeat_infinite());
+        // Replacing the old sink stops whatever was playing before
+        output.music = Some(sink);
+    }
+}
+
+fn update_music_volume(output: NonSend<SoundOutput>, settings: Res<Settings>) {
+    if !settings.is_changed() {
+        return;
+    }
+
+    if let Some(music) = &output.music {
+        music.set_volume(settings.audio.music_gain());
+    }
+}
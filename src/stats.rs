@@ -0,0 +1,303 @@
+use super::app_state::*;
+use super::character::*;
+use super::events::*;
+use super::player::*;
+use super::simulation::*;
+use super::teams::*;
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const STATS_DIR: &str = "stats";
+
+// Who landed what on whom over a match. Counted on simulation ticks, so a paused match isn't
+// time alive and a rolled back one isn't counted twice.
+pub struct StatsPlugin;
+impl Plugin for StatsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(MatchStats::default())
+            .insert_resource(StatsExport(stats_dir()))
+            .add_system_set(SystemSet::on_enter(AppState::Match).with_system(reset_match_stats.system()))
+            .add_system_set(SystemSet::on_exit(AppState::Match).with_system(export_match_stats.system()))
+            .add_system_to_stage(
+                SIMULATION,
+                track_match_stats
+                    .system()
+                    .label("track_match_stats")
+                    .after("respawn_players"),
+            );
+    }
+}
+
+// Where each match's stats are written, None to not bother
+pub struct StatsExport(pub Option<PathBuf>);
+
+#[derive(Default, Clone, Serialize, Debug)]
+pub struct PlayerStats {
+    // 0 is P1
+    pub slot: usize,
+    pub character: String,
+    // Only in a team match
+    pub team: Option<usize>,
+    pub shots_fired: u32,
+    pub hits: u32,
+    pub damage_dealt: f32,
+    pub damage_taken: f32,
+    pub kos: u32,
+    // Went off without anyone having hit them that stock
+    pub self_destructs: u32,
+    pub stocks_lost: u32,
+    pub ticks_alive: u64,
+    // Tick their last stock went, None if they lasted to the end
+    pub eliminated_at: Option<u64>,
+}
+
+impl PlayerStats {
+    // 0 to 1, hits for every shot fired
+    pub fn accuracy(&self) -> f32 {
+        if self.shots_fired == 0 {
+            0.
+        } else {
+            self.hits as f32 / self.shots_fired as f32
+        }
+    }
+
+    pub fn seconds_alive(&self) -> f64 {
+        self.ticks_alive as f64 / TICK_RATE
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct MatchStats {
+    pub ticks: u64,
+    pub players: [Option<PlayerStats>; MAX_PLAYERS],
+    // Attackers can be gone by the time their KO comes through, so they're remembered by slot
+    slots: HashMap<Entity, usize>,
+}
+
+impl MatchStats {
+    pub fn get(&self, slot: usize) -> Option<&PlayerStats> {
+        self.players.get(slot).and_then(|player| player.as_ref())
+    }
+
+    // For a player rewound back into the match as a new entity
+    pub fn move_player(&mut self, from: Entity, to: Entity) {
+        if let Some(slot) = self.slots.remove(&from) {
+            self.slots.insert(to, slot);
+        }
+    }
+
+    fn by_entity(&mut self, entity: Entity) -> Option<&mut PlayerStats> {
+        let slot = *self.slots.get(&entity)?;
+        self.players[slot].as_mut()
+    }
+
+    // Everyone who played, last one standing first and the first one out last
+    pub fn standings(&self) -> Vec<&PlayerStats> {
+        let mut players: Vec<&PlayerStats> = self.players.iter().flatten().collect();
+        players.sort_by_key(|player| {
+            (player.eliminated_at.is_some(), Reverse(player.eliminated_at), player.slot)
+        });
+        players
+    }
+
+    // Only once there's a single player left in it
+    pub fn winner(&self) -> Option<usize> {
+        let mut standing = self.players.iter().flatten().filter(|player| player.eliminated_at.is_none());
+        match (standing.next(), standing.next()) {
+            (Some(player), None) => Some(player.slot),
+            _ => None,
+        }
+    }
+
+    // Once everyone still standing is on the same team
+    pub fn winning_team(&self) -> Option<usize> {
+        let mut teams = self
+            .players
+            .iter()
+            .flatten()
+            .filter(|player| player.eliminated_at.is_none())
+            .map(|player| player.team);
+        let first = teams.next()??;
+        teams.all(|team| team == Some(first)).then_some(first)
+    }
+
+    // Teams count as one side, everyone else is on their own
+    fn sides(&self, standing_only: bool) -> usize {
+        self.players
+            .iter()
+            .flatten()
+            .filter(|player| !standing_only || player.eliminated_at.is_none())
+            .map(|player| player.team.map_or((false, player.slot), |team| (true, team)))
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    pub fn is_over(&self) -> bool {
+        let played = self.sides(false);
+        let left = self.sides(true);
+        // On your own it goes on until you're out
+        if played > 1 {
+            left <= 1
+        } else {
+            played == 1 && left == 0
+        }
+    }
+
+    // Tick the last side that lost went out on
+    pub fn decided_at(&self) -> Option<u64> {
+        if !self.is_over() {
+            return None;
+        }
+        self.players.iter().flatten().filter_map(|player| player.eliminated_at).max()
+    }
+
+    pub fn save(&self, path: &Path, seed: u64) -> io::Result<()> {
+        #[derive(Serialize)]
+        struct PlayerReport<'a> {
+            #[serde(flatten)]
+            stats: &'a PlayerStats,
+            accuracy: f32,
+            seconds_alive: f64,
+        }
+
+        #[derive(Serialize)]
+        struct MatchReport<'a> {
+            seed: u64,
+            seconds: f64,
+            winner: Option<usize>,
+            winning_team: Option<usize>,
+            players: Vec<PlayerReport<'a>>,
+        }
+
+        let report = MatchReport {
+            seed,
+            seconds: self.ticks as f64 / TICK_RATE,
+            winner: self.winner(),
+            winning_team: self.winning_team(),
+            players: self
+                .standings()
+                .into_iter()
+                .map(|stats| PlayerReport {
+                    stats,
+                    accuracy: stats.accuracy(),
+                    seconds_alive: stats.seconds_alive(),
+                })
+                .collect(),
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string_pretty(&report).map_err(io::Error::other)?;
+        fs::write(path, json)
+    }
+}
+
+pub fn stats_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("smashbubs").join(STATS_DIR))
+}
+
+fn reset_match_stats(mut stats: ResMut<MatchStats>) {
+    *stats = MatchStats::default();
+}
+
+// Who's in the match, as far as the stats care
+type StatsPlayers<'a> = Query<
+    'a,
+    (
+        Entity,
+        &'static PlayerSlot,
+        &'static PlayerCharacter,
+        &'static Lives,
+        Option<&'static Team>,
+    ),
+    With<Player>,
+>;
+
+fn track_match_stats(
+    clock: Res<SimulationClock>,
+    library: Res<CharacterLibrary>,
+    mut stats: ResMut<MatchStats>,
+    mut fire_events: EventReader<PlayerFireEvent>,
+    mut hit_events: EventReader<PlayerHitEvent>,
+    mut ko_events: EventReader<PlayerKoEvent>,
+    players: StatsPlayers,
+) {
+    let stats = &mut *stats;
+    stats.ticks += 1;
+    for (entity, slot, character, _, team) in players.iter() {
+        if let Entry::Vacant(entry) = stats.slots.entry(entity) {
+            entry.insert(slot.0);
+            stats.players[slot.0] = Some(PlayerStats {
+                slot: slot.0,
+                character: library
+                    .get(character.0)
+                    .map_or("?".to_string(), |character| character.definition.name.clone()),
+                team: team.map(|team| team.0),
+                ..Default::default()
+            });
+        }
+        if let Some(player) = stats.players[slot.0].as_mut() {
+            player.ticks_alive += 1;
+        }
+    }
+
+    for event in fire_events.iter() {
+        if let Some(player) = stats.by_entity(event.player) {
+            player.shots_fired += 1;
+        }
+    }
+
+    for event in hit_events.iter() {
+        if let Some(victim) = stats.by_entity(event.victim) {
+            victim.damage_taken += event.damage;
+        }
+        if let Some(attacker) = event.attacker.and_then(|attacker| stats.by_entity(attacker)) {
+            attacker.hits += 1;
+            attacker.damage_dealt += event.damage;
+        }
+    }
+
+    for event in ko_events.iter() {
+        let out = players.get(event.player).is_ok_and(|(_, _, _, lives, _)| lives.0 <= 0);
+        if let Some(victim) = stats.by_entity(event.player) {
+            victim.stocks_lost += 1;
+            if event.last_attacker.is_none() {
+                victim.self_destructs += 1;
+            }
+            if out {
+                victim.eliminated_at = Some(clock.tick);
+            }
+        }
+        if let Some(attacker) = event.last_attacker.and_then(|attacker| stats.by_entity(attacker)) {
+            attacker.kos += 1;
+        }
+    }
+}
+
+fn export_match_stats(seed: Res<MatchSeed>, export: Res<StatsExport>, stats: Res<MatchStats>) {
+    let dir = match &export.0 {
+        Some(dir) => dir,
+        None => return,
+    };
+    // Nothing happened, most likely quit straight away from the pause menu
+    if stats.players.iter().flatten().all(|player| player.shots_fired == 0 && player.stocks_lost == 0) {
+        return;
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let path = dir.join(format!("match-{}.json", timestamp));
+    match stats.save(&path, seed.0) {
+        Ok(()) => info!("Saved match stats to {}", path.display()),
+        Err(err) => error!("Failed to save match stats to {}. {}", path.display(), err),
+    }
+}
@@ -0,0 +1,108 @@
+use super::player::*;
+use super::settings::*;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Extra room above the measured rest noise so a calibrated stick doesn't flicker at the edge
+const REST_NOISE_MARGIN: f32 = 0.03;
+
+pub struct StickPlugin;
+impl Plugin for StickPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_system(read_sticks.system().label("read_sticks"));
+    }
+}
+
+// Both sticks after deadzones and the response curve, already scaled to 0..1 in length.
+// Gameplay reads this instead of the raw axes.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct StickInput {
+    pub movement: Vec2,
+    pub aim: Vec2,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum ResponseCurve {
+    Linear,
+    // Finer control near the centre, full speed only near the edge
+    Quadratic,
+    Cubic,
+}
+
+pub const RESPONSE_CURVES: [ResponseCurve; 3] =
+    [ResponseCurve::Linear, ResponseCurve::Quadratic, ResponseCurve::Cubic];
+
+impl ResponseCurve {
+    fn apply(&self, t: f32) -> f32 {
+        match self {
+            ResponseCurve::Linear => t,
+            ResponseCurve::Quadratic => t * t,
+            ResponseCurve::Cubic => t * t * t,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(default)]
+pub struct StickSettings {
+    // Radial, anything shorter than this reads as centred
+    pub inner_deadzone: f32,
+    // Anything longer than this reads as fully pushed
+    pub outer_deadzone: f32,
+    pub curve: ResponseCurve,
+    // How far the left and right sticks wander while untouched, measured by calibration
+    pub rest_noise: (f32, f32),
+    // Aim only in the 8 compass directions
+    pub eight_way_aim: bool,
+}
+
+impl Default for StickSettings {
+    fn default() -> StickSettings {
+        StickSettings {
+            inner_deadzone: 0.15,
+            outer_deadzone: 0.95,
+            curve: ResponseCurve::Linear,
+            rest_noise: (0., 0.),
+            eight_way_aim: false,
+        }
+    }
+}
+
+impl StickSettings {
+    pub fn apply(&self, raw: Vec2, rest_noise: f32) -> Vec2 {
+        let inner = self.inner_deadzone.max(rest_noise + REST_NOISE_MARGIN);
+        let outer = self.outer_deadzone.max(inner + 0.01);
+
+        let length = raw.length();
+        if length <= inner {
+            return Vec2::ZERO;
+        }
+
+        let t = ((length - inner) / (outer - inner)).min(1.);
+        raw / length * self.curve.apply(t)
+    }
+}
+
+pub fn raw_stick(axes: &Axis<GamepadAxis>, gamepad: Gamepad, x: GamepadAxisType, y: GamepadAxisType) -> Vec2 {
+    Vec2::new(
+        axes.get(GamepadAxis(gamepad, x)).unwrap_or(0.),
+        axes.get(GamepadAxis(gamepad, y)).unwrap_or(0.),
+    )
+}
+
+fn read_sticks(
+    settings: Res<Settings>,
+    axes: Res<Axis<GamepadAxis>>,
+    mut query: Query<(&Gamepad, &PlayerSlot, &mut StickInput), With<Player>>,
+) {
+    for (gamepad, slot, mut sticks) in query.iter_mut() {
+        let stick_settings = settings.input.sticks(slot.0);
+        let (left_noise, right_noise) = stick_settings.rest_noise;
+
+        let left = raw_stick(&axes, *gamepad, GamepadAxisType::LeftStickX, GamepadAxisType::LeftStickY);
+        let right = raw_stick(&axes, *gamepad, GamepadAxisType::RightStickX, GamepadAxisType::RightStickY);
+
+        sticks.movement = stick_settings.apply(left, left_noise);
+        sticks.aim = stick_settings.apply(right, right_noise);
+    }
+}
@@ -0,0 +1,113 @@
+use super::actions::*;
+use super::app_state::*;
+use super::lobby::*;
+use super::player::*;
+use bevy::prelude::*;
+
+pub const TEAM_COUNT: usize = 2;
+
+// 2v2 and the like. Picked in the lobby, where everyone also chooses a side. Teammates are
+// tinted the same colour, can share their stocks and only hurt each other with friendly fire on.
+pub struct TeamsPlugin;
+impl Plugin for TeamsPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(Teams::default())
+            .insert_resource(TeamStocks::default())
+            .add_system_set(SystemSet::on_enter(AppState::Match).with_system(fill_team_stocks.system()));
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Team(pub usize);
+
+impl Team {
+    pub fn name(&self) -> &'static str {
+        match self.0 {
+            0 => "Red",
+            _ => "Blue",
+        }
+    }
+
+    // Multiplied into the sprite, so light enough to still see the character through it
+    pub fn color(&self) -> Color {
+        match self.0 {
+            0 => Color::rgb(1., 0.55, 0.55),
+            _ => Color::rgb(0.55, 0.7, 1.),
+        }
+    }
+
+    pub fn next(&self) -> Team {
+        Team((self.0 + 1) % TEAM_COUNT)
+    }
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub struct Teams {
+    pub enabled: bool,
+    // One pool of lives per team rather than each player having their own
+    pub shared_stocks: bool,
+    pub friendly_fire: bool,
+}
+
+impl Teams {
+    // Off, then teams with their own stocks, then teams sharing them
+    pub fn cycle(&mut self) {
+        match (self.enabled, self.shared_stocks) {
+            (false, _) => {
+                self.enabled = true;
+                self.shared_stocks = false;
+            }
+            (true, false) => self.shared_stocks = true,
+            (true, true) => self.enabled = false,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match (self.enabled, self.shared_stocks) {
+            (false, _) => "off",
+            (true, false) => "own stocks",
+            (true, true) => "shared stocks",
+        }
+    }
+
+    pub fn shares_stocks(&self) -> bool {
+        self.enabled && self.shared_stocks
+    }
+
+    // Without teams nobody's on anyone's side
+    pub fn are_teammates(&self, a: Option<&Team>, b: Option<&Team>) -> bool {
+        self.enabled && a.is_some() && a == b
+    }
+
+    // Whether a hit from `attacker` on `victim` does anything
+    pub fn can_hurt(&self, attacker: Option<&Team>, victim: Option<&Team>) -> bool {
+        self.friendly_fire || !self.are_teammates(attacker, victim)
+    }
+}
+
+// Lives each team has in reserve, not counting the ones its players are on stage with. Only used
+// with shared stocks.
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub struct TeamStocks(pub [i8; TEAM_COUNT]);
+
+impl TeamStocks {
+    // Everyone brings their stocks to the pool, less the one they start on stage with
+    pub fn for_players(teams: impl Iterator<Item = Team>) -> TeamStocks {
+        let mut stocks = TeamStocks::default();
+        for team in teams {
+            stocks.0[team.0] += STARTING_LIVES - 1;
+        }
+        stocks
+    }
+}
+
+// A replay fills it in from its roster instead
+fn fill_team_stocks(source: Res<ActionSource>, teams: Res<Teams>, lobby: Res<Lobby>, mut stocks: ResMut<TeamStocks>) {
+    if *source == ActionSource::Replay {
+        return;
+    }
+    *stocks = TeamStocks::default();
+    if teams.shares_stocks() {
+        *stocks = TeamStocks::for_players(lobby.slots.iter().flatten().map(|slot| slot.team));
+    }
+}
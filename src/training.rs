@@ -0,0 +1,457 @@
+use super::actions::*;
+use super::app_state::*;
+use super::bot::*;
+use super::character::*;
+use super::events::*;
+use super::lobby::*;
+use super::navigation::*;
+use super::options_menu::*;
+use super::pause::*;
+use super::player::*;
+use super::settings::*;
+use super::simulation::*;
+use super::window::*;
+use bevy::prelude::*;
+use heron::prelude::*;
+
+// How much one press of up or down changes the selected player's damage
+const DAMAGE_STEP: f32 = 5.;
+// How often the jumping dummy jumps
+const DUMMY_JUMP_TICKS: u32 = 60;
+// The walking dummy turns round this far from the edge
+const DUMMY_EDGE_MARGIN: f32 = 24.;
+// Longest a knockback prediction is followed for
+const TRAJECTORY_TICKS: u32 = 180;
+
+// Practice against a dummy. Picked in the lobby with West. In the match the d-pad picks a
+// player and sets their damage, the left stick button changes what the dummy does and the
+// right one puts everyone back where they started.
+pub struct TrainingPlugin;
+impl Plugin for TrainingPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.insert_resource(Training::default())
+            .add_startup_system(setup_training_text.system())
+            .add_system_set(SystemSet::on_enter(AppState::Match).with_system(spawn_training_dummy.system()))
+            .add_system_set(
+                SystemSet::on_update(AppState::Match)
+                    .with_system(training_input.system())
+                    .with_system(read_knockback.system()),
+            )
+            .add_system(update_training_text.system())
+            .add_system_to_stage(
+                SIMULATION,
+                apply_training_edits
+                    .system()
+                    .label("training_edits")
+                    .after("record_actions")
+                    .before("player_input"),
+            )
+            .add_system_to_stage(
+                SIMULATION,
+                dummy_actions
+                    .system()
+                    .label("dummy_actions")
+                    .after("external_actions")
+                    .after("reset_jumps")
+                    .before("record_actions")
+                    .before("resolve_aim"),
+            );
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DummyBehaviour {
+    Stand,
+    Walk,
+    Jump,
+}
+
+impl DummyBehaviour {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DummyBehaviour::Stand => "stand",
+            DummyBehaviour::Walk => "walk",
+            DummyBehaviour::Jump => "jump",
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            DummyBehaviour::Stand => 0,
+            DummyBehaviour::Walk => 1,
+            DummyBehaviour::Jump => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<DummyBehaviour> {
+        match id {
+            0 => Some(DummyBehaviour::Stand),
+            1 => Some(DummyBehaviour::Walk),
+            2 => Some(DummyBehaviour::Jump),
+            _ => None,
+        }
+    }
+
+    fn next(&self) -> DummyBehaviour {
+        match self {
+            DummyBehaviour::Stand => DummyBehaviour::Walk,
+            DummyBehaviour::Walk => DummyBehaviour::Jump,
+            DummyBehaviour::Jump => DummyBehaviour::Stand,
+        }
+    }
+}
+
+// What the training controls change in the match. Picked from the pads in Update but only
+// made on a tick, so replays can record them and make them again.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TrainingEdit {
+    RaiseDamage(u8),
+    LowerDamage(u8),
+    // Everyone back where they started
+    Reset,
+    NextDummy,
+}
+
+impl TrainingEdit {
+    // Kind, then the slot it's for
+    pub fn to_bytes(&self) -> [u8; 2] {
+        match self {
+            TrainingEdit::RaiseDamage(slot) => [0, *slot],
+            TrainingEdit::LowerDamage(slot) => [1, *slot],
+            TrainingEdit::Reset => [2, 0],
+            TrainingEdit::NextDummy => [3, 0],
+        }
+    }
+
+    pub fn from_bytes(bytes: [u8; 2]) -> Option<TrainingEdit> {
+        match bytes {
+            [0, slot] => Some(TrainingEdit::RaiseDamage(slot)),
+            [1, slot] => Some(TrainingEdit::LowerDamage(slot)),
+            [2, _] => Some(TrainingEdit::Reset),
+            [3, _] => Some(TrainingEdit::NextDummy),
+            _ => None,
+        }
+    }
+}
+
+pub struct Training {
+    pub enabled: bool,
+    // KOs still happen, they just don't cost a life
+    pub infinite_stocks: bool,
+    pub dummy: DummyBehaviour,
+    // Edits for the next tick, from the pads or a replay
+    pub pending: Vec<TrainingEdit>,
+    // Slot whose damage the d-pad changes
+    selected: usize,
+    last_knockback: Option<String>,
+}
+
+impl Default for Training {
+    fn default() -> Training {
+        Training {
+            enabled: false,
+            infinite_stocks: true,
+            dummy: DummyBehaviour::Stand,
+            pending: Vec::new(),
+            selected: 0,
+            last_knockback: None,
+        }
+    }
+}
+
+impl Training {
+    pub fn loses_lives(&self) -> bool {
+        !(self.enabled && self.infinite_stocks)
+    }
+
+    // Off, then on with infinite stocks, then on with normal stocks
+    pub fn cycle(&mut self) {
+        match (self.enabled, self.infinite_stocks) {
+            (false, _) => {
+                self.enabled = true;
+                self.infinite_stocks = true;
+            }
+            (true, true) => self.infinite_stocks = false,
+            (true, false) => self.enabled = false,
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match (self.enabled, self.infinite_stocks) {
+            (false, _) => "off",
+            (true, true) => "infinite stocks",
+            (true, false) => "normal stocks",
+        }
+    }
+}
+
+// Driven by the training settings rather than a pad or a bot
+pub struct TrainingDummy {
+    direction: f32,
+    ticks: u32,
+}
+
+struct TrainingText;
+
+fn setup_training_text(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(20.),
+                    left: Val::Px(20.),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: asset_server.load(MENU_FONT),
+                    font_size: 18.,
+                    color: Color::WHITE,
+                },
+                Default::default(),
+            ),
+            visible: Visible {
+                is_visible: false,
+                is_transparent: true,
+            },
+            ..Default::default()
+        })
+        .insert(TrainingText);
+}
+
+// Into the first free slot, so there's always something to hit. A replay's roster already has
+// it.
+fn spawn_training_dummy(
+    mut commands: Commands,
+    source: Res<ActionSource>,
+    library: Res<CharacterLibrary>,
+    lobby: Res<Lobby>,
+    mut training: ResMut<Training>,
+) {
+    training.last_knockback = None;
+    training.pending.clear();
+    if !training.enabled || *source == ActionSource::Replay {
+        return;
+    }
+
+    let slot = match lobby.slots.iter().position(|slot| slot.is_none()) {
+        Some(slot) => slot,
+        None => {
+            info!("No free slot for a training dummy");
+            return;
+        }
+    };
+    if let Some(dummy) = spawn_player(&mut commands, &library, bot_gamepad(slot), slot, library.default_index()) {
+        commands.entity(dummy).insert(TrainingDummy {
+            direction: 1.,
+            ticks: 0,
+        });
+        training.selected = slot;
+    }
+}
+
+fn dummy_actions(
+    source: Res<ActionSource>,
+    training: Res<Training>,
+    graph: Res<NavGraph>,
+    mut query: Query<(&mut TrainingDummy, &Transform, &AvailableJumps, &mut PlayerActions), With<Player>>,
+) {
+    if *source == ActionSource::Replay {
+        return;
+    }
+
+    for (mut dummy, transform, jumps, mut actions) in query.iter_mut() {
+        dummy.ticks += 1;
+        let position = transform.translation.truncate();
+
+        let mut movement = Vec2::ZERO;
+        let mut jump = false;
+        match training.dummy {
+            DummyBehaviour::Stand => {}
+            DummyBehaviour::Walk => {
+                // Back and forth along whatever it's standing on
+                if let Some(platform) = graph.platform_under(position).map(|index| graph.platforms[index]) {
+                    if position.x > platform.right - DUMMY_EDGE_MARGIN {
+                        dummy.direction = -1.;
+                    } else if position.x < platform.left + DUMMY_EDGE_MARGIN {
+                        dummy.direction = 1.;
+                    }
+                }
+                movement.x = dummy.direction;
+            }
+            DummyBehaviour::Jump => {
+                jump = dummy.ticks % DUMMY_JUMP_TICKS == 0 && jumps.0 == MAX_JUMPS;
+            }
+        }
+
+        *actions = PlayerActions {
+            movement,
+            jump: actions.jump || jump,
+            ..Default::default()
+        };
+    }
+}
+
+fn training_input(
+    buttons: Res<Input<GamepadButton>>,
+    source: Res<ActionSource>,
+    menu: Res<OptionsMenu>,
+    pause: Res<MatchPause>,
+    settings: Res<Settings>,
+    mut training: ResMut<Training>,
+    players: Query<&PlayerSlot, With<Player>>,
+) {
+    // The d-pad is for the pause menu while it's up
+    if !training.enabled || *source == ActionSource::Replay || menu.open || pause.is_paused() {
+        return;
+    }
+
+    let mut slots: Vec<usize> = players.iter().map(|slot| slot.0).collect();
+    slots.sort_unstable();
+
+    // The stick buttons can be bound to jump or fire, and then they're for playing with
+    let bindings = &settings.input.bindings;
+    let bound: [GamepadButtonType; 2] = [bindings.jump.into(), bindings.fire.into()];
+
+    for GamepadButton(_, button) in buttons.get_just_pressed() {
+        if bound.contains(button) {
+            continue;
+        }
+        let selected = slots.iter().position(|slot| *slot == training.selected);
+        let edit = match button {
+            GamepadButtonType::DPadLeft | GamepadButtonType::DPadRight if !slots.is_empty() => {
+                let step = if *button == GamepadButtonType::DPadRight { 1 } else { slots.len() - 1 };
+                let index = selected.map_or(0, |index| (index + step) % slots.len());
+                training.selected = slots[index];
+                continue;
+            }
+            GamepadButtonType::DPadUp => TrainingEdit::RaiseDamage(training.selected as u8),
+            GamepadButtonType::DPadDown => TrainingEdit::LowerDamage(training.selected as u8),
+            GamepadButtonType::LeftThumb => TrainingEdit::NextDummy,
+            GamepadButtonType::RightThumb => TrainingEdit::Reset,
+            _ => continue,
+        };
+        training.pending.push(edit);
+    }
+}
+
+fn apply_training_edits(
+    mut training: ResMut<Training>,
+    mut players: Query<(&PlayerSlot, &mut DamageTaken, &mut Transform, &mut Velocity), With<Player>>,
+) {
+    let edits: Vec<TrainingEdit> = training.pending.drain(..).collect();
+    for edit in edits {
+        let (slot, change) = match edit {
+            TrainingEdit::RaiseDamage(slot) => (slot, DAMAGE_STEP),
+            TrainingEdit::LowerDamage(slot) => (slot, -DAMAGE_STEP),
+            TrainingEdit::NextDummy => {
+                training.dummy = training.dummy.next();
+                continue;
+            }
+            TrainingEdit::Reset => {
+                for (_, _, mut transform, mut velocity) in players.iter_mut() {
+                    transform.translation = spawn_point();
+                    *velocity = Velocity::from_linear(Vec3::ZERO);
+                }
+                continue;
+            }
+        };
+        for (player, mut damage, _, _) in players.iter_mut() {
+            if player.0 == slot as usize {
+                damage.0 = (damage.0 + change).max(0.);
+            }
+        }
+    }
+}
+
+// Follows the launch with gravity and nothing in the way, to see whether it would have killed
+fn predict_knockback(start: Vec3, velocity: Vec3, gravity: Vec3) -> (f32, Option<f32>) {
+    let step = 1. / TICK_RATE as f32;
+    let mut position = start;
+    let mut velocity = velocity;
+    let mut peak = start.y;
+    for tick in 0..TRAJECTORY_TICKS {
+        velocity += gravity * step;
+        position += velocity * step;
+        peak = peak.max(position.y);
+        if position.x.abs() > VIRTUAL_WIDTH / 2. || position.y.abs() > VIRTUAL_HEIGHT / 2. {
+            return (peak - start.y, Some(tick as f32 * step));
+        }
+    }
+    (peak - start.y, None)
+}
+
+fn read_knockback(
+    gravity: Res<Gravity>,
+    mut training: ResMut<Training>,
+    mut hit_events: EventReader<PlayerHitEvent>,
+    players: Query<(&PlayerSlot, &DamageTaken, &Transform), With<Player>>,
+) {
+    for event in hit_events.iter() {
+        if !training.enabled {
+            continue;
+        }
+        let (slot, damage, transform) = match players.get(event.victim) {
+            Ok(player) => player,
+            Err(_) => continue,
+        };
+
+        let launch = event.direction * event.knockback;
+        let angle = launch.y.atan2(launch.x).to_degrees();
+        let (rise, ko) = predict_knockback(transform.translation, launch, gravity.vector());
+        let outcome = match ko {
+            Some(seconds) => format!("KO after {:.2}s", seconds),
+            None => "survives".to_string(),
+        };
+        training.last_knockback = Some(format!(
+            "P{} at {:.0} damage: launched {:.0} px/s at {:.0} deg, rises {:.0} px, {}",
+            slot.0 + 1,
+            damage.0,
+            event.knockback,
+            angle,
+            rise,
+            outcome,
+        ));
+    }
+}
+
+fn update_training_text(
+    state: Res<State<AppState>>,
+    training: Res<Training>,
+    players: Query<(&PlayerSlot, &DamageTaken), With<Player>>,
+    mut query: Query<(&mut Text, &mut Visible), With<TrainingText>>,
+) {
+    let showing = training.enabled && *state.current() == AppState::Match;
+    for (mut text, mut visible) in query.iter_mut() {
+        visible.is_visible = showing;
+        if !showing {
+            continue;
+        }
+
+        let mut damages: Vec<(usize, f32)> = players.iter().map(|(slot, damage)| (slot.0, damage.0)).collect();
+        damages.sort_by_key(|(slot, _)| *slot);
+        let damages: Vec<String> = damages
+            .iter()
+            .map(|(slot, damage)| {
+                let label = format!("P{} {:.0}", slot + 1, damage);
+                if *slot == training.selected {
+                    format!("[{}]", label)
+                } else {
+                    label
+                }
+            })
+            .collect();
+
+        text.sections[0].value = format!(
+            "TRAINING  dummy: {}  {}\n{}\n{}\nLeft/Right: pick  Up/Down: damage  L3: dummy  R3: reset",
+            training.dummy.name(),
+            training.describe(),
+            damages.join("  "),
+            training.last_knockback.as_deref().unwrap_or("No hits yet"),
+        );
+    }
+}
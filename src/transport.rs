@@ -0,0 +1,190 @@
+use bevy::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+
+// Comfortably bigger than any packet netplay sends
+const MAX_PACKET_SIZE: usize = 1024;
+
+// Unreliable, unordered datagrams. Netplay copes with loss and reordering itself, so this is
+// all it needs from the network.
+pub trait Transport: Send + Sync {
+    fn send(&mut self, to: SocketAddr, bytes: &[u8]);
+    fn receive(&mut self) -> Option<(SocketAddr, Vec<u8>)>;
+}
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn bind(addr: SocketAddr) -> io::Result<UdpTransport> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpTransport { socket })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, to: SocketAddr, bytes: &[u8]) {
+        // A full send buffer is just another lost packet
+        if let Err(err) = self.socket.send_to(bytes, to) {
+            if err.kind() != io::ErrorKind::WouldBlock {
+                warn!("Failed to send to {}. {}", to, err);
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Option<(SocketAddr, Vec<u8>)> {
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((length, from)) => return Some((from, buffer[..length].to_vec())),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return None,
+                // Windows reports an unreachable peer from an earlier send here, skip it
+                Err(err) if err.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(err) => {
+                    warn!("Failed to receive. {}", err);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+// How bad the pretend network is
+#[derive(Clone, Copy, Debug)]
+pub struct LinkConditions {
+    // In steps of the LoopbackNetwork
+    pub latency: u32,
+    // Extra random delay on top of latency, which also reorders packets
+    pub jitter: u32,
+    // 0 to 1
+    pub loss: f32,
+}
+
+impl Default for LinkConditions {
+    fn default() -> LinkConditions {
+        LinkConditions {
+            latency: 0,
+            jitter: 0,
+            loss: 0.,
+        }
+    }
+}
+
+struct InFlight {
+    deliver_at: u64,
+    from: SocketAddr,
+    to: SocketAddr,
+    bytes: Vec<u8>,
+}
+
+struct LoopbackState {
+    now: u64,
+    conditions: LinkConditions,
+    rng: ChaCha8Rng,
+    in_flight: Vec<InFlight>,
+    inboxes: HashMap<SocketAddr, Vec<(SocketAddr, Vec<u8>)>>,
+}
+
+// An in-memory network for tests. Time only moves when step is called, and the same seed
+// always drops and delays the same packets.
+#[derive(Clone)]
+pub struct LoopbackNetwork {
+    state: Arc<Mutex<LoopbackState>>,
+}
+
+impl LoopbackNetwork {
+    pub fn new(conditions: LinkConditions, seed: u64) -> LoopbackNetwork {
+        LoopbackNetwork {
+            state: Arc::new(Mutex::new(LoopbackState {
+                now: 0,
+                conditions,
+                rng: ChaCha8Rng::seed_from_u64(seed),
+                in_flight: Vec::new(),
+                inboxes: HashMap::new(),
+            })),
+        }
+    }
+
+    // A peer on this network. Ports are just names here.
+    pub fn endpoint(&self, port: u16) -> LoopbackTransport {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        self.state.lock().unwrap().inboxes.entry(addr).or_default();
+        LoopbackTransport {
+            network: self.clone(),
+            addr,
+        }
+    }
+
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        self.state.lock().unwrap().conditions = conditions;
+    }
+
+    // Moves time on and hands out everything that has arrived
+    pub fn step(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.now += 1;
+
+        let now = state.now;
+        let (arrived, still_flying): (Vec<InFlight>, Vec<InFlight>) = state
+            .in_flight
+            .drain(..)
+            .partition(|packet| packet.deliver_at <= now);
+        state.in_flight = still_flying;
+
+        for packet in arrived {
+            if let Some(inbox) = state.inboxes.get_mut(&packet.to) {
+                inbox.push((packet.from, packet.bytes));
+            }
+        }
+    }
+}
+
+pub struct LoopbackTransport {
+    network: LoopbackNetwork,
+    addr: SocketAddr,
+}
+
+impl LoopbackTransport {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&mut self, to: SocketAddr, bytes: &[u8]) {
+        let mut state = self.network.state.lock().unwrap();
+        let conditions = state.conditions;
+        if state.rng.gen::<f32>() < conditions.loss {
+            return;
+        }
+
+        let jitter = if conditions.jitter > 0 {
+            state.rng.gen_range(0..=conditions.jitter)
+        } else {
+            0
+        };
+        let deliver_at = state.now + 1 + (conditions.latency + jitter) as u64;
+        state.in_flight.push(InFlight {
+            deliver_at,
+            from: self.addr,
+            to,
+            bytes: bytes.to_vec(),
+        });
+    }
+
+    fn receive(&mut self) -> Option<(SocketAddr, Vec<u8>)> {
+        let mut state = self.network.state.lock().unwrap();
+        let inbox = state.inboxes.get_mut(&self.addr)?;
+        if inbox.is_empty() {
+            None
+        } else {
+            Some(inbox.remove(0))
+        }
+    }
+}
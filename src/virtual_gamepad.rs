@@ -0,0 +1,266 @@
+use bevy::app::Events;
+use bevy::input::gamepad::GamepadEventRaw;
+use bevy::prelude::*;
+use gilrs::Gilrs;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Made up pads, a range each and all well clear of anything gilrs hands out, so they can be
+// mixed with real ones and never mistaken for each other
+pub const FIRST_VIRTUAL_GAMEPAD: usize = 1000;
+// Bots and training dummies don't have a pad, but players are looked up by one everywhere
+pub const FIRST_BOT_GAMEPAD: usize = 2000;
+// Whoever is on the other end of a netplay match
+pub const FIRST_REMOTE_GAMEPAD: usize = 3000;
+
+// Gamepads that only exist in software. Tests drive them through VirtualGamepads, and
+// `--gamepad-script <file>` plays a script of inputs into them.
+pub struct VirtualGamepadPlugin;
+impl Plugin for VirtualGamepadPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        if let Some(path) = script_argument() {
+            match VirtualScript::load(&path) {
+                Ok(script) => {
+                    app.insert_resource(script);
+                }
+                Err(err) => error!("Failed to load gamepad script {}. {}", path.display(), err),
+            }
+        }
+
+        app.insert_resource(VirtualGamepads::default());
+        // After real pads' events when there are any, so both go out on the same frame
+        let inject = inject_virtual_gamepad_events.exclusive_system();
+        if app.world().get_non_send_resource::<Gilrs>().is_some() {
+            app.add_system_to_stage(CoreStage::PreUpdate, inject.after("gilrs_events"));
+        } else {
+            app.add_system_to_stage(CoreStage::PreUpdate, inject);
+        }
+    }
+}
+
+fn script_argument() -> Option<PathBuf> {
+    let mut args = std::env::args().skip_while(|arg| arg != "--gamepad-script");
+    args.next()?;
+    args.next().map(PathBuf::from)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VirtualInput {
+    Connect,
+    Disconnect,
+    Button(GamepadButtonType, f32),
+    Axis(GamepadAxisType, f32),
+}
+
+// Inputs queued up here go out as GamepadEventRaw at the start of the next frame, exactly
+// like events from a real pad
+#[derive(Default)]
+pub struct VirtualGamepads {
+    queued: Vec<GamepadEventRaw>,
+}
+
+impl VirtualGamepads {
+    pub fn gamepad(index: usize) -> Gamepad {
+        Gamepad(FIRST_VIRTUAL_GAMEPAD + index)
+    }
+
+    pub fn send(&mut self, gamepad: Gamepad, input: VirtualInput) {
+        let event = match input {
+            VirtualInput::Connect => GamepadEventType::Connected,
+            VirtualInput::Disconnect => GamepadEventType::Disconnected,
+            VirtualInput::Button(button, value) => GamepadEventType::ButtonChanged(button, value),
+            VirtualInput::Axis(axis, value) => GamepadEventType::AxisChanged(axis, value),
+        };
+        self.queued.push(GamepadEventRaw(gamepad, event));
+    }
+
+    pub fn connect(&mut self, index: usize) -> Gamepad {
+        let gamepad = VirtualGamepads::gamepad(index);
+        self.send(gamepad, VirtualInput::Connect);
+        gamepad
+    }
+
+    pub fn disconnect(&mut self, gamepad: Gamepad) {
+        self.send(gamepad, VirtualInput::Disconnect);
+    }
+
+    pub fn press(&mut self, gamepad: Gamepad, button: GamepadButtonType) {
+        self.send(gamepad, VirtualInput::Button(button, 1.));
+    }
+
+    pub fn release(&mut self, gamepad: Gamepad, button: GamepadButtonType) {
+        self.send(gamepad, VirtualInput::Button(button, 0.));
+    }
+
+    pub fn set_axis(&mut self, gamepad: Gamepad, axis: GamepadAxisType, value: f32) {
+        self.send(gamepad, VirtualInput::Axis(axis, value));
+    }
+
+    pub fn set_stick(&mut self, gamepad: Gamepad, x: GamepadAxisType, y: GamepadAxisType, stick: Vec2) {
+        self.set_axis(gamepad, x, stick.x);
+        self.set_axis(gamepad, y, stick.y);
+    }
+}
+
+struct ScriptStep {
+    frame: u64,
+    pad: usize,
+    input: VirtualInput,
+}
+
+// One input per line, `#` starts a comment:
+//   <frame> connect <pad>
+//   <frame> disconnect <pad>
+//   <frame> press <pad> <button>
+//   <frame> release <pad> <button>
+//   <frame> axis <pad> <axis> <value>
+// Buttons and axes use the GamepadButtonType and GamepadAxisType names, like South or LeftStickX.
+pub struct VirtualScript {
+    steps: Vec<ScriptStep>,
+    next: usize,
+    frame: u64,
+}
+
+fn parse_button(name: &str) -> Option<GamepadButtonType> {
+    let button = match name {
+        "South" => GamepadButtonType::South,
+        "East" => GamepadButtonType::East,
+        "North" => GamepadButtonType::North,
+        "West" => GamepadButtonType::West,
+        "C" => GamepadButtonType::C,
+        "Z" => GamepadButtonType::Z,
+        "LeftTrigger" => GamepadButtonType::LeftTrigger,
+        "LeftTrigger2" => GamepadButtonType::LeftTrigger2,
+        "RightTrigger" => GamepadButtonType::RightTrigger,
+        "RightTrigger2" => GamepadButtonType::RightTrigger2,
+        "Select" => GamepadButtonType::Select,
+        "Start" => GamepadButtonType::Start,
+        "Mode" => GamepadButtonType::Mode,
+        "LeftThumb" => GamepadButtonType::LeftThumb,
+        "RightThumb" => GamepadButtonType::RightThumb,
+        "DPadUp" => GamepadButtonType::DPadUp,
+        "DPadDown" => GamepadButtonType::DPadDown,
+        "DPadLeft" => GamepadButtonType::DPadLeft,
+        "DPadRight" => GamepadButtonType::DPadRight,
+        _ => return None,
+    };
+    Some(button)
+}
+
+fn parse_axis(name: &str) -> Option<GamepadAxisType> {
+    let axis = match name {
+        "LeftStickX" => GamepadAxisType::LeftStickX,
+        "LeftStickY" => GamepadAxisType::LeftStickY,
+        "LeftZ" => GamepadAxisType::LeftZ,
+        "RightStickX" => GamepadAxisType::RightStickX,
+        "RightStickY" => GamepadAxisType::RightStickY,
+        "RightZ" => GamepadAxisType::RightZ,
+        "DPadX" => GamepadAxisType::DPadX,
+        "DPadY" => GamepadAxisType::DPadY,
+        _ => return None,
+    };
+    Some(axis)
+}
+
+fn parse_step(line: &str) -> Result<Option<ScriptStep>, String> {
+    let line = line.split('#').next().unwrap_or("");
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.is_empty() {
+        return Ok(None);
+    }
+    if words.len() < 3 {
+        return Err("expected <frame> <command> <pad>".to_string());
+    }
+
+    let frame = words[0]
+        .parse()
+        .map_err(|_| format!("bad frame {}", words[0]))?;
+    let pad = words[2].parse().map_err(|_| format!("bad pad {}", words[2]))?;
+    let arg = |index: usize| {
+        words
+            .get(index)
+            .copied()
+            .ok_or_else(|| format!("{} is missing an argument", words[1]))
+    };
+    let button = |index: usize| {
+        let name = arg(index)?;
+        parse_button(name).ok_or_else(|| format!("unknown button {}", name))
+    };
+
+    let input = match words[1] {
+        "connect" => VirtualInput::Connect,
+        "disconnect" => VirtualInput::Disconnect,
+        "press" => VirtualInput::Button(button(3)?, 1.),
+        "release" => VirtualInput::Button(button(3)?, 0.),
+        "axis" => {
+            let name = arg(3)?;
+            let axis = parse_axis(name).ok_or_else(|| format!("unknown axis {}", name))?;
+            let value: f32 = arg(4)?.parse().map_err(|_| format!("bad axis value {}", words[4]))?;
+            VirtualInput::Axis(axis, value.clamp(-1., 1.))
+        }
+        command => return Err(format!("unknown command {}", command)),
+    };
+
+    Ok(Some(ScriptStep { frame, pad, input }))
+}
+
+impl VirtualScript {
+    pub fn parse(script: &str) -> Result<VirtualScript, String> {
+        let mut steps = Vec::new();
+        for (number, line) in script.lines().enumerate() {
+            match parse_step(line) {
+                Ok(Some(step)) => steps.push(step),
+                Ok(None) => (),
+                Err(err) => return Err(format!("line {}: {}", number + 1, err)),
+            }
+        }
+        // Keep lines for the same frame in the order they were written
+        steps.sort_by_key(|step| step.frame);
+
+        Ok(VirtualScript {
+            steps,
+            next: 0,
+            frame: 0,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<VirtualScript, String> {
+        let script = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        VirtualScript::parse(&script)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.steps.len()
+    }
+
+    // Queues everything due this frame and moves on to the next
+    fn queue_due(&mut self, pads: &mut VirtualGamepads) {
+        while let Some(step) = self.steps.get(self.next) {
+            if step.frame > self.frame {
+                break;
+            }
+            pads.send(VirtualGamepads::gamepad(step.pad), step.input);
+            self.next += 1;
+        }
+        self.frame += 1;
+    }
+}
+
+fn inject_virtual_gamepad_events(world: &mut World) {
+    let world = world.cell();
+    let mut events = world.get_resource_mut::<Events<GamepadEventRaw>>().unwrap();
+    let mut pads = world.get_resource_mut::<VirtualGamepads>().unwrap();
+
+    // Gilrs normally swaps the event buffers, without it somebody still has to
+    if world.get_non_send::<Gilrs>().is_none() {
+        events.update();
+    }
+
+    if let Some(mut script) = world.get_resource_mut::<VirtualScript>() {
+        script.queue_due(&mut pads);
+    }
+
+    for event in pads.queued.drain(..) {
+        events.send(event);
+    }
+}
@@ -1,14 +1,41 @@
+use super::settings::*;
 use bevy::prelude::*;
 
+// Gameplay always happens in this space, the camera scales it to whatever the window is
+pub const VIRTUAL_WIDTH: f32 = 600.;
+pub const VIRTUAL_HEIGHT: f32 = 600.;
+
 pub struct WindowPlugin;
 impl Plugin for WindowPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        app.insert_resource(ClearColor(Color::rgb(0.04, 0.04, 0.04)))
+        let settings = app
+            .world()
+            .get_resource::<Settings>()
+            .cloned()
+            .unwrap_or_default();
+        let (width, height) = settings.video.resolution;
+
+        app.insert_resource(ClearColor(settings.clear_color()))
             .insert_resource(WindowDescriptor {
                 title: "Smash Poopers!".to_string(),
-                width: 600.,
-                height: 600.,
+                width: width as f32,
+                height: height as f32,
+                resizable: true,
+                mode: settings.window_mode(),
                 ..Default::default()
-            });
+            })
+            .add_system(toggle_fullscreen.system());
+    }
+}
+
+// F11 or Alt+Enter
+fn toggle_fullscreen(keys: Res<Input<KeyCode>>, file: Res<SettingsFile>, mut settings: ResMut<Settings>) {
+    let alt = keys.pressed(KeyCode::LAlt) || keys.pressed(KeyCode::RAlt);
+    let alt_enter = alt && keys.just_pressed(KeyCode::Return);
+    if !keys.just_pressed(KeyCode::F11) && !alt_enter {
+        return;
     }
+
+    settings.video.fullscreen = !settings.video.fullscreen;
+    settings.save(&file);
 }
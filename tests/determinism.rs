@@ -0,0 +1,206 @@
+use bevy::app::{Events, ManualEventReader};
+use bevy::prelude::*;
+use bevy_playground::bot::*;
+use bevy_playground::events::*;
+use bevy_playground::headless_app;
+use bevy_playground::player::*;
+use bevy_playground::settings::*;
+use bevy_playground::simulation::*;
+use bevy_playground::snapshot::*;
+use bevy_playground::stats::*;
+use bevy_playground::virtual_gamepad::*;
+
+fn with_pads(app: &mut App, func: impl FnOnce(&mut VirtualGamepads)) {
+    func(&mut app.world.get_resource_mut::<VirtualGamepads>().unwrap());
+}
+
+fn press_and_release(app: &mut App, gamepads: &[Gamepad], button: GamepadButtonType) {
+    with_pads(app, |pads| gamepads.iter().for_each(|gamepad| pads.press(*gamepad, button)));
+    app.update();
+    with_pads(app, |pads| gamepads.iter().for_each(|gamepad| pads.release(*gamepad, button)));
+    app.update();
+}
+
+fn start_match(seed: u64, bot: bool) -> (App, Vec<Gamepad>) {
+    let mut builder = headless_app();
+    builder.insert_resource(MatchSeed(seed));
+    let mut app = builder.app;
+    app.update();
+
+    let mut gamepads = Vec::new();
+    with_pads(&mut app, |pads| gamepads = vec![pads.connect(0), pads.connect(1)]);
+    app.update();
+    // Once to join the lobby, once more to ready up and start
+    press_and_release(&mut app, &gamepads, GamepadButtonType::Start);
+    if bot {
+        press_and_release(&mut app, &gamepads[..1], GamepadButtonType::North);
+    }
+    press_and_release(&mut app, &gamepads, GamepadButtonType::Start);
+    (app, gamepads)
+}
+
+// Two players running at each other, hopping and shooting on a fixed pattern. `nudge` changes
+// one input on one frame.
+fn play_frame(app: &mut App, gamepads: &[Gamepad], frame: usize, nudge: Option<usize>) -> StateChecksum {
+    let settings = app.world.get_resource::<Settings>().unwrap();
+    let jump: GamepadButtonType = settings.input.bindings.jump.into();
+    let fire: GamepadButtonType = settings.input.bindings.fire.into();
+
+    with_pads(app, |pads| {
+        for (index, gamepad) in gamepads.iter().enumerate() {
+            let toward = if index == 0 { 1. } else { -1. };
+            let x = if Some(frame) == nudge && index == 0 { -toward } else { toward };
+            pads.set_axis(*gamepad, GamepadAxisType::LeftStickX, x);
+
+            let phase = (frame + index * 7) % 40;
+            match phase {
+                0 => pads.press(*gamepad, jump),
+                2 => pads.release(*gamepad, jump),
+                10 | 20 => pads.press(*gamepad, fire),
+                12 | 22 => pads.release(*gamepad, fire),
+                _ => {}
+            }
+        }
+    });
+    app.update();
+    *app.world.get_resource::<StateChecksum>().unwrap()
+}
+
+// The checksum after every frame
+fn play_match(seed: u64, nudge: Option<usize>) -> Vec<StateChecksum> {
+    let (mut app, gamepads) = start_match(seed, false);
+    (0..300).map(|frame| play_frame(&mut app, &gamepads, frame, nudge)).collect()
+}
+
+#[test]
+fn same_seed_and_inputs_play_out_the_same() {
+    let first = play_match(7, None);
+    assert!(first.last().unwrap().tick > 0);
+    assert_eq!(first, play_match(7, None));
+}
+
+#[test]
+fn one_different_input_changes_the_checksum() {
+    let first = play_match(7, None);
+    let nudged = play_match(7, Some(100));
+    assert_eq!(first[..100], nudged[..100]);
+    assert_ne!(first, nudged);
+}
+
+// Bots are what draws on it, so the seed has to change where one goes and not just the checksum
+#[test]
+fn the_game_rng_comes_from_the_match_seed() {
+    let bot_path = |seed| {
+        let (mut app, gamepads) = start_match(seed, true);
+        // Before anyone's had time to knock it out
+        (0..120)
+            .map(|frame| {
+                play_frame(&mut app, &gamepads, frame, None);
+                app.world
+                    .query_filtered::<&Transform, With<Bot>>()
+                    .iter(&app.world)
+                    .map(|transform| transform.translation)
+                    .next()
+            })
+            .collect::<Vec<Option<Vec3>>>()
+    };
+
+    let path = bot_path(3);
+    assert!(path.iter().all(Option::is_some));
+    assert_eq!(path, bot_path(3));
+    assert_ne!(path, bot_path(4));
+}
+
+// What a netplay rollback does: back to an earlier tick, then the same inputs over again
+#[test]
+fn a_restored_snapshot_plays_out_the_same_again() {
+    let (mut app, gamepads) = start_match(7, false);
+    for frame in 0..60 {
+        play_frame(&mut app, &gamepads, frame, None);
+    }
+    let tick = app.world.get_resource::<SimulationClock>().unwrap().tick;
+    let snapshot = Snapshot::capture(&mut app.world, tick);
+    let first: Vec<StateChecksum> = (60..120)
+        .map(|frame| play_frame(&mut app, &gamepads, frame, None))
+        .collect();
+
+    // Both frames leave every button up, so the pads are already as they were
+    snapshot.restore(&mut app.world);
+    app.world.get_resource_mut::<SimulationClock>().unwrap().tick = snapshot.tick;
+    assert_eq!(Snapshot::capture(&mut app.world, tick).checksum(), snapshot.checksum());
+    let again: Vec<StateChecksum> = (60..120)
+        .map(|frame| play_frame(&mut app, &gamepads, frame, None))
+        .collect();
+    assert_eq!(first, again);
+}
+
+// A misprediction can knock someone out for good and the real inputs then undo it
+#[test]
+fn a_restored_snapshot_brings_back_a_player_who_was_knocked_out() {
+    let (mut app, gamepads) = start_match(7, false);
+    for frame in 0..30 {
+        play_frame(&mut app, &gamepads, frame, None);
+    }
+    let tick = app.world.get_resource::<SimulationClock>().unwrap().tick;
+    let snapshot = Snapshot::capture(&mut app.world, tick);
+
+    let (_, mut transform, mut lives) = app
+        .world
+        .query::<(&PlayerSlot, &mut Transform, &mut Lives)>()
+        .iter_mut(&mut app.world)
+        .find(|(slot, _, _)| slot.0 == 0)
+        .unwrap();
+    lives.0 = 1;
+    transform.translation = Vec3::new(0., -10000., 0.);
+    play_frame(&mut app, &gamepads, 30, None);
+    let slots = |app: &mut App| {
+        let mut slots: Vec<usize> = app.world.query::<&PlayerSlot>().iter(&app.world).map(|slot| slot.0).collect();
+        slots.sort_unstable();
+        slots
+    };
+    assert_eq!(slots(&mut app), vec![1]);
+
+    snapshot.restore(&mut app.world);
+    assert_eq!(slots(&mut app), vec![0, 1]);
+    assert_eq!(Snapshot::capture(&mut app.world, tick).checksum(), snapshot.checksum());
+    let stats = app.world.get_resource::<MatchStats>().unwrap();
+    assert_eq!(stats.get(0).unwrap().stocks_lost, 0);
+}
+
+// Sounds, particles and the like have already gone off for ticks being run again
+#[test]
+fn ticks_run_again_say_so_in_their_events() {
+    let (mut app, gamepads) = start_match(7, false);
+    for frame in 0..30 {
+        play_frame(&mut app, &gamepads, frame, None);
+    }
+    let tick = app.world.get_resource::<SimulationClock>().unwrap().tick;
+    let snapshot = Snapshot::capture(&mut app.world, tick);
+    for frame in 30..35 {
+        play_frame(&mut app, &gamepads, frame, None);
+    }
+
+    let fired = |app: &mut App, frames: usize| {
+        let fire: GamepadButtonType = app.world.get_resource::<Settings>().unwrap().input.bindings.fire.into();
+        with_pads(app, |pads| pads.press(gamepads[0], fire));
+        let mut reader: ManualEventReader<PlayerFireEvent> = Default::default();
+        reader.iter(app.world.get_resource::<Events<PlayerFireEvent>>().unwrap()).count();
+        let mut reruns = Vec::new();
+        for _ in 0..frames {
+            app.update();
+            let events = app.world.get_resource::<Events<PlayerFireEvent>>().unwrap();
+            reruns.extend(reader.iter(events).map(|event| event.rerun));
+        }
+        with_pads(app, |pads| pads.release(gamepads[0], fire));
+        app.update();
+        reruns
+    };
+
+    // Just like a rollback, back to the snapshot and straight through to where it was again
+    snapshot.restore(&mut app.world);
+    let mut clock = app.world.get_resource_mut::<SimulationClock>().unwrap();
+    clock.tick = snapshot.tick;
+    clock.resimulate = 5;
+    assert_eq!(fired(&mut app, 1), vec![true]);
+    assert_eq!(fired(&mut app, 1), vec![false]);
+}
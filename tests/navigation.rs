@@ -0,0 +1,61 @@
+use bevy::prelude::*;
+use bevy_playground::headless_app;
+use bevy_playground::navigation::*;
+
+// The graph the game builds for Battlefield, from the map it spawns and its gravity
+fn battlefield() -> NavGraph {
+    let mut app = headless_app().app;
+    app.update();
+    std::mem::take(&mut *app.world.get_resource_mut::<NavGraph>().unwrap())
+}
+
+// Middle and top of the platform a point would land on
+fn landing(graph: &NavGraph, point: Vec2) -> Option<Vec2> {
+    let platform = graph.platforms[graph.platform_under(point)?];
+    Some(Vec2::new((platform.left + platform.right) / 2., platform.top))
+}
+
+fn kinds(path: &NavPath) -> Vec<MoveKind> {
+    path.edges.iter().map(|edge| edge.kind).collect()
+}
+
+#[test]
+fn standing_points_find_their_platform() {
+    let graph = battlefield();
+    assert_eq!(graph.platforms.len(), 4);
+    assert_eq!(landing(&graph, Vec2::new(0., -84.)), Some(Vec2::new(1., -92.)));
+    assert_eq!(landing(&graph, Vec2::new(-110., 16.)), Some(Vec2::new(-110., 8.)));
+    // In the air over the side platform, it would land there first
+    assert_eq!(landing(&graph, Vec2::new(110., 60.)), Some(Vec2::new(110., 8.)));
+    assert_eq!(landing(&graph, Vec2::new(0., -200.)), None);
+}
+
+#[test]
+fn side_platforms_need_a_double_jump_from_the_floor() {
+    let graph = battlefield();
+    let path = graph.find_path(Vec2::new(0., -84.), Vec2::new(-110., 16.)).unwrap();
+    assert_eq!(kinds(&path), vec![MoveKind::DoubleJump]);
+    assert_eq!(path.target_x, -110.);
+}
+
+#[test]
+fn the_top_platform_goes_by_way_of_a_side_one() {
+    let graph = battlefield();
+    let up = graph.find_path(Vec2::new(0., -84.), Vec2::new(0., 116.)).unwrap();
+    assert_eq!(kinds(&up), vec![MoveKind::DoubleJump, MoveKind::DoubleJump]);
+
+    // Straight down off the top lands on a side platform first
+    let down = graph.find_path(Vec2::new(0., 116.), Vec2::new(0., -84.)).unwrap();
+    assert_eq!(kinds(&down), vec![MoveKind::Drop, MoveKind::Drop]);
+}
+
+#[test]
+fn actions_press_jump_for_each_jump_and_walk_to_the_target() {
+    let graph = battlefield();
+    let path = graph.find_path(Vec2::new(150., -84.), Vec2::new(-110., 16.)).unwrap();
+    let actions = path.actions();
+
+    assert_eq!(actions.iter().filter(|actions| actions.jump).count(), 2);
+    assert!(actions.front().unwrap().movement.x < 0.);
+    assert!((actions.len() as i64 - path.ticks as i64).abs() <= 2);
+}
@@ -0,0 +1,155 @@
+use bevy_playground::actions::*;
+use bevy_playground::netplay::*;
+use bevy_playground::transport::*;
+
+const TICKS: u64 = 300;
+
+// Changes every tick so any guess about it is wrong
+fn input_for(slot: usize, tick: u64) -> PackedActions {
+    PackedActions {
+        movement: [(tick % 100) as i8, slot as i8],
+        aim: [0, 0],
+        buttons: tick.is_multiple_of(7) as u8,
+    }
+}
+
+struct Run {
+    sessions: Vec<RollbackSession>,
+    rollbacks: usize,
+}
+
+// Two peers ticking in lockstep with the network, each as far ahead as prediction allows
+fn run_peers(conditions: LinkConditions, desync_from: Option<u64>) -> Run {
+    let network = LoopbackNetwork::new(conditions, 7);
+    let mut transports = [network.endpoint(7000), network.endpoint(7001)];
+    let mut sessions = vec![
+        RollbackSession::new(0, vec![(1, transports[1].addr())]),
+        RollbackSession::new(1, vec![(0, transports[0].addr())]),
+    ];
+    let mut ticks_run = [0; 2];
+    let mut rollbacks = 0;
+
+    for _ in 0..TICKS * 4 {
+        for (index, (session, transport)) in sessions.iter_mut().zip(transports.iter_mut()).enumerate() {
+            session.poll(transport);
+            if session.take_rollback().is_some() {
+                rollbacks += 1;
+            }
+
+            let next = ticks_run[index] + 1;
+            if next <= TICKS && next <= session.confirmed_tick() + MAX_PREDICTION {
+                session.add_local_input(next, input_for(index, next));
+                for slot in session.player_slots() {
+                    session.input(next, slot);
+                }
+
+                let desynced = index == 1 && desync_from.is_some_and(|from| next >= from);
+                session.add_local_checksum(next, if desynced { 1 } else { 0 });
+                ticks_run[index] = next;
+            }
+            session.send(transport);
+        }
+        network.step();
+    }
+
+    Run { sessions, rollbacks }
+}
+
+#[test]
+fn inputs_round_trip() {
+    let message = Message::Inputs {
+        slot: 2,
+        first_tick: 41,
+        ack: 39,
+        inputs: vec![input_for(2, 41), input_for(2, 42)],
+    };
+    assert_eq!(Message::decode(&message.encode()), Some(message));
+
+    let message = Message::Checksum {
+        tick: 12,
+        checksum: 0xdead_beef_0000_0001,
+    };
+    assert_eq!(Message::decode(&message.encode()), Some(message));
+
+    assert_eq!(Message::decode(b"SB"), None);
+    assert_eq!(Message::decode(b"nope, not a message"), None);
+}
+
+#[test]
+fn inputs_from_too_far_ahead_are_dropped() {
+    let peer = "127.0.0.1:7001".parse().unwrap();
+    let mut session = RollbackSession::new(0, vec![(1, peer)]);
+    let message = Message::Inputs {
+        slot: 1,
+        first_tick: u32::MAX - 1,
+        ack: 0,
+        inputs: vec![input_for(1, 1), input_for(1, 1)],
+    };
+    session.receive(peer, &message.encode());
+
+    assert_eq!(session.input(u32::MAX as u64 - 1, 1), PackedActions::default());
+    assert_eq!(session.confirmed_tick(), 0);
+}
+
+#[test]
+fn checksums_wait_for_a_pending_rollback() {
+    let peer = "127.0.0.1:7001".parse().unwrap();
+    let mut session = RollbackSession::new(0, vec![(1, peer)]);
+    session.add_local_input(1, input_for(0, 1));
+    // Simulated with a guess for P2 that turns out wrong
+    let guess = session.input(1, 1);
+    assert_ne!(guess, input_for(1, 1));
+    session.add_local_checksum(1, 1);
+
+    let inputs = Message::Inputs {
+        slot: 1,
+        first_tick: 1,
+        ack: 1,
+        inputs: vec![input_for(1, 1)],
+    };
+    session.receive(peer, &inputs.encode());
+    session.receive(peer, &Message::Checksum { tick: 1, checksum: 2 }.encode());
+    assert_eq!(session.desynced_at, None);
+
+    // Rerun with the real input, and it agrees
+    assert_eq!(session.take_rollback(), Some(1));
+    session.add_local_checksum(1, 2);
+    assert_eq!(session.desynced_at, None);
+}
+
+#[test]
+fn everyone_ends_up_with_every_input() {
+    let conditions = LinkConditions {
+        latency: 3,
+        jitter: 2,
+        loss: 0.2,
+    };
+    let mut run = run_peers(conditions, None);
+
+    for session in run.sessions.iter_mut() {
+        assert_eq!(session.confirmed_tick(), TICKS);
+        for tick in 1..=TICKS {
+            for slot in 0..2 {
+                assert_eq!(session.input(tick, slot), input_for(slot, tick));
+            }
+        }
+        assert_eq!(session.desynced_at, None);
+    }
+    // Remote inputs change every tick, so a lagging link has to guess wrong
+    assert!(run.rollbacks > 0);
+}
+
+#[test]
+fn different_checksums_are_a_desync() {
+    // Checksums aren't resent, so keep them all arriving to know which tick gets flagged
+    let conditions = LinkConditions {
+        latency: 2,
+        jitter: 0,
+        loss: 0.,
+    };
+    let run = run_peers(conditions, Some(120));
+
+    for session in run.sessions.iter() {
+        assert_eq!(session.desynced_at, Some(120));
+    }
+}
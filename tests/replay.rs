@@ -0,0 +1,77 @@
+use bevy_playground::actions::*;
+use bevy_playground::map::Stage;
+use bevy_playground::replay::*;
+use bevy_playground::teams::*;
+use bevy_playground::training::*;
+
+fn actions(tick: u8) -> PackedActions {
+    PackedActions {
+        movement: [tick as i8, -(tick as i8)],
+        aim: [0, 127],
+        buttons: tick % 4,
+    }
+}
+
+#[test]
+fn replays_round_trip() {
+    let replay = Replay {
+        seed: 0x1234_5678_9abc_def0,
+        stage: Stage::Battlefield,
+        hit_stop: true,
+        aim_assist: false,
+        training: TrainingSetup {
+            enabled: true,
+            infinite_stocks: false,
+            dummy: DummyBehaviour::Walk,
+        },
+        teams: Teams {
+            enabled: true,
+            shared_stocks: true,
+            friendly_fire: false,
+        },
+        roster: vec![
+            RosterEntry {
+                slot: 0,
+                joined_tick: 1,
+                character: "blue".to_string(),
+                team: Some(Team(1)),
+            },
+            RosterEntry {
+                slot: 1,
+                joined_tick: 1,
+                character: "red".to_string(),
+                team: None,
+            },
+        ],
+        ticks: (1..=20)
+            .map(|tick| [Some(actions(tick)), if tick > 5 { Some(actions(tick * 2)) } else { None }, None, None])
+            .collect(),
+        training_edits: vec![
+            (3, TrainingEdit::RaiseDamage(1)),
+            (3, TrainingEdit::NextDummy),
+            (12, TrainingEdit::LowerDamage(0)),
+            (19, TrainingEdit::Reset),
+        ],
+    };
+
+    let mut bytes = Vec::new();
+    replay.write(&mut bytes).unwrap();
+    let read = Replay::read(&mut bytes.as_slice()).unwrap();
+
+    assert_eq!(read.seed, replay.seed);
+    assert_eq!(read.stage, replay.stage);
+    assert_eq!((read.hit_stop, read.aim_assist), (replay.hit_stop, replay.aim_assist));
+    assert_eq!(read.training, replay.training);
+    assert_eq!(read.teams, replay.teams);
+    assert_eq!(read.roster.len(), 2);
+    assert_eq!(read.roster[1].character, "red");
+    let teams: Vec<Option<Team>> = read.roster.iter().map(|entry| entry.team).collect();
+    assert_eq!(teams, vec![Some(Team(1)), None]);
+    assert_eq!(read.ticks, replay.ticks);
+    assert_eq!(read.training_edits, replay.training_edits);
+
+    // Cut short anywhere is an error, not a panic
+    for len in 0..bytes.len() {
+        assert!(Replay::read(&mut &bytes[..len]).is_err());
+    }
+}
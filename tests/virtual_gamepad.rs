@@ -0,0 +1,528 @@
+use bevy::app::{Events, ManualEventReader};
+use bevy::prelude::*;
+use bevy_playground::app_state::*;
+use bevy_playground::bot::*;
+use bevy_playground::camera::*;
+use bevy_playground::events::*;
+use bevy_playground::headless_app;
+use bevy_playground::hit_stop::*;
+use bevy_playground::lobby::*;
+use bevy_playground::options_menu::*;
+use bevy_playground::pause::*;
+use bevy_playground::player::*;
+use bevy_playground::projectile::*;
+use bevy_playground::settings::*;
+use bevy_playground::simulation::*;
+use bevy_playground::stats::*;
+use bevy_playground::teams::*;
+use bevy_playground::training::*;
+use bevy_playground::virtual_gamepad::*;
+
+fn run(app: &mut App, frames: usize) {
+    for _ in 0..frames {
+        app.update();
+    }
+}
+
+fn with_pads(app: &mut App, func: impl FnOnce(&mut VirtualGamepads)) {
+    func(&mut app.world.get_resource_mut::<VirtualGamepads>().unwrap());
+}
+
+fn bindings(app: &App) -> (GamepadButtonType, GamepadButtonType) {
+    let settings = app.world.get_resource::<Settings>().unwrap();
+    (
+        settings.input.bindings.jump.into(),
+        settings.input.bindings.fire.into(),
+    )
+}
+
+// Presses and releases a button, counting events of type T sent in the meantime
+fn tap<T: Send + Sync + 'static>(app: &mut App, gamepad: Gamepad, button: GamepadButtonType) -> usize {
+    let mut reader: ManualEventReader<T> = Default::default();
+    reader.iter(app.world.get_resource::<Events<T>>().unwrap()).count();
+
+    let mut count = 0;
+    for release in [false, true] {
+        with_pads(app, |pads| {
+            if release {
+                pads.release(gamepad, button);
+            } else {
+                pads.press(gamepad, button);
+            }
+        });
+        for _ in 0..2 {
+            app.update();
+            count += reader.iter(app.world.get_resource::<Events<T>>().unwrap()).count();
+        }
+    }
+    count
+}
+
+// Runs a few frames, counting events of type T sent in them
+fn count<T: Send + Sync + 'static>(app: &mut App, frames: usize) -> usize {
+    let mut reader: ManualEventReader<T> = Default::default();
+    reader.iter(app.world.get_resource::<Events<T>>().unwrap()).count();
+
+    let mut count = 0;
+    for _ in 0..frames {
+        app.update();
+        count += reader.iter(app.world.get_resource::<Events<T>>().unwrap()).count();
+    }
+    count
+}
+
+// Presses and releases a button, for when nothing needs counting
+fn press(app: &mut App, gamepad: Gamepad, button: GamepadButtonType) {
+    with_pads(app, |pads| pads.press(gamepad, button));
+    run(app, 2);
+    with_pads(app, |pads| pads.release(gamepad, button));
+    run(app, 2);
+}
+
+// Everyone joins, nobody's ready yet
+fn lobby_with_players(count: usize) -> (App, Vec<Gamepad>) {
+    let mut app = headless_app().app;
+    run(&mut app, 2);
+
+    let mut gamepads = Vec::new();
+    with_pads(&mut app, |pads| gamepads = (0..count).map(|index| pads.connect(index)).collect());
+    run(&mut app, 2);
+    for gamepad in gamepads.iter() {
+        press(&mut app, *gamepad, GamepadButtonType::Start);
+    }
+    (app, gamepads)
+}
+
+fn ready_up(app: &mut App, gamepads: &[Gamepad]) {
+    for gamepad in gamepads.iter() {
+        press(app, *gamepad, GamepadButtonType::Start);
+    }
+    run(app, 2);
+}
+
+// Everyone joins, then everyone readies up
+fn game_with_players(count: usize) -> (App, Vec<Gamepad>) {
+    let (mut app, gamepads) = lobby_with_players(count);
+    ready_up(&mut app, &gamepads);
+    (app, gamepads)
+}
+
+fn game_with_player() -> (App, Gamepad) {
+    let (app, gamepads) = game_with_players(1);
+    (app, gamepads[0])
+}
+
+fn players(app: &mut App) -> Vec<(Gamepad, PlayerSlot)> {
+    app.world
+        .query_filtered::<(&Gamepad, &PlayerSlot), With<Player>>()
+        .iter(&app.world)
+        .map(|(gamepad, slot)| (*gamepad, *slot))
+        .collect()
+}
+
+#[test]
+fn connecting_a_pad_only_joins_after_start() {
+    let mut app = headless_app().app;
+    run(&mut app, 2);
+
+    let mut gamepad = Gamepad(0);
+    with_pads(&mut app, |pads| gamepad = pads.connect(0));
+    run(&mut app, 4);
+    assert!(players(&mut app).is_empty());
+
+    press(&mut app, gamepad, GamepadButtonType::Start);
+    let lobby = app.world.get_resource::<Lobby>().unwrap();
+    assert_eq!(lobby.slot_of(gamepad), Some(0));
+    assert!(players(&mut app).is_empty());
+
+    // Back leaves again
+    press(&mut app, gamepad, GamepadButtonType::Select);
+    let lobby = app.world.get_resource::<Lobby>().unwrap();
+    assert_eq!(lobby.slot_of(gamepad), None);
+}
+
+#[test]
+fn readying_up_starts_the_match() {
+    let (mut app, gamepad) = game_with_player();
+
+    assert_eq!(players(&mut app), vec![(gamepad, PlayerSlot(0))]);
+}
+
+#[test]
+fn jump_button_jumps() {
+    let (mut app, gamepad) = game_with_player();
+    let (jump, _) = bindings(&app);
+
+    assert_eq!(tap::<PlayerJumpEvent>(&mut app, gamepad, jump), 1);
+}
+
+#[test]
+fn fire_button_shoots_where_the_stick_aims() {
+    let (mut app, gamepad) = game_with_player();
+    let (_, fire) = bindings(&app);
+
+    with_pads(&mut app, |pads| pads.set_axis(gamepad, GamepadAxisType::RightStickY, 1.));
+    run(&mut app, 1);
+    assert_eq!(tap::<PlayerFireEvent>(&mut app, gamepad, fire), 1);
+
+    let projectiles = app
+        .world
+        .query_filtered::<Entity, With<Projectile>>()
+        .iter(&app.world)
+        .count();
+    assert_eq!(projectiles, 1);
+}
+
+// Without textures the map is too thin for a real shot to land on, so the impact is sent here
+#[test]
+fn a_shot_bursting_on_the_map_shakes_the_screen() {
+    let (mut app, _) = game_with_player();
+    let trauma = |app: &App| app.world.get_resource::<ScreenShake>().unwrap().trauma;
+    let impact = |app: &mut App, rerun| {
+        let mut events = app.world.get_resource_mut::<Events<ProjectileImpactEvent>>().unwrap();
+        events.send(ProjectileImpactEvent {
+            position: Vec3::ZERO,
+            direction: -Vec3::Y,
+            rerun,
+        });
+        app.update();
+    };
+    assert_eq!(trauma(&app), 0.);
+
+    impact(&mut app, true);
+    assert_eq!(trauma(&app), 0.);
+    impact(&mut app, false);
+    assert!(trauma(&app) > 0.);
+}
+
+#[test]
+fn neutral_stick_shoots_the_way_the_player_faces() {
+    let (mut app, gamepad) = game_with_player();
+    let (_, fire) = bindings(&app);
+
+    with_pads(&mut app, |pads| pads.press(gamepad, fire));
+    run(&mut app, 1);
+
+    let mut reader: ManualEventReader<PlayerFireEvent> = Default::default();
+    let events = app.world.get_resource::<Events<PlayerFireEvent>>().unwrap();
+    let directions: Vec<Vec3> = reader.iter(events).map(|event| event.direction).collect();
+    assert_eq!(directions, vec![Vec3::X]);
+}
+
+#[test]
+fn bots_added_in_the_lobby_join_the_match_and_shoot() {
+    let (mut app, gamepads) = lobby_with_players(1);
+    let gamepad = gamepads[0];
+    press(&mut app, gamepad, GamepadButtonType::North);
+    press(&mut app, gamepad, GamepadButtonType::DPadUp);
+
+    let lobby = app.world.get_resource::<Lobby>().unwrap();
+    assert_eq!(lobby.slots[1].unwrap().bot, Some(BotDifficulty::Hard));
+    assert!(!lobby.everyone_ready());
+
+    ready_up(&mut app, &gamepads);
+    let bots = app
+        .world
+        .query_filtered::<&PlayerSlot, With<Bot>>()
+        .iter(&app.world)
+        .copied()
+        .collect::<Vec<_>>();
+    assert_eq!(bots, vec![PlayerSlot(1)]);
+
+    assert!(count::<PlayerFireEvent>(&mut app, 120) > 0);
+}
+
+#[test]
+fn training_dummy_is_knocked_out_without_losing_a_life() {
+    let (mut app, gamepads) = lobby_with_players(1);
+    let gamepad = gamepads[0];
+    press(&mut app, gamepad, GamepadButtonType::West);
+    assert!(!app.world.get_resource::<Training>().unwrap().loses_lives());
+    ready_up(&mut app, &gamepads);
+
+    let dummy = app
+        .world
+        .query_filtered::<(Entity, &PlayerSlot), With<TrainingDummy>>()
+        .iter(&app.world)
+        .map(|(entity, slot)| (entity, *slot))
+        .next();
+    let (dummy, slot) = dummy.expect("no training dummy");
+    assert_eq!(slot, PlayerSlot(1));
+
+    app.world.get_mut::<Transform>(dummy).unwrap().translation.y = -10000.;
+    assert_eq!(count::<PlayerKoEvent>(&mut app, 4), 1);
+    // Everyone starts with 2
+    assert_eq!(app.world.get::<Lives>(dummy).unwrap().0, 2);
+
+    // Made on a tick, like everything else that changes the match
+    let damage = app.world.get::<DamageTaken>(dummy).unwrap().0;
+    press(&mut app, gamepad, GamepadButtonType::DPadUp);
+    assert_eq!(app.world.get::<DamageTaken>(dummy).unwrap().0, damage + 5.);
+    assert!(app.world.get_resource::<Training>().unwrap().pending.is_empty());
+}
+
+#[test]
+fn only_the_player_who_paused_can_resume() {
+    let (mut app, gamepads) = game_with_players(2);
+
+    let tick = |app: &App| app.world.get_resource::<SimulationClock>().unwrap().tick;
+    let paused_by = |app: &App| app.world.get_resource::<MatchPause>().unwrap().paused_by();
+
+    press(&mut app, gamepads[1], GamepadButtonType::Start);
+    assert_eq!(paused_by(&app), Some(PlayerSlot(1)));
+    let paused_at = tick(&app);
+    press(&mut app, gamepads[0], GamepadButtonType::Start);
+    run(&mut app, 10);
+    assert_eq!(paused_by(&app), Some(PlayerSlot(1)));
+    assert_eq!(tick(&app), paused_at);
+
+    press(&mut app, gamepads[1], GamepadButtonType::Start);
+    assert_eq!(paused_by(&app), None);
+    assert!(tick(&app) > paused_at);
+}
+
+#[test]
+fn buttons_pressed_in_the_pause_menu_dont_carry_into_the_match() {
+    let (mut app, gamepad) = game_with_player();
+    let (jump, _) = bindings(&app);
+    press(&mut app, gamepad, GamepadButtonType::Start);
+    // South is both Resume and the default jump
+    assert_eq!(jump, GamepadButtonType::South);
+    assert_eq!(tap::<PlayerJumpEvent>(&mut app, gamepad, jump), 0);
+    assert!(!app.world.get_resource::<MatchPause>().unwrap().is_paused());
+    run(&mut app, 10);
+    assert_eq!(tap::<PlayerJumpEvent>(&mut app, gamepad, jump), 1);
+}
+
+#[test]
+fn buttons_pressed_in_the_settings_menu_dont_jump() {
+    let (mut app, gamepad) = game_with_player();
+    let (jump, _) = bindings(&app);
+    press(&mut app, gamepad, GamepadButtonType::Select);
+    assert!(app.world.get_resource::<OptionsMenu>().unwrap().open);
+    // South also changes whatever setting is selected
+    assert_eq!(tap::<PlayerJumpEvent>(&mut app, gamepad, jump), 0);
+    press(&mut app, gamepad, GamepadButtonType::East);
+    assert!(!app.world.get_resource::<OptionsMenu>().unwrap().open);
+    run(&mut app, 10);
+    assert_eq!(tap::<PlayerJumpEvent>(&mut app, gamepad, jump), 1);
+}
+
+#[test]
+fn quitting_from_the_pause_menu_goes_back_to_the_lobby() {
+    let (mut app, gamepad) = game_with_player();
+    press(&mut app, gamepad, GamepadButtonType::Start);
+    for _ in 0..3 {
+        press(&mut app, gamepad, GamepadButtonType::DPadDown);
+    }
+    press(&mut app, gamepad, GamepadButtonType::South);
+
+    let state = app.world.get_resource::<State<AppState>>().unwrap();
+    assert_eq!(*state.current(), AppState::Lobby);
+    assert!(players(&mut app).is_empty());
+    assert!(!app.world.get_resource::<MatchPause>().unwrap().is_paused());
+    // Still in the lobby, just not ready
+    assert!(app.world.get_resource::<Lobby>().unwrap().slots[0].is_some());
+}
+
+#[test]
+fn restarting_starts_the_ticks_and_the_seed_over() {
+    let (mut app, gamepad) = game_with_player();
+    run(&mut app, 30);
+    let seed = app.world.get_resource::<MatchSeed>().unwrap().0;
+
+    // Pause, down twice to Restart and pick it
+    press(&mut app, gamepad, GamepadButtonType::Start);
+    for _ in 0..2 {
+        press(&mut app, gamepad, GamepadButtonType::DPadDown);
+    }
+    press(&mut app, gamepad, GamepadButtonType::South);
+
+    let state = app.world.get_resource::<State<AppState>>().unwrap();
+    assert_eq!(*state.current(), AppState::Match);
+    assert!(app.world.get_resource::<SimulationClock>().unwrap().tick < 5);
+    assert_ne!(app.world.get_resource::<MatchSeed>().unwrap().0, seed);
+}
+
+#[test]
+fn stats_credit_kos_to_the_last_attacker_and_the_match_ends_with_one_left() {
+    let (mut app, gamepads) = game_with_players(2);
+    let (_, fire) = bindings(&app);
+    assert_eq!(tap::<PlayerFireEvent>(&mut app, gamepads[0], fire), 1);
+
+    let entity = |app: &mut App, slot: usize| {
+        app.world
+            .query::<(Entity, &PlayerSlot)>()
+            .iter(&app.world)
+            .find(|(_, player)| player.0 == slot)
+            .map(|(entity, _)| entity)
+            .unwrap()
+    };
+    let knock_out = |app: &mut App, victim: Entity, attacker: Option<Entity>| {
+        // The shot may have landed, and hit stop would hold them where they were
+        app.world.entity_mut(victim).remove::<HitStop>();
+        app.world.get_mut::<LastAttacker>(victim).unwrap().0 = attacker;
+        app.world.get_mut::<Transform>(victim).unwrap().translation.y = -10000.;
+        run(app, 2);
+    };
+    let p1 = entity(&mut app, 0);
+    let p2 = entity(&mut app, 1);
+
+    knock_out(&mut app, p2, Some(p1));
+    knock_out(&mut app, p1, None);
+    let state = app.world.get_resource::<State<AppState>>().unwrap();
+    assert_eq!(*state.current(), AppState::Match);
+
+    knock_out(&mut app, p2, Some(p1));
+    let state = app.world.get_resource::<State<AppState>>().unwrap();
+    assert_eq!(*state.current(), AppState::Results);
+
+    let stats = app.world.get_resource::<MatchStats>().unwrap();
+    assert_eq!(stats.winner(), Some(0));
+    let first = stats.get(0).unwrap();
+    assert_eq!((first.shots_fired, first.kos, first.self_destructs, first.stocks_lost), (1, 2, 1, 1));
+    let second = stats.get(1).unwrap();
+    assert_eq!((second.kos, second.stocks_lost), (0, 2));
+    assert!(second.eliminated_at.is_some());
+    assert_eq!(stats.decided_at(), second.eliminated_at);
+    assert!(first.ticks_alive > 0);
+}
+
+#[test]
+fn a_team_match_needs_both_sides_and_ends_when_one_team_is_out() {
+    let (mut app, gamepads) = lobby_with_players(2);
+    // Teams, then teams with shared stocks
+    press(&mut app, gamepads[0], GamepadButtonType::LeftThumb);
+    press(&mut app, gamepads[0], GamepadButtonType::LeftThumb);
+    assert!(app.world.get_resource::<Teams>().unwrap().shares_stocks());
+
+    // Both red, so readying up isn't enough
+    press(&mut app, gamepads[1], GamepadButtonType::RightTrigger);
+    ready_up(&mut app, &gamepads);
+    let state = app.world.get_resource::<State<AppState>>().unwrap();
+    assert_eq!(*state.current(), AppState::Lobby);
+
+    press(&mut app, gamepads[1], GamepadButtonType::Select);
+    press(&mut app, gamepads[1], GamepadButtonType::RightTrigger);
+    ready_up(&mut app, &gamepads[1..]);
+    let state = app.world.get_resource::<State<AppState>>().unwrap();
+    assert_eq!(*state.current(), AppState::Match);
+    // One each in reserve, on top of the one they're on stage with
+    assert_eq!(*app.world.get_resource::<TeamStocks>().unwrap(), TeamStocks([1, 1]));
+
+    let red = app
+        .world
+        .query::<(Entity, &Team)>()
+        .iter(&app.world)
+        .find(|(_, team)| **team == Team(0))
+        .map(|(entity, _)| entity)
+        .unwrap();
+    for _ in 0..2 {
+        app.world.get_mut::<Transform>(red).unwrap().translation.y = -10000.;
+        run(&mut app, 2);
+    }
+
+    let state = app.world.get_resource::<State<AppState>>().unwrap();
+    assert_eq!(*state.current(), AppState::Results);
+    assert_eq!(app.world.get_resource::<MatchStats>().unwrap().winning_team(), Some(1));
+}
+
+#[test]
+fn shared_stocks_bring_teammates_back_until_the_pool_runs_dry() {
+    // Red, blue, red, blue
+    let (mut app, gamepads) = lobby_with_players(4);
+    press(&mut app, gamepads[0], GamepadButtonType::LeftThumb);
+    press(&mut app, gamepads[0], GamepadButtonType::LeftThumb);
+    ready_up(&mut app, &gamepads);
+    assert_eq!(*app.world.get_resource::<TeamStocks>().unwrap(), TeamStocks([2, 2]));
+
+    let red: Vec<Entity> = app
+        .world
+        .query::<(Entity, &Team)>()
+        .iter(&app.world)
+        .filter(|(_, team)| **team == Team(0))
+        .map(|(entity, _)| entity)
+        .collect();
+    assert_eq!(red.len(), 2);
+    let knock_out = |app: &mut App, victim: Entity| {
+        app.world.get_mut::<Transform>(victim).unwrap().translation.y = -10000.;
+        run(app, 2);
+    };
+
+    // Both come back off the reserve
+    knock_out(&mut app, red[0]);
+    knock_out(&mut app, red[1]);
+    assert_eq!(*app.world.get_resource::<TeamStocks>().unwrap(), TeamStocks([0, 2]));
+    assert!(red.iter().all(|player| app.world.get_entity(*player).is_some()));
+
+    // Then each goes out on their next KO, and the team with them
+    knock_out(&mut app, red[0]);
+    assert!(app.world.get_entity(red[0]).is_none());
+    let state = app.world.get_resource::<State<AppState>>().unwrap();
+    assert_eq!(*state.current(), AppState::Match);
+
+    knock_out(&mut app, red[1]);
+    let state = app.world.get_resource::<State<AppState>>().unwrap();
+    assert_eq!(*state.current(), AppState::Results);
+    let stats = app.world.get_resource::<MatchStats>().unwrap();
+    assert_eq!(stats.winning_team(), Some(1));
+    assert_eq!((stats.get(0).unwrap().stocks_lost, stats.get(2).unwrap().stocks_lost), (2, 2));
+}
+
+#[test]
+fn friendly_fire_decides_whether_teammates_can_hurt_each_other() {
+    let mut teams = Teams::default();
+    let (red, blue) = (Team(0), Team(1));
+    assert!(teams.can_hurt(Some(&red), Some(&red)));
+
+    teams.enabled = true;
+    assert!(!teams.can_hurt(Some(&red), Some(&red)));
+    assert!(teams.can_hurt(Some(&red), Some(&blue)));
+    assert!(teams.can_hurt(None, Some(&red)));
+
+    teams.friendly_fire = true;
+    assert!(teams.can_hurt(Some(&red), Some(&red)));
+}
+
+#[test]
+fn scripts_drive_pads_by_frame() {
+    let script = VirtualScript::parse(
+        "# frame command pad args\n\
+         0 connect 0\n\
+         2 press 0 South\n\
+         2 axis 0 LeftStickX 0.5\n\
+         4 release 0 South\n\
+         5 axis 0 LeftStickX -3 # clamped\n",
+    )
+    .unwrap();
+    assert!(!script.is_finished());
+
+    let mut app = headless_app().app;
+    app.world.insert_resource(script);
+
+    let gamepad = VirtualGamepads::gamepad(0);
+    let south = GamepadButton(gamepad, GamepadButtonType::South);
+    let stick = GamepadAxis(gamepad, GamepadAxisType::LeftStickX);
+    // Each frame's button and stick, once the script's lines for that frame have gone out
+    let expected = [
+        // Connecting zeroes the axes
+        (false, Some(0.)),
+        (false, Some(0.)),
+        (true, Some(0.5)),
+        (true, Some(0.5)),
+        (false, Some(0.5)),
+        (false, Some(-1.)),
+        (false, Some(-1.)),
+    ];
+    for (frame, (pressed, axis)) in expected.iter().enumerate() {
+        app.update();
+        let buttons = app.world.get_resource::<Input<GamepadButton>>().unwrap();
+        let axes = app.world.get_resource::<Axis<GamepadAxis>>().unwrap();
+        assert_eq!(buttons.pressed(south), *pressed, "South on frame {}", frame);
+        assert_eq!(axes.get(stick), *axis, "LeftStickX on frame {}", frame);
+    }
+    assert!(app.world.get_resource::<VirtualScript>().unwrap().is_finished());
+
+    assert!(VirtualScript::parse("0 wiggle 0").is_err());
+    assert!(VirtualScript::parse("0 press 0 Banana").is_err());
+}